@@ -0,0 +1,200 @@
+use std::error::Error as StdError;
+use std::fs::File;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches};
+use smoltcp::phy::{Loopback, Medium, PcapMode, PcapWriter};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Cidr};
+use trust_dns::rr::record_type::RecordType;
+use tun_tap_mac::Iface;
+
+use crate::dns;
+use crate::error::Error;
+use crate::ethernet::MacAddress;
+use crate::loopback;
+use crate::phy::Phy;
+use crate::stack::Stack;
+use crate::tap;
+
+/// The options mget, ping and traceroute share for setting up their interface.
+pub fn interface_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("tap-device")
+            .long("tap")
+            .takes_value(true)
+            .value_name("tap-device")
+            .required_unless("loopback")
+            .help("TAP device to send the traffic from"),
+        Arg::with_name("loopback")
+            .long("loopback")
+            .conflicts_with_all(&["tap-device", "address"])
+            .help("Uses a loopback device instead of a TAP device"),
+        Arg::with_name("mac")
+            .long("mac")
+            .takes_value(true)
+            .value_name("address")
+            .help("MAC address for the interface instead of one derived from the machine-id"),
+        Arg::with_name("mac-seed")
+            .long("mac-seed")
+            .takes_value(true)
+            .value_name("text")
+            .conflicts_with("mac")
+            .help("Derives the MAC address from this text instead of the machine-id"),
+        Arg::with_name("address")
+            .long("address")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("cidr")
+            .help("Static address for the interface, e.g. 192.168.42.1/24 or 2001:db8::1/64, instead of DHCP and SLAAC"),
+        Arg::with_name("gateway")
+            .long("gateway")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("address")
+            .help("Default gateway to use with --address, one per address family"),
+        Arg::with_name("dns-server")
+            .long("dns-server")
+            .takes_value(true)
+            .value_name("ip")
+            .help("DNS server to use instead of the one from DHCP, or 1.1.1.1"),
+        Arg::with_name("pcap")
+            .long("pcap")
+            .takes_value(true)
+            .value_name("file")
+            .help("Records every frame sent or received to a pcap file"),
+    ]
+}
+
+/// What a tool does with its device, whichever kind was opened.
+pub trait Run {
+    fn run<D : Phy>(self, app : &ArgMatches, device : D) -> Result<(), Error>;
+}
+
+/// Opens the loopback or TAP device `app` asks for, recording to a pcap
+/// file if asked to, and runs `tool` on it.
+pub fn with_device<R : Run>(app : &ArgMatches, tool : R) -> Result<(), Error> {
+    let pcap = match app.value_of("pcap") {
+        Some(path) => Some(File::create(path).map_err(|e| Error::io(format!("unable to create {}", path), e))?),
+        None => None,
+    };
+    if app.is_present("loopback") {
+        let device = Loopback::new(Medium::Ethernet);
+        match pcap {
+            Some(pcap) => tool.run(app, PcapWriter::new(device, pcap, PcapMode::Both)),
+            None => tool.run(app, device),
+        }
+    } else {
+        let tap_text = app.value_of("tap-device").unwrap();
+        let device = Iface::without_packet_info(tap_text, tun_tap_mac::Mode::Tap)
+            .and_then(tap::TapDevice::new)
+            .map_err(|e| Error::io(format!("unable to use {} as a network interface", tap_text), e))?;
+        match pcap {
+            Some(pcap) => tool.run(app, PcapWriter::new(device, pcap, PcapMode::Both)),
+            None => tool.run(app, device),
+        }
+    }
+}
+
+/// The value of the `name` argument, which has to be there or have a default.
+pub fn value<T>(app : &ArgMatches, name : &str) -> Result<T, Error>
+    where T : FromStr, T::Err : StdError + Send + Sync + 'static
+{
+    app.value_of(name).unwrap()
+        .parse()
+        .map_err(|e| Error::parse(format!("<{}>", name), e))
+}
+
+/// The value of the `name` argument as a number of seconds.
+pub fn seconds(app : &ArgMatches, name : &str) -> Result<Duration, Error> {
+    Duration::try_from_secs_f64(value(app, name)?).map_err(|e| Error::parse(format!("<{}>", name), e))
+}
+
+/// The MAC address of `app`, else one derived from its seed, else from the
+/// machine-id and the interface name, so that reruns look like the same device.
+pub fn mac_address(app : &ArgMatches) -> Result<MacAddress, Error> {
+    if app.is_present("mac") {
+        let mac : MacAddress = value(app, "mac")?;
+        if mac.is_multicast() {
+            return Err(Error::Usage("<mac> must be a unicast address".to_string()));
+        }
+        return Ok(mac);
+    }
+    if let Some(seed) = app.value_of("mac-seed") {
+        return Ok(MacAddress::from_seed(seed.as_bytes()));
+    }
+    let interface = app.value_of("tap-device").unwrap_or("loopback");
+    Ok(MacAddress::for_interface(interface).unwrap_or_else(|e| {
+        eprintln!("warning: unable to read the machine-id ({}), using a random MAC address", e);
+        MacAddress::new()
+    }))
+}
+
+/// Gives `stack` the loopback addresses, the static ones of `app` or the
+/// ones DHCP and SLAAC come up with, for the families asked for.
+pub fn configure<D : Phy>(app : &ArgMatches, stack : &mut Stack<D>, ipv4 : bool, ipv6 : bool) -> Result<(), Error> {
+    let mut gateways : Vec<IpAddress> = Vec::new();
+    for gateway in app.values_of("gateway").into_iter().flatten() {
+        // smoltcp's address types fail to parse with ()
+        let gateway = gateway.parse()
+            .map_err(|_| Error::Usage(format!("unable to parse <gateway> {:?} as an IP address", gateway)))?;
+        gateways.push(gateway);
+    }
+    match app.values_of("address") {
+        _ if app.is_present("loopback") => {
+            stack.configure(Ipv4Cidr::new(loopback::IPV4_ADDRESS, 8).into(), None);
+            stack.configure(Ipv6Cidr::new(loopback::IPV6_ADDRESS, 64).into(), None);
+        }
+        Some(addresses) => {
+            for address in addresses {
+                let address : IpCidr = address.parse()
+                    .map_err(|_| Error::Usage(format!("unable to parse <address> {:?} as a CIDR", address)))?;
+                let gateway = gateways.iter()
+                    .find(|gateway| gateway.version() == address.address().version())
+                    .copied();
+                stack.configure(address, gateway);
+            }
+        }
+        None => {
+            stack.autoconfigure(ipv4, ipv6, Duration::from_secs(10)).map_err(|e| {
+                if e.kind() != std::io::ErrorKind::TimedOut {
+                    return Error::io("unable to autoconfigure the interface", e);
+                }
+                eprintln!("hint: pass --address for a static configuration");
+                Error::Timeout(format!("autoconfiguration ({})", e))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// The DNS server of `app`, else the first one from DHCP, else a public one.
+pub fn dns_server<D>(app : &ArgMatches, stack : &Stack<D>, ipv4 : bool) -> Result<IpAddr, Error> {
+    if app.is_present("dns-server") {
+        return value(app, "dns-server");
+    }
+    Ok(match stack.dns_servers.first() {
+        Some(ip) => IpAddr::V4((*ip).into()),
+        None if !ipv4 => "2606:4700:4700::1111".parse().unwrap(),
+        None => "1.1.1.1".parse().unwrap(),
+    })
+}
+
+/// The IPv4 address `host` stands for: itself when it is one, else its A
+/// record from the DNS server of `app`. A loopback run only reaches itself.
+pub fn ipv4_destination<D>(app : &ArgMatches, stack : &Stack<D>, host : &str) -> Result<Ipv4Address, Error> {
+    if let Ok(addr) = host.parse::<std::net::Ipv4Addr>() {
+        return Ok(addr.into());
+    }
+    if app.is_present("loopback") {
+        return Ok(loopback::IPV4_ADDRESS);
+    }
+    let mut upstream = dns::HostUdp(dns_server(app, stack, true)?.to_string());
+    match dns::resolve(&mut upstream, host, &[RecordType::A])?.first() {
+        Some(IpAddr::V4(addr)) => Ok((*addr).into()),
+        _ => Err(Error::NoAddresses(host.to_string())),
+    }
+}
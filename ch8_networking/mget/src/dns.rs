@@ -1,13 +1,19 @@
-use std::error::Error;
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
 
-use trust_dns::op::{Message, MessageType, OpCode, Query};
-use trust_dns::proto::error::ProtoError;
+use smoltcp::wire::IpEndpoint;
+
+use trust_dns::op::{Message, MessageType, OpCode, Query, ResponseCode};
 use trust_dns::rr::domain::Name;
+use trust_dns::rr::record_data::RData;
 use trust_dns::rr::record_type::RecordType;
 use trust_dns::serialize::binary::*;
 
+use crate::error::Error;
+use crate::phy::Phy;
+use crate::stack::Stack;
+
 fn message_id() -> u16 {
     let candidate = rand::random();
     if candidate == 0 {
@@ -15,67 +21,399 @@ fn message_id() -> u16 {
     }
     candidate
 }
-#[derive(Debug)]
-pub enum DnsError {
-    ParseDomainName(ProtoError),
-    ParseDnsServiceAddress(std::net::AddrParseError),
-    Encoing(ProtoError),
-    Decoding(ProtoError),
-    Network(std::io::Error),
-    Sending(std::io::Error),
-    Receiving(std::io::Error)
+/// Carries an encoded query to a DNS server and brings back its answer:
+/// the first datagram from the server that `accept` takes. Anything else,
+/// such as a stale or spoofed answer, is skipped until the timeout.
+pub trait Upstream {
+    fn exchange(&mut self, request : &[u8], accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error>;
 }
 
-impl std::fmt::Display for DnsError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:#?}", self)
+/// Queries go through the host's own network stack.
+pub struct HostUdp(pub String);
+
+/// Queries go out of mget's smoltcp interface, next to the HTTP traffic.
+pub struct StackUdp<'a, D>(pub &'a mut Stack<D>, pub IpAddr);
+
+impl Upstream for HostUdp {
+    fn exchange(&mut self, request : &[u8], accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+        let dns_server_ip : IpAddr = self.0.parse().map_err(|e| Error::parse("the DNS server address", e))?;
+        let dns_server = SocketAddr::new(dns_server_ip, 53);
+        let what = format!("DNS query to {}", dns_server_ip);
+        let mut response_buffer : Vec<u8> = vec![0;512];
+        let any = if dns_server.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let localhost = UdpSocket::bind(any).map_err(|e| Error::io("unable to open a UDP socket", e))?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        localhost
+            .set_nonblocking(false)
+            .map_err(|e| Error::io("unable to set up a UDP socket", e))?;
+        let _n_bytes_sent = localhost
+            .send_to(request, dns_server)
+            .map_err(|e| Error::network(what.clone(), e))?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout(what));
+            }
+            localhost
+                .set_read_timeout(Some(remaining))
+                .map_err(|e| Error::io("unable to set up a UDP socket", e))?;
+            let (b_bytes_recv, remote_port) = localhost
+                .recv_from(&mut response_buffer)
+                .map_err(|e| Error::network(what.clone(), e))?;
+            if remote_port == dns_server && accept(&response_buffer[..b_bytes_recv]) {
+                response_buffer.truncate(b_bytes_recv);
+                return Ok(response_buffer);
+            }
+        }
     }
 }
 
-impl std::error::Error for DnsError {}
+impl<'a, D : Phy> Upstream for StackUdp<'a, D> {
+    fn exchange(&mut self, request : &[u8], accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+        let dns_server = IpEndpoint::new(self.1.into(), 53);
+        let response = self.0
+            .udp_exchange(dns_server, request, accept, Duration::from_secs(5))
+            .map_err(|e| Error::network(format!("DNS query to {}", self.1), e))?;
+        Ok(response)
+    }
+}
+
+/// Whether `response` is the server's answer to `request`, not to some
+/// other query.
+fn answers(request : &Message, response : &Message) -> bool {
+    response.id() == request.id()
+        && response.message_type() == MessageType::Response
+        && response.queries() == request.queries()
+}
 
-pub fn resolve(dns_server_address : &str, domain_name: &str)
-    -> Result<Option<std::net::IpAddr>, Box<dyn Error>> 
+fn query(upstream : &mut dyn Upstream, domain_name : Name, record_type : RecordType)
+    -> Result<Message, Error>
 {
-    let domain_name = Name::from_ascii(domain_name).map_err(DnsError::ParseDomainName)?;
-    let dns_server_address = format!("{}:53", dns_server_address);
-    let dns_server : SocketAddr = dns_server_address.parse().map_err(DnsError::ParseDnsServiceAddress)?;
     let mut request_buffer : Vec<u8> = Vec::with_capacity(64);
-    let mut response_buffer : Vec<u8> = vec![0;512];
     let mut request = Message::new();
-    request.add_query(Query::query(domain_name, RecordType::A));
+    request.add_query(Query::query(domain_name, record_type));
     request
         .set_id(message_id())
         .set_message_type(MessageType::Query)
         .set_op_code(OpCode::Query)
         .set_recursion_desired(true);
-    let localhost = UdpSocket::bind("0.0.0.0:0").map_err(DnsError::Network)?;
-    let timeout = Duration::from_secs(5);
-    localhost
-        .set_nonblocking(false)
-        .map_err(DnsError::Network)?;
     let mut encoder = BinEncoder::new(&mut request_buffer);
-    request.emit(&mut encoder).map_err(DnsError::Encoing)?;
-    let _n_bytes_sent = localhost
-        .send_to(&request_buffer, dns_server)
-        .map_err(DnsError::Sending)?;
-    loop {
-        let (_b_bytes_recv, remote_port) = localhost
-            .recv_from(&mut response_buffer)
-            .map_err(DnsError::Receiving)?;
-        if remote_port == dns_server {
-            break;
+    request.emit(&mut encoder).map_err(|e| Error::Usage(format!("unable to encode the query: {}", e)))?;
+    let accept = |bytes : &[u8]| Message::from_vec(bytes).is_ok_and(|response| answers(&request, &response));
+    let response_buffer = upstream.exchange(&request_buffer, &accept)?;
+    let response = Message::from_vec(&response_buffer)
+        .map_err(|e| Error::Protocol(format!("undecodable DNS message: {}", e)))?;
+    Ok(response)
+}
+
+/// Looks up the addresses of `domain_name` with one query per record type
+/// in `record_types`, A or AAAA, keeping their order. A query that fails
+/// doesn't stop the others, so a server that chokes on AAAA still gives the
+/// A records. Fails when the name doesn't exist, or with the last failure
+/// when no addresses came back.
+pub fn resolve(upstream : &mut dyn Upstream, domain_name: &str, record_types : &[RecordType])
+    -> Result<Vec<std::net::IpAddr>, Error>
+{
+    let name = domain_name;
+    let domain_name = Name::from_ascii(domain_name)
+        // ProtoError isn't a std::error::Error
+        .map_err(|e| Error::Parse { what : format!("{:?} as a domain name", name), source : e.to_string().into() })?;
+    let mut addrs = Vec::new();
+    let mut failure = None;
+    for &record_type in record_types {
+        let response = match query(upstream, domain_name.clone(), record_type) {
+            Ok(response) => response,
+            Err(e) => {
+                failure = Some(e);
+                continue;
+            }
+        };
+        match response.response_code() {
+            ResponseCode::NoError => {}
+            ResponseCode::NXDomain => return Err(Error::NxDomain(name.to_string())),
+            code => {
+                failure = Some(Error::DnsServer { name : name.to_string(), code });
+                continue;
+            }
+        }
+        for answer in response.answers(){
+            if answer.record_type() == record_type {
+                match answer.rdata().to_ip_addr() {
+                    Some(server_ip) => addrs.push(server_ip),
+                    None => failure = Some(Error::Protocol(format!("{} record without an address", record_type))),
+                }
+            }
         }
     }
-    let response = Message::from_vec(&response_buffer)
-        .map_err(DnsError::Decoding)?;
-    for answer in response.answers(){
-        if answer.record_type() == RecordType::A {
-            let resource = answer.rdata();
-            let server_ip = resource.to_ip_addr().expect("invalid IP address received");
-            return Ok(Some(server_ip));
+    match failure {
+        Some(e) if addrs.is_empty() => Err(e),
+        _ => Ok(addrs),
+    }
+}
+
+/// The in-addr.arpa or ip6.arpa name holding the PTR records of `addr`.
+pub fn reverse_name(addr : IpAddr) -> Name {
+    let labels : Vec<String> = match addr {
+        IpAddr::V4(addr) => addr.octets().iter().rev().map(|octet| octet.to_string())
+            .chain(vec!["in-addr".to_string(), "arpa".to_string()])
+            .collect(),
+        IpAddr::V6(addr) => addr.octets().iter().rev()
+            .flat_map(|octet| vec![format!("{:x}", octet & 0x0f), format!("{:x}", octet >> 4)])
+            .chain(vec!["ip6".to_string(), "arpa".to_string()])
+            .collect(),
+    };
+    Name::from_labels(labels).expect("reverse names are always valid")
+}
+
+/// Looks up the host names of `addr`, e.g. to log which peer we talk to.
+pub fn reverse(upstream : &mut dyn Upstream, addr : IpAddr)
+    -> Result<Vec<String>, Error>
+{
+    let response = query(upstream, reverse_name(addr), RecordType::PTR)?;
+    let mut names = Vec::new();
+    for answer in response.answers() {
+        if let RData::PTR(name) = answer.rdata() {
+            names.push(name.to_string().trim_end_matches('.').to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Remembers the answers of `resolve` and `reverse` for the rest of the run,
+/// so that fetching several URLs from one host asks only once. Failed
+/// lookups of addresses are not remembered, but a failed reverse lookup is
+/// remembered as finding no names, so that a server which never answers
+/// them costs one timeout rather than one per request.
+pub struct Cache {
+    record_types : Vec<RecordType>,
+    addrs : HashMap<String, Vec<IpAddr>>,
+    names : HashMap<IpAddr, Vec<String>>,
+}
+
+impl Cache {
+    pub fn new(record_types : &[RecordType]) -> Self {
+        Cache { record_types : record_types.to_vec(), addrs : HashMap::new(), names : HashMap::new() }
+    }
+
+    pub fn resolve(&mut self, upstream : &mut dyn Upstream, domain_name : &str)
+        -> Result<Vec<IpAddr>, Error>
+    {
+        let key = domain_name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.addrs.get(&key) {
+            return Ok(addrs.clone());
         }
+        let addrs = resolve(upstream, domain_name, &self.record_types)?;
+        self.addrs.insert(key, addrs.clone());
+        Ok(addrs)
     }
-    Ok(None)
 
-}
\ No newline at end of file
+    pub fn reverse(&mut self, upstream : &mut dyn Upstream, addr : IpAddr)
+        -> Result<Vec<String>, Error>
+    {
+        if let Some(names) = self.names.get(&addr) {
+            return Ok(names.clone());
+        }
+        let names = reverse(upstream, addr);
+        self.names.insert(addr, names.as_ref().cloned().unwrap_or_default());
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns::rr::Record;
+
+    /// Answers every query with the records of `answers` of the queried
+    /// type, and CNAMEs.
+    struct Canned(Vec<Record>);
+
+    impl Upstream for Canned {
+        fn exchange(&mut self, request : &[u8], _accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+            let request = Message::from_vec(request).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .add_queries(request.queries().to_vec())
+                .add_answers(self.0.iter()
+                    .filter(|answer| answer.record_type() == request.queries()[0].query_type()
+                        || answer.record_type() == RecordType::CNAME)
+                    .cloned());
+            Ok(response.to_vec().unwrap())
+        }
+    }
+
+    #[test]
+    fn resolves_through_any_upstream() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut upstream = Canned(vec![
+            Record::from_rdata(name.clone(), 60, RData::CNAME(Name::from_ascii("www.example.com.").unwrap())),
+            Record::from_rdata(name.clone(), 60, RData::A("192.0.2.1".parse().unwrap())),
+            Record::from_rdata(name, 60, RData::AAAA("2001:db8::1".parse().unwrap())),
+        ]);
+        let addrs = resolve(&mut upstream, "example.com", &[RecordType::AAAA, RecordType::A]).unwrap();
+        assert_eq!(addrs, vec!["2001:db8::1".parse::<IpAddr>().unwrap(), "192.0.2.1".parse().unwrap()]);
+        let addrs = resolve(&mut upstream, "example.com", &[RecordType::A]).unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    /// Races the answers of `Canned` with forgeries of them, which come
+    /// first, the way an off-path attacker's would. With `false` the genuine
+    /// answer is lost.
+    struct Spoofed(Canned, Vec<fn(&mut Message)>, bool);
+
+    impl Upstream for Spoofed {
+        fn exchange(&mut self, request : &[u8], accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+            let genuine = self.0.exchange(request, &|_| true)?;
+            let mut datagrams = Vec::new();
+            for forge in &self.1 {
+                let mut forgery = Message::from_vec(&genuine).unwrap();
+                forge(&mut forgery);
+                datagrams.push(forgery.to_vec().unwrap());
+            }
+            if self.2 {
+                datagrams.push(genuine);
+            }
+            datagrams.into_iter().find(|datagram| accept(datagram))
+                .ok_or_else(|| Error::Timeout("DNS query".to_string()))
+        }
+    }
+
+    /// Makes `forgery` the answer to another question.
+    fn ask(forgery : &mut Message, name : &str, record_type : RecordType) {
+        let mut other = Message::new();
+        other
+            .set_id(forgery.id())
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type))
+            .add_answers(forgery.answers().to_vec());
+        *forgery = other;
+    }
+
+    #[test]
+    fn skips_answers_to_other_queries() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let canned = || Canned(vec![Record::from_rdata(name.clone(), 60, RData::A("192.0.2.1".parse().unwrap()))]);
+        let forgeries : Vec<fn(&mut Message)> = vec![
+            |forgery| { forgery.set_id(forgery.id().wrapping_add(1)); },
+            |forgery| { forgery.set_message_type(MessageType::Query); },
+            |forgery| ask(forgery, "example.org.", RecordType::A),
+            |forgery| ask(forgery, "example.com.", RecordType::AAAA),
+        ];
+        let mut upstream = Spoofed(canned(), forgeries.clone(), true);
+        let addrs = resolve(&mut upstream, "example.com", &[RecordType::A]).unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+
+        let mut upstream = Spoofed(canned(), forgeries, false);
+        let e = resolve(&mut upstream, "example.com", &[RecordType::A]).unwrap_err();
+        assert_eq!(e.exit_code(), 9);
+    }
+
+    /// Fails every query, to show that an answer came from a cache.
+    struct Unreachable;
+
+    impl Upstream for Unreachable {
+        fn exchange(&mut self, _request : &[u8], _accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+            Err(Error::Unreachable("the DNS server".to_string()))
+        }
+    }
+
+    /// Answers every query with response code `self.0` and no records.
+    struct Failing(ResponseCode);
+
+    impl Upstream for Failing {
+        fn exchange(&mut self, request : &[u8], _accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+            let request = Message::from_vec(request).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_response_code(self.0)
+                .add_queries(request.queries().to_vec());
+            Ok(response.to_vec().unwrap())
+        }
+    }
+
+    #[test]
+    fn reports_failed_lookups() {
+        let e = resolve(&mut Failing(ResponseCode::NXDomain), "nowhere.example", &[RecordType::A]).unwrap_err();
+        assert!(matches!(e, Error::NxDomain(ref name) if name == "nowhere.example"));
+        assert_eq!(e.exit_code(), 6);
+        let e = resolve(&mut Failing(ResponseCode::ServFail), "example.com", &[RecordType::A]).unwrap_err();
+        assert_eq!(e.to_string(), "DNS server answered Server Failure for example.com");
+        assert!(reverse(&mut Failing(ResponseCode::NXDomain), "192.0.2.1".parse().unwrap()).unwrap().is_empty());
+    }
+
+    /// Answers like `Canned`, except that queries for the given record
+    /// type get the given response code, or no answer at all with `None`.
+    struct Choking(Canned, RecordType, Option<ResponseCode>);
+
+    impl Upstream for Choking {
+        fn exchange(&mut self, request : &[u8], accept : &dyn Fn(&[u8]) -> bool) -> Result<Vec<u8>, Error> {
+            let query = Message::from_vec(request).unwrap();
+            if query.queries()[0].query_type() != self.1 {
+                return self.0.exchange(request, accept);
+            }
+            match self.2 {
+                Some(code) => Failing(code).exchange(request, accept),
+                None => Err(Error::Timeout("DNS query".to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn one_failed_record_type_is_not_fatal() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let canned = || Canned(vec![Record::from_rdata(name.clone(), 60, RData::A("192.0.2.1".parse().unwrap()))]);
+        let a : IpAddr = "192.0.2.1".parse().unwrap();
+        for choke in [Some(ResponseCode::ServFail), Some(ResponseCode::Refused), None] {
+            let mut upstream = Choking(canned(), RecordType::AAAA, choke);
+            assert_eq!(resolve(&mut upstream, "example.com", &[RecordType::AAAA, RecordType::A]).unwrap(), vec![a]);
+        }
+        let mut upstream = Choking(canned(), RecordType::A, Some(ResponseCode::ServFail));
+        let e = resolve(&mut upstream, "example.com", &[RecordType::AAAA, RecordType::A]).unwrap_err();
+        assert_eq!(e.exit_code(), 6);
+        let mut upstream = Choking(canned(), RecordType::A, None);
+        let e = resolve(&mut upstream, "example.com", &[RecordType::A]).unwrap_err();
+        assert_eq!(e.exit_code(), 9);
+        let mut upstream = Choking(canned(), RecordType::AAAA, Some(ResponseCode::NXDomain));
+        let e = resolve(&mut upstream, "example.com", &[RecordType::AAAA, RecordType::A]).unwrap_err();
+        assert!(matches!(e, Error::NxDomain(_)));
+    }
+
+    #[test]
+    fn caches_answers() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut upstream = Canned(vec![
+            Record::from_rdata(name, 60, RData::A("192.0.2.1".parse().unwrap())),
+            Record::from_rdata(reverse_name("192.0.2.1".parse().unwrap()), 60,
+                RData::PTR(Name::from_ascii("host.example.").unwrap())),
+        ]);
+        let mut cache = Cache::new(&[RecordType::A]);
+        let addr : IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(cache.resolve(&mut upstream, "example.com").unwrap(), vec![addr]);
+        assert_eq!(cache.reverse(&mut upstream, addr).unwrap(), vec!["host.example".to_string()]);
+
+        assert_eq!(cache.resolve(&mut Unreachable, "Example.com.").unwrap(), vec![addr]);
+        assert_eq!(cache.reverse(&mut Unreachable, addr).unwrap(), vec!["host.example".to_string()]);
+        assert!(cache.resolve(&mut Unreachable, "example.org").is_err());
+        let elsewhere : IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(cache.reverse(&mut Unreachable, elsewhere).is_err());
+        let mut answering = Canned(vec![
+            Record::from_rdata(reverse_name(elsewhere), 60, RData::PTR(Name::from_ascii("other.example.").unwrap())),
+        ]);
+        assert!(cache.reverse(&mut answering, elsewhere).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reverse_names() {
+        let name = reverse_name("192.0.2.1".parse().unwrap());
+        assert_eq!(name.to_string(), "1.2.0.192.in-addr.arpa.");
+        let mut upstream = Canned(vec![
+            Record::from_rdata(name, 60, RData::PTR(Name::from_ascii("host.example.").unwrap())),
+        ]);
+        let names = reverse(&mut upstream, "192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(names, vec!["host.example".to_string()]);
+    }
+}
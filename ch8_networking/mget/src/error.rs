@@ -0,0 +1,188 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use smoltcp::socket::tcp;
+use trust_dns::op::ResponseCode;
+
+/// The exit statuses of mget, ping and traceroute, which the sample crate
+/// exits with too. 2 to 8 mean what they mean for wget, the rest are ours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Usage = 2,
+    Io = 3,
+    Network = 4,
+    Tls = 5,
+    Dns = 6,
+    Protocol = 7,
+    Status = 8,
+    Timeout = 9,
+    Refused = 10,
+}
+
+impl Kind {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Prints `error` and its causes to stderr and exits with the status of `kind`.
+pub fn exit(error : &dyn StdError, kind : Kind) -> ! {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    eprintln!("error: {}", message);
+    std::process::exit(kind.exit_code())
+}
+
+/// Everything that can go wrong in mget, ping and traceroute.
+#[derive(Debug)]
+pub enum Error {
+    /// A command line that asks for something impossible.
+    Usage(String),
+    /// A command-line value, or another piece of user input, that doesn't parse.
+    Parse { what : String, source : Box<dyn StdError + Send + Sync> },
+    /// Files, the TAP device and the output.
+    Io { context : String, source : io::Error },
+    Network { context : String, source : Option<io::Error> },
+    Unreachable(String),
+    /// Says what timed out, e.g. "connection to 192.0.2.1:80".
+    Timeout(String),
+    /// Says what was refused, e.g. "connection to 192.0.2.1:80".
+    Refused(String),
+    NxDomain(String),
+    NoAddresses(String),
+    DnsServer { name : String, code : ResponseCode },
+    /// A peer that doesn't speak HTTP, DNS or ICMP the way it should.
+    Protocol(String),
+    Tls(Box<dyn StdError + Send + Sync>),
+    /// An HTTP error response.
+    Status { status : u16, reason : String },
+}
+
+impl Error {
+    pub fn io(context : impl Into<String>, source : io::Error) -> Error {
+        Error::Io { context : context.into(), source }
+    }
+
+    /// Sorts an error from a network operation on `what` into a timeout, a
+    /// refusal or some other network failure.
+    pub fn network(what : impl Into<String>, source : io::Error) -> Error {
+        let what = what.into();
+        match source.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout(what),
+            io::ErrorKind::ConnectionRefused => Error::Refused(what),
+            _ => Error::Network { context : format!("{} failed", what), source : Some(source) },
+        }
+    }
+
+    pub fn parse<E : StdError + Send + Sync + 'static>(what : impl Into<String>, source : E) -> Error {
+        Error::Parse { what : what.into(), source : Box::new(source) }
+    }
+
+    pub fn tls<E : StdError + Send + Sync + 'static>(source : E) -> Error {
+        Error::Tls(Box::new(source))
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Error::Usage(_) | Error::Parse { .. } => Kind::Usage,
+            Error::Io { .. } => Kind::Io,
+            Error::Network { .. } | Error::Unreachable(_) => Kind::Network,
+            Error::Tls(_) => Kind::Tls,
+            Error::NxDomain(_) | Error::NoAddresses(_) | Error::DnsServer { .. } => Kind::Dns,
+            Error::Protocol(_) => Kind::Protocol,
+            Error::Status { .. } => Kind::Status,
+            Error::Timeout(_) => Kind::Timeout,
+            Error::Refused(_) => Kind::Refused,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind().exit_code()
+    }
+
+    pub fn exit(&self) -> ! {
+        exit(self, self.kind())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Parse { what, .. } => write!(f, "unable to parse {}", what),
+            Error::Io { context, .. } => write!(f, "{}", context),
+            Error::Network { context, .. } => write!(f, "{}", context),
+            Error::Unreachable(what) => write!(f, "{} is unreachable", what),
+            Error::Timeout(what) => write!(f, "{} timed out", what),
+            Error::Refused(what) => write!(f, "{} refused", what),
+            Error::NxDomain(name) => write!(f, "{} does not exist", name),
+            Error::NoAddresses(name) => write!(f, "no addresses for {}", name),
+            Error::DnsServer { name, code } => write!(f, "DNS server answered {} for {}", code, name),
+            Error::Protocol(message) => write!(f, "malformed response: {}", message),
+            Error::Tls(_) => write!(f, "TLS error"),
+            Error::Status { status, reason } => write!(f, "server answered {} {}", status, reason),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Parse { source, .. } | Error::Tls(source) => Some(source.as_ref()),
+            Error::Io { source, .. } => Some(source),
+            Error::Network { source, .. } => source.as_ref().map(|source| source as &(dyn StdError + 'static)),
+            _ => None,
+        }
+    }
+}
+
+impl From<tcp::ConnectError> for Error {
+    fn from(value : tcp::ConnectError) -> Self {
+        Error::Network { context : format!("unable to connect: {:?}", value), source : None }
+    }
+}
+
+impl From<tcp::SendError> for Error {
+    fn from(value : tcp::SendError) -> Self {
+        Error::Network { context : format!("unable to send: {:?}", value), source : None }
+    }
+}
+
+impl From<tcp::RecvError> for Error {
+    fn from(value : tcp::RecvError) -> Self {
+        Error::Network { context : format!("unable to receive: {:?}", value), source : None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_network_errors() {
+        let timeout = Error::network("DNS query to 192.0.2.53", io::ErrorKind::WouldBlock.into());
+        assert_eq!(timeout.to_string(), "DNS query to 192.0.2.53 timed out");
+        assert_eq!(timeout.exit_code(), 9);
+        let refused = Error::network("connection to 192.0.2.1:80", io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(refused.to_string(), "connection to 192.0.2.1:80 refused");
+        assert_eq!(refused.exit_code(), 10);
+        let other = Error::network("DNS query to 192.0.2.53", io::Error::other("no route"));
+        assert_eq!(other.to_string(), "DNS query to 192.0.2.53 failed");
+        assert_eq!(other.source().unwrap().to_string(), "no route");
+        assert_eq!(other.exit_code(), 4);
+    }
+
+    #[test]
+    fn chains_sources() {
+        let e = Error::parse("<count>", "x".parse::<u64>().unwrap_err());
+        assert_eq!(e.to_string(), "unable to parse <count>");
+        assert_eq!(e.source().unwrap().to_string(), "invalid digit found in string");
+        assert_eq!(e.exit_code(), 2);
+        assert!(Error::NxDomain("example.invalid".to_string()).source().is_none());
+    }
+}
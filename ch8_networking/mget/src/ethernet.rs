@@ -1,10 +1,18 @@
-use rand;
 use std::fmt;
 use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::str::FromStr;
 use rand::RngCore;
 use smoltcp::wire;
 
-#[derive(Debug)]
+/// Bit 0 of the first octet: set for multicast (and broadcast), clear for unicast.
+const MULTICAST_BIT : u8 = 0b_0000_0001;
+/// Bit 1 of the first octet: set when the address was assigned locally
+/// rather than out of a vendor's IEEE block.
+const LOCAL_BIT : u8 = 0b_0000_0010;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct MacAddress([u8;6]);
 
 impl Display for MacAddress {
@@ -20,23 +28,233 @@ impl Display for MacAddress {
 }
 
 impl MacAddress {
+    /// A random locally administered unicast address.
     pub fn new() -> MacAddress {
         let mut octets : [u8;6] = [0;6];
         rand::thread_rng().fill_bytes(&mut octets);
-        octets[0] |= 0b_0000_0010;
-        octets[0] &= 0b_1111_1110;
-        MacAddress {0:octets}
+        octets[0] |= LOCAL_BIT;
+        octets[0] &= !MULTICAST_BIT;
+        MacAddress(octets)
+    }
+    /// A locally administered unicast address that is the same for every
+    /// run given the same `seed`.
+    pub fn from_seed(seed : &[u8]) -> MacAddress {
+        // FNV-1a, which unlike std's hashers promises the same output forever
+        let mut hash : u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in seed {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        let mut octets : [u8;6] = [0;6];
+        octets.copy_from_slice(&hash.to_be_bytes()[2..]);
+        octets[0] |= LOCAL_BIT;
+        octets[0] &= !MULTICAST_BIT;
+        MacAddress(octets)
+    }
+    /// The address this host uses on `interface`, seeded with its machine-id
+    /// so that it keeps its neighbor cache entries and DHCP lease across runs.
+    pub fn for_interface(interface : &str) -> io::Result<MacAddress> {
+        let machine_id = fs::read_to_string("/etc/machine-id")
+            .or_else(|_| fs::read_to_string("/var/lib/dbus/machine-id"))?;
+        let machine_id = machine_id.trim();
+        if machine_id.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty machine-id"));
+        }
+        let mut seed = machine_id.as_bytes().to_vec();
+        seed.push(0);
+        seed.extend_from_slice(interface.as_bytes());
+        Ok(MacAddress::from_seed(&seed))
+    }
+    pub fn octets(&self) -> [u8;6] {
+        self.0
+    }
+    pub fn is_local(&self) -> bool {
+        (self.0[0] & LOCAL_BIT) == LOCAL_BIT
+    }
+    pub fn is_universal(&self) -> bool {
+        !self.is_local()
+    }
+    pub fn is_multicast(&self) -> bool {
+        (self.0[0] & MULTICAST_BIT) == MULTICAST_BIT
+    }
+    pub fn is_unicast(&self) -> bool {
+        !self.is_multicast()
+    }
+    pub fn is_broadcast(&self) -> bool {
+        self.0 == [0xff;6]
+    }
+    /// The first three octets, which name the vendor of a universal address.
+    pub fn oui(&self) -> [u8;3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+    /// The organization the IEEE assigned this address's OUI to, if it is a
+    /// universal address in the bundled registry.
+    #[cfg(feature = "oui")]
+    pub fn vendor(&self) -> Option<&'static str> {
+        if self.is_local() {
+            return None;
+        }
+        vendor(REGISTRY, self.oui())
     }
-    fn is_local(&self) -> bool {
-        (self.0[0] & 0b_0000_0010) == 0b_0000_0010
+}
+
+impl Default for MacAddress {
+    fn default() -> Self {
+        MacAddress::new()
+    }
+}
+
+impl From<[u8;6]> for MacAddress {
+    fn from(octets : [u8;6]) -> MacAddress {
+        MacAddress(octets)
+    }
+}
+
+impl From<MacAddress> for wire::EthernetAddress {
+    fn from(mac : MacAddress) -> wire::EthernetAddress {
+        wire::EthernetAddress(mac.0)
     }
-    fn is_unicast(&self) -> bool {
-        (self.0[0] & 0b_0000_0001) == 0b_0000_0001
+}
+
+impl From<wire::EthernetAddress> for MacAddress {
+    fn from(mac : wire::EthernetAddress) -> MacAddress {
+        MacAddress(mac.0)
     }
 }
 
-impl Into<wire::EthernetAddress> for MacAddress {
-    fn into(self) -> wire::EthernetAddress {
-        wire::EthernetAddress{0 : self.0}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseMacError {
+    /// Neither six colon or dash separated octets nor three dotted groups.
+    Format,
+    Digit(char),
+}
+
+impl fmt::Display for ParseMacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseMacError::Format => write!(f, "expected a MAC address like 02:00:5e:10:00:01, 02-00-5e-10-00-01 or 0200.5e10.0001"),
+            ParseMacError::Digit(c) => write!(f, "invalid hex digit {:?} in MAC address", c),
+        }
+    }
+}
+
+impl std::error::Error for ParseMacError {}
+
+impl FromStr for MacAddress {
+    type Err = ParseMacError;
+
+    /// Accepts `02:00:5e:10:00:01`, `02-00-5E-10-00-01` and Cisco's `0200.5e10.0001`.
+    fn from_str(s : &str) -> Result<MacAddress, ParseMacError> {
+        let (separator, groups) = if s.contains(':') {
+            (':', 6)
+        } else if s.contains('-') {
+            ('-', 6)
+        } else if s.contains('.') {
+            ('.', 3)
+        } else {
+            return Err(ParseMacError::Format);
+        };
+        let digits : Vec<&str> = s.split(separator).collect();
+        if digits.len() != groups || digits.iter().any(|group| group.len() != 12 / groups) {
+            return Err(ParseMacError::Format);
+        }
+        let digits = digits.concat();
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(ParseMacError::Digit(c));
+        }
+        let mut octets = [0;6];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap();
+        }
+        Ok(MacAddress(octets))
+    }
+}
+
+/// An excerpt of the IEEE MA-L registry. The full one from
+/// https://standards-oui.ieee.org/oui/oui.txt can be dropped in its place.
+#[cfg(feature = "oui")]
+static REGISTRY : &str = include_str!("../data/oui.txt");
+
+/// Looks `oui` up among the `(base 16)` lines of an IEEE oui.txt.
+#[cfg(feature = "oui")]
+fn vendor(registry : &str, oui : [u8;3]) -> Option<&str> {
+    let oui = format!("{:02X}{:02X}{:02X}", oui[0], oui[1], oui[2]);
+    registry.lines()
+        .filter_map(|line| line.split_once("(base 16)"))
+        .find(|(assignment, _)| assignment.trim() == oui)
+        .map(|(_, organization)| organization.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_addresses_are_local_unicast() {
+        for _ in 0..32 {
+            let mac = MacAddress::new();
+            assert!(mac.is_local());
+            assert!(!mac.is_universal());
+            assert!(mac.is_unicast());
+            assert!(!mac.is_multicast());
+        }
+    }
+
+    #[test]
+    fn seeded_addresses_are_stable() {
+        let mac = MacAddress::from_seed(b"tap-mget");
+        assert_eq!(mac, MacAddress::from_seed(b"tap-mget"));
+        assert_ne!(mac, MacAddress::from_seed(b"tap-mget2"));
+        assert!(mac.is_local() && mac.is_unicast());
+        // pinned, so that a change of hash shows up before the addresses change on the LAN
+        assert_eq!(MacAddress::from_seed(b"").to_string(), "9e:e4:84:22:23:25");
+        for seed in 0..32u8 {
+            let mac = MacAddress::from_seed(&[seed]);
+            assert!(mac.is_local() && mac.is_unicast());
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let vmware : MacAddress = [0x00, 0x50, 0x56, 0x01, 0x02, 0x03].into();
+        assert!(vmware.is_unicast() && vmware.is_universal());
+        let ipv4_multicast : MacAddress = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb].into();
+        assert!(ipv4_multicast.is_multicast() && ipv4_multicast.is_universal());
+        let broadcast = MacAddress::from([0xff;6]);
+        assert!(broadcast.is_broadcast() && broadcast.is_multicast() && broadcast.is_local());
+        assert!(!vmware.is_broadcast());
+    }
+
+    #[test]
+    fn parses_every_notation() {
+        let mac = MacAddress::from([0x02, 0x00, 0x5e, 0x10, 0x00, 0xab]);
+        assert_eq!("02:00:5e:10:00:ab".parse(), Ok(mac));
+        assert_eq!("02-00-5E-10-00-AB".parse(), Ok(mac));
+        assert_eq!("0200.5e10.00Ab".parse(), Ok(mac));
+        assert_eq!(mac.to_string().parse(), Ok(mac));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for text in ["", "02005e1000ab", "02:00:5e:10:00", "02:00:5e:10:00:ab:cd", "2:00:5e:10:00:ab",
+                     "02:00-5e:10:00:ab", "0200.5e10.00ab.", "02005e.1000ab", "02:00:5e:10:00:+a"] {
+            let parsed : Result<MacAddress, _> = text.parse();
+            assert!(parsed.is_err(), "{:?} parsed", text);
+        }
+        assert_eq!("02:00:5e:10:00:ag".parse::<MacAddress>(), Err(ParseMacError::Digit('g')));
+        assert_eq!("02:00:5e:10:00:é".parse::<MacAddress>(), Err(ParseMacError::Digit('é')));
+    }
+
+    #[cfg(feature = "oui")]
+    #[test]
+    fn looks_up_vendors() {
+        let mac : MacAddress = "00:50:56:c0:00:08".parse().unwrap();
+        assert_eq!(mac.vendor(), Some("VMware, Inc."));
+        let mac : MacAddress = "b8-27-eb-12-34-56".parse().unwrap();
+        assert_eq!(mac.vendor(), Some("Raspberry Pi Foundation"));
+        // the same OUI with the local bit set is nobody's
+        let mac : MacAddress = "02:50:56:c0:00:08".parse().unwrap();
+        assert_eq!(mac.vendor(), None);
+        assert_eq!(MacAddress::from([0x00, 0x00, 0x00, 0x00, 0x00, 0x01]).vendor(), None);
     }
 }
@@ -1,120 +1,769 @@
-use std::collections::BTreeMap;
 use std::fmt;
+use std::io;
+use std::io::prelude::*;
 use std::net::IpAddr;
-use std::os::unix::io::AsRawFd;
-use tun_tap_mac::Iface;
-use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache, Routes};
-use smoltcp::phy::{wait as phy_wait, TapInterface};
-use smoltcp::socket::{SocketSet, TcpSocket, TcpSocketBuffer};
+use std::sync::Arc;
+use rustls::{ClientConfig, ClientConnection};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
-use url::Url;
+use smoltcp::wire::IpAddress;
+use url::{Position, Url};
+
+use crate::error::Error;
+use crate::meter::TcpStats;
+use crate::phy::Phy;
+use crate::progress::{human_bytes, human_rate, Progress};
+use crate::stack::Stack;
+use crate::tls::SocketIo;
+
+const MAX_HEAD : usize = 64 * 1024;
+/// How long a server may leave a SYN or our data unacknowledged before the
+/// connection is given up on.
+const TIMEOUT : std::time::Duration = std::time::Duration::from_secs(30);
 
 #[derive(Debug)]
 enum HttpState {
-    Connect,
     Request,
     Response
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+}
+
+impl Method {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+        }
+    }
+}
+
+/// Status line and headers of a response. The body is streamed elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub version : String,
+    pub status : u16,
+    pub reason : String,
+    pub headers : Vec<(String, String)>,
+}
+
+impl Response {
+    /// First value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name : &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Where a redirect points to, relative to the URL that was fetched.
+    pub fn location(&self) -> Option<&str> {
+        match self.status {
+            301 | 302 | 303 | 307 | 308 => self.header("Location"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}\r\n", self.version, self.status, self.reason)?;
+        for (name, value) in &self.headers {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        write!(f, "\r\n")
+    }
+}
+
+/// How the connection for a request is set up.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// smoltcp scales the window it advertises when this is over 64 KiB.
+    pub rx_buffer : usize,
+    pub tx_buffer : usize,
+    /// Draws a progress bar on stderr while the body arrives.
+    pub progress : bool,
+    /// Asks the server to keep the connection open for further requests.
+    pub keep_alive : bool,
+    /// How long a connection may go without sending or receiving anything.
+    pub timeout : std::time::Duration,
+    /// Whether the caller follows redirects, in which case their bodies are
+    /// dropped rather than written out.
+    pub follow_redirects : bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            rx_buffer : 256 * 1024,
+            tx_buffer : 16 * 1024,
+            progress : false,
+            keep_alive : false,
+            timeout : TIMEOUT,
+            follow_redirects : true,
+        }
+    }
+}
+
+/// What a request took, for the summary after it.
 #[derive(Debug)]
-pub enum UpstreamError {
-    Network(smoltcp::wire::Error),
-    InvalidUrl,
-    Content(std::str::Utf8Error)
+pub struct Transfer {
+    pub bytes : u64,
+    pub elapsed : std::time::Duration,
+    pub tcp : TcpStats,
+    /// Whether the request went over a connection left open by an earlier one.
+    pub reused : bool,
 }
 
-impl fmt::Display for UpstreamError {
+impl fmt::Display for Transfer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(
+            f,
+            "{} in {:.2} s ({}), {} segments, {} retransmissions",
+            human_bytes(self.bytes),
+            self.elapsed.as_secs_f64(),
+            human_rate(self.bytes, self.elapsed),
+            self.tcp.segments,
+            self.tcp.retransmissions,
+        )?;
+        if let Some(avg) = self.tcp.rtt_avg() {
+            write!(
+                f,
+                ", rtt min/avg/max {:.3}/{:.3}/{:.3} ms",
+                self.tcp.rtt_min.total_micros() as f64 / 1000.0,
+                avg.total_micros() as f64 / 1000.0,
+                self.tcp.rtt_max.total_micros() as f64 / 1000.0,
+            )?;
+        }
+        if self.reused {
+            write!(f, ", connection reused")?;
+        }
+        Ok(())
     }
 }
 
-impl From<smoltcp::wire::Error> for UpstreamError {
-    fn from(value: smoltcp::wire::Error) -> Self {
-        UpstreamError::Network(value)
+/// Counts what goes through to the sink, for the progress bar if any.
+struct Counter<'a, W> {
+    inner : &'a mut W,
+    bytes : u64,
+    progress : Option<Progress>,
+}
+
+impl<'a, W : Write> Write for Counter<'a, W> {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        let amt = self.inner.write(buf)?;
+        self.bytes += amt as u64;
+        Ok(amt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-impl From<std::str::Utf8Error> for UpstreamError {
-    fn from(value: std::str::Utf8Error) -> Self {
-        UpstreamError::Content(value)
+#[derive(Debug, PartialEq)]
+enum Phase {
+    Head,
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+/// Incremental HTTP/1.1 response parser. Bytes go in as they arrive from the
+/// socket and the decoded body is written to a sink, so neither the framing
+/// of TCP segments nor chunk boundaries leak into the output. The bodies of
+/// redirects are dropped when they are going to be followed.
+pub struct ResponseParser {
+    method : Method,
+    follow_redirects : bool,
+    phase : Phase,
+    buffer : Vec<u8>,
+    response : Option<Response>,
+}
+
+impl ResponseParser {
+    pub fn new(method : Method, follow_redirects : bool) -> Self {
+        ResponseParser { method, follow_redirects, phase : Phase::Head, buffer : Vec::new(), response : None }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// The size of the body, once the head has given it.
+    pub fn content_length(&self) -> Option<u64> {
+        let response = self.response.as_ref()?;
+        if self.method == Method::Head || response.header("Transfer-Encoding").is_some() {
+            return None;
+        }
+        response.header("Content-Length")?.trim().parse().ok()
+    }
+
+    pub fn feed<W : Write>(&mut self, data : &[u8], body : &mut W) -> Result<(), Error> {
+        self.buffer.extend_from_slice(data);
+        loop {
+            let progressed = match self.phase {
+                Phase::Head => self.head()?,
+                Phase::Length(remaining) => {
+                    let amt = remaining.min(self.buffer.len());
+                    self.write(amt, body)?;
+                    self.phase = if amt == remaining { Phase::Done } else { Phase::Length(remaining - amt) };
+                    amt > 0
+                }
+                Phase::ChunkSize => match self.line() {
+                    Some(line) => {
+                        let size = line.split(';').next().unwrap_or("").trim();
+                        let size = usize::from_str_radix(size, 16)
+                            .map_err(|_| Error::Protocol(format!("invalid chunk size: {:?}", line)))?;
+                        self.phase = if size == 0 { Phase::Trailers } else { Phase::ChunkData(size) };
+                        true
+                    }
+                    None => false,
+                },
+                Phase::ChunkData(remaining) => {
+                    let amt = remaining.min(self.buffer.len());
+                    self.write(amt, body)?;
+                    self.phase = if amt == remaining { Phase::ChunkEnd } else { Phase::ChunkData(remaining - amt) };
+                    amt > 0
+                }
+                Phase::ChunkEnd => match self.line() {
+                    Some(line) if line.is_empty() => {
+                        self.phase = Phase::ChunkSize;
+                        true
+                    }
+                    Some(_) => return Err(Error::Protocol("chunk is longer than its size".to_string())),
+                    None => false,
+                },
+                Phase::Trailers => match self.line() {
+                    Some(line) => {
+                        if line.is_empty() {
+                            self.phase = Phase::Done;
+                        }
+                        true
+                    }
+                    None => false,
+                },
+                Phase::UntilClose => {
+                    self.write(self.buffer.len(), body)?;
+                    false
+                }
+                Phase::Done => {
+                    self.buffer.clear();
+                    false
+                }
+            };
+            if !progressed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Called once the server has closed the connection.
+    pub fn finish(self) -> Result<Response, Error> {
+        match (self.phase, self.response) {
+            (Phase::Done, Some(response)) | (Phase::UntilClose, Some(response)) => Ok(response),
+            (_, None) => Err(Error::Protocol("connection closed before the response head".to_string())),
+            (_, Some(_)) => Err(Error::Protocol("connection closed before the end of the body".to_string())),
+        }
+    }
+
+    /// Moves `amt` body bytes from the buffer to `body`.
+    fn write<W : Write>(&mut self, amt : usize, body : &mut W) -> Result<(), Error> {
+        let followed = self.follow_redirects
+            && self.response.as_ref().and_then(|response| response.location()).is_some();
+        if !followed {
+            body.write_all(&self.buffer[..amt]).map_err(|e| Error::io("unable to write the body", e))?;
+        }
+        self.buffer.drain(..amt);
+        Ok(())
+    }
+
+    /// Takes one CRLF-terminated line off the buffer.
+    fn line(&mut self) -> Option<String> {
+        let end = self.buffer.windows(2).position(|pair| pair == b"\r\n")?;
+        let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        self.buffer.drain(..end + 2);
+        Some(line)
+    }
+
+    fn head(&mut self) -> Result<bool, Error> {
+        let end = match self.buffer.windows(4).position(|quad| quad == b"\r\n\r\n") {
+            Some(end) => end,
+            None if self.buffer.len() > MAX_HEAD => {
+                return Err(Error::Protocol("response head is too large".to_string()))
+            }
+            None => return Ok(false),
+        };
+        let head = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        self.buffer.drain(..end + 4);
+        let response = parse_head(&head)?;
+
+        // informational responses such as 100 Continue are followed by the real one
+        if response.status / 100 == 1 {
+            return Ok(true);
+        }
+        let chunked = response
+            .header("Transfer-Encoding")
+            .map(|value| value.to_ascii_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        self.phase = if self.method == Method::Head || response.status == 204 || response.status == 304 {
+            Phase::Done
+        } else if chunked {
+            Phase::ChunkSize
+        } else if let Some(length) = response.header("Content-Length") {
+            match length.trim().parse::<usize>() {
+                Ok(0) => Phase::Done,
+                Ok(length) => Phase::Length(length),
+                Err(_) => return Err(Error::Protocol(format!("invalid Content-Length: {:?}", length))),
+            }
+        } else {
+            Phase::UntilClose
+        };
+        self.response = Some(response);
+        Ok(true)
+    }
+}
+
+fn parse_head(head : &str) -> Result<Response, Error> {
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("").to_string();
+    if !version.starts_with("HTTP/1.") {
+        return Err(Error::Protocol(format!("invalid status line: {:?}", status_line)));
+    }
+    let status = parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..600).contains(code))
+        .ok_or_else(|| Error::Protocol(format!("invalid status line: {:?}", status_line)))?;
+    let reason = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::Protocol(format!("invalid header: {:?}", line)))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(Response { version, status, reason, headers })
+}
+
+fn request(url : &Url, method : Method, keep_alive : bool) -> Result<String, Error> {
+    let host = url.host_str().ok_or_else(|| no_host(url))?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    Ok(format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: mget\r\nAccept: */*\r\nConnection: {}\r\n\r\n",
+        method.as_str(),
+        &url[Position::BeforePath..Position::AfterQuery],
+        host,
+        if keep_alive { "keep-alive" } else { "close" },
+    ))
+}
+
+/// Whether the server lets the connection stay open after `response`.
+fn persistent(response : &Response) -> bool {
+    let connection = response.header("Connection").unwrap_or("").to_ascii_lowercase();
+    if response.version == "HTTP/1.0" {
+        connection.contains("keep-alive")
+    } else {
+        !connection.contains("close")
     }
 }
 
+fn no_host(url : &Url) -> Error {
+    Error::Usage(format!("{} has no host", url))
+}
+
 fn random_port() -> u16 {
     49152 + rand::random::<u16>() %16384
 }
 
-pub fn get(tap : Iface, mac : EthernetAddress, addr : IpAddr, url : Url)
-    -> Result<(), UpstreamError>
-{
-    let domain_name = url.host_str().ok_or(UpstreamError::InvalidUrl)?;
-    let neighbor_cache = NeighborCache::new(BTreeMap::new());
-    let tcp_rx_buffer = TcpSocketBuffer::new(vec![0;1024]);
-    let tcp_tx_buffer = TcpSocketBuffer::new(vec![0;1024]);
-    let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
-    let ip_addrs = [IpCidr::new(IpAddress::v4(192,168,42,1),24)];
-    let fd = tap.as_raw_fd();
-    let mut routes = Routes::new(BTreeMap::new());
-    let default_gateway = Ipv4Address::new(192, 168, 42, 100);
-    routes.add_default_ipv4_route(default_gateway).unwrap();
-    let mut iface = EthernetInterfaceBuilder::new(tap)
-        .ethernet_addr(mac)
-        .neighbor_cache(neighbor_cache)
-        .ip_addrs(ip_addrs)
-        .routes(routes)
-        .finalize();
-    let mut sockets = SocketSet::new(vec![]);
-    let tcp_handle = sockets.add(tcp_socket);
-
-    let http_header = format!(
-        "Get {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
-        url.path(),
-        domain_name
-    );
-
-    let mut state = HttpState::Connect;
-    'http: loop {
-        let timestamp = Instant::now();
-        match iface.poll(&mut sockets, timestamp) {
-            Ok(_) => {}
-            Err(smoltcp::Error::Unrecognized) => {}
-            Err(e) => {
-                eprintln!("error : {:?}", e);
+/// Where a connection goes. Requests for the same origin may share one.
+#[derive(Debug, Clone, PartialEq)]
+struct Origin {
+    scheme : String,
+    host : String,
+    port : u16,
+    addr : IpAddr,
+}
+
+impl Origin {
+    fn remote(&self) -> (IpAddress, u16) {
+        (IpAddress::from(self.addr), self.port)
+    }
+}
+
+struct Connection {
+    origin : Origin,
+    handle : SocketHandle,
+    local_port : u16,
+    session : Option<ClientConnection>,
+    opened : std::time::Instant,
+    established : bool,
+}
+
+/// Connections kept open between requests with HTTP/1.1 keep-alive, one per
+/// origin, with `https://` ones set up by `tls`. Whatever is left open has
+/// to be closed with `close`.
+pub struct Pool {
+    tls : Arc<ClientConfig>,
+    connections : Vec<Connection>,
+}
+
+impl Pool {
+    pub fn new(tls : Arc<ClientConfig>) -> Self {
+        Pool { tls, connections : Vec::new() }
+    }
+
+    /// Fetches `url` from `addr` and writes the decoded body to `body`.
+    /// Redirects are returned as they are, for the caller to follow. With
+    /// `options.keep_alive` the connection is kept for the next request to
+    /// the same origin, and an open one is used if there is one.
+    pub fn get<D : Phy, W : Write>(
+        &mut self,
+        stack : &mut Stack<D>,
+        addr : IpAddr,
+        url : &Url,
+        method : Method,
+        options : &Options,
+        body : &mut W,
+    ) -> Result<(Response, Transfer), Error> {
+        let origin = Origin {
+            scheme : url.scheme().to_string(),
+            host : url.host_str().ok_or_else(|| no_host(url))?.to_string(),
+            port : url.port_or_known_default().ok_or_else(|| Error::Usage(format!("{} has no port", url)))?,
+            addr,
+        };
+        let http_request = request(url, method, options.keep_alive)?;
+        let started = std::time::Instant::now();
+        let mut idle = self.take(stack, &origin);
+        loop {
+            let reused = idle.is_some();
+            let mut connection = match idle.take() {
+                Some(connection) => {
+                    eprintln!("reusing connection");
+                    connection
+                }
+                None => open(stack, origin.clone(), &self.tls, options)?,
+            };
+            let progress = if options.progress { Some(Progress::new()) } else { None };
+            let mut counter = Counter { inner : &mut *body, bytes : 0, progress };
+            let mut parser = ResponseParser::new(method, options.follow_redirects);
+            let result = exchange(stack, &mut connection, &http_request, &mut parser, &mut counter, options.timeout);
+            let tcp = stack.device.finish();
+            let keep = match result {
+                // the server may have timed the connection out just as it was reused
+                Err(_) if reused && parser.response.is_none() && parser.buffer.is_empty() => {
+                    close(stack, connection);
+                    continue;
+                }
+                Err(e) => {
+                    close(stack, connection);
+                    return Err(e);
+                }
+                Ok(keep) => keep && options.keep_alive,
+            };
+            if keep {
+                self.connections.push(connection);
+            } else {
+                close(stack, connection);
             }
+            let response = parser.finish()?;
+            let transfer = Transfer { bytes : counter.bytes, elapsed : started.elapsed(), tcp, reused };
+            return Ok((response, transfer));
         }
+    }
+
+    /// Closes every connection that was kept open.
+    pub fn close<D : Phy>(&mut self, stack : &mut Stack<D>) {
+        for connection in self.connections.drain(..) {
+            close(stack, connection);
+        }
+    }
+
+    /// The open connection to `origin`, unless the server has closed it since.
+    fn take<D : Phy>(&mut self, stack : &mut Stack<D>, origin : &Origin) -> Option<Connection> {
+        let position = self.connections.iter().position(|connection| connection.origin == *origin)?;
+        let connection = self.connections.swap_remove(position);
+        stack.poll(Instant::now());
+        let socket = stack.sockets.get::<tcp::Socket>(connection.handle);
+        if socket.may_send() && socket.may_recv() {
+            Some(connection)
+        } else {
+            close(stack, connection);
+            None
+        }
+    }
+}
+
+fn open<D : Phy>(stack : &mut Stack<D>, origin : Origin, tls : &Arc<ClientConfig>, options : &Options)
+    -> Result<Connection, Error>
+{
+    let session = match origin.scheme.as_str() {
+        "http" => None,
+        "https" => Some(crate::tls::connect(tls, &origin.host)?),
+        scheme => return Err(Error::Usage(format!("{}:// is not supported, only http:// and https://", scheme))),
+    };
+    let tcp_rx_buffer = tcp::SocketBuffer::new(vec![0; options.rx_buffer]);
+    let tcp_tx_buffer = tcp::SocketBuffer::new(vec![0; options.tx_buffer]);
+    let mut tcp_socket = tcp::Socket::new(tcp_rx_buffer, tcp_tx_buffer);
+    tcp_socket.set_timeout(Some(options.timeout.into()));
+    eprintln!("connecting");
+    let local_port = random_port();
+    tcp_socket.connect(stack.iface.context(), origin.remote(), local_port)?;
+    let handle = stack.sockets.add(tcp_socket);
+    Ok(Connection { origin, handle, local_port, session, opened : std::time::Instant::now(), established : false })
+}
+
+fn close<D : Phy>(stack : &mut Stack<D>, mut connection : Connection) {
+    let socket = stack.sockets.get_mut::<tcp::Socket>(connection.handle);
+    if let Some(session) = connection.session.as_mut() {
+        session.send_close_notify();
+        let _ = session.write_tls(&mut SocketIo(socket));
+    }
+    socket.close();
+    // lets the FIN go out before the socket is dropped
+    stack.poll(Instant::now());
+    stack.sockets.remove(connection.handle);
+}
+
+/// Sends `http_request` over `connection` and feeds the response to
+/// `parser`. Returns whether the connection can take another request, and
+/// gives up once nothing has been sent or received for `timeout`.
+fn exchange<D : Phy, W : Write>(
+    stack : &mut Stack<D>,
+    connection : &mut Connection,
+    http_request : &str,
+    parser : &mut ResponseParser,
+    body : &mut Counter<W>,
+    timeout : std::time::Duration,
+) -> Result<bool, Error> {
+    stack.device.watch(connection.local_port, connection.origin.remote().into());
+    let mut sent = 0;
+    let mut state = HttpState::Request;
+    // smoltcp only times out data that goes unacknowledged, not a server
+    // that acknowledges the request and then never answers
+    let mut progressed = std::time::Instant::now();
+    let open = 'http: loop {
+        let timestamp = Instant::now();
+        let sent_before = sent;
+        stack.poll(timestamp);
         {
-            let mut socket = sockets.get::<TcpSocket>(tcp_handle);
+            let socket = stack.sockets.get_mut::<tcp::Socket>(connection.handle);
+            connection.established |= socket.may_send();
             state = match state {
-                HttpState::Connect if !socket.is_active() => {
-                    eprintln!("connecting");
-                    socket.connect((addr, 80), random_port())?;
-                    HttpState::Request
-                }
                 HttpState::Request if socket.may_send() => {
-                    eprintln!("sending request");
-                    socket.send_slice(http_header.as_ref())?;
-                    HttpState::Response
+                    sent += match connection.session.as_mut() {
+                        // rustls holds on to the request until the handshake is done
+                        Some(session) => session.writer().write(&http_request.as_bytes()[sent..]).map_err(Error::tls)?,
+                        None => socket.send_slice(&http_request.as_bytes()[sent..])?,
+                    };
+                    if sent < http_request.len() {
+                        HttpState::Request
+                    } else {
+                        eprintln!("sent request");
+                        HttpState::Response
+                    }
                 }
-                HttpState::Response if socket.can_recv() => {
-                    socket.recv(|raw_data| {
-                        let output = String::from_utf8_lossy(raw_data);
-                        println!("{}", output);
-                        (raw_data.len(), ())
-                    })?;
-                    HttpState::Response
+                HttpState::Request if !socket.is_active() => {
+                    let what = format!("connection to {}", std::net::SocketAddr::new(connection.origin.addr, connection.origin.port));
+                    return Err(if connection.established {
+                        Error::Network { context : "connection closed by the server".to_string(), source : None }
+                    } else if connection.opened.elapsed() >= timeout {
+                        // smoltcp resets the connection when the SYN goes unanswered
+                        Error::Timeout(what)
+                    } else {
+                        Error::Refused(what)
+                    });
                 }
-                HttpState::Response if !socket.may_recv() => {
+                _ => state,
+            };
+
+            let mut received = Vec::new();
+            match connection.session.as_mut() {
+                Some(session) => {
+                    while session.wants_write() && socket.can_send() {
+                        session.write_tls(&mut SocketIo(socket)).map_err(|e| Error::network("TLS exchange", e))?;
+                    }
+                    while session.wants_read() && socket.can_recv() {
+                        session.read_tls(&mut SocketIo(socket)).map_err(|e| Error::network("TLS exchange", e))?;
+                        session.process_new_packets().map_err(Error::tls)?;
+                    }
+                    let mut chunk = [0; 4096];
+                    loop {
+                        match session.reader().read(&mut chunk) {
+                            Ok(0) => break,
+                            Ok(amt) => received.extend_from_slice(&chunk[..amt]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(Error::tls(e)),
+                        }
+                    }
+                }
+                None => {
+                    // one recv stops where the ring buffer wraps around
+                    while socket.can_recv() {
+                        received.extend(socket.recv(|raw_data| (raw_data.len(), raw_data.to_vec()))?);
+                    }
+                }
+            }
+
+            if sent > sent_before || !received.is_empty() {
+                progressed = std::time::Instant::now();
+            }
+            if let HttpState::Response = state {
+                parser.feed(&received, body)?;
+                if let Some(progress) = body.progress.as_mut() {
+                    if body.bytes > 0 {
+                        progress.update(body.bytes, parser.content_length());
+                    }
+                }
+                if parser.is_done() {
+                    break 'http socket.may_recv();
+                }
+                if !socket.may_recv() {
                     eprintln!("received complete response");
-                    break 'http;
+                    break 'http false;
                 }
-                _ => state,
             }
         }
-        phy_wait(fd, iface.poll_delay(&socket, timestamp)).expect("wait error");
+        let remaining = match timeout.checked_sub(progressed.elapsed()) {
+            Some(remaining) => remaining,
+            None => {
+                let what = format!("response from {}", std::net::SocketAddr::new(connection.origin.addr, connection.origin.port));
+                return Err(Error::Timeout(what));
+            }
+        };
+        stack.wait(timestamp, Some(remaining.into())).map_err(|e| Error::io("unable to wait for the device", e))?;
+    };
+    body.flush().map_err(|e| Error::io("unable to write the body", e))?;
+    if let Some(progress) = body.progress.as_mut() {
+        progress.finish(body.bytes, parser.content_length());
+    }
+    Ok(open && parser.response.as_ref().is_some_and(persistent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pieces : &[&[u8]], closed : bool) -> (Result<Response, Error>, Vec<u8>) {
+        let mut parser = ResponseParser::new(Method::Get, true);
+        let mut body = Vec::new();
+        for piece in pieces {
+            parser.feed(piece, &mut body).unwrap();
+        }
+        assert_eq!(parser.is_done(), !closed);
+        (parser.finish(), body)
+    }
+
+    #[test]
+    fn content_length() {
+        let (response, body) = parse(
+            &[b"HTTP/1.1 200 OK\r\nContent-Len", b"gth: 5\r\nX-A: b\r\n\r\nhel", b"lo extra"],
+            false,
+        );
+        let response = response.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.header("content-length"), Some("5"));
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn chunked_split_across_reads() {
+        let raw : &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let pieces : Vec<&[u8]> = raw.chunks(3).collect();
+        let (response, body) = parse(&pieces, false);
+        assert!(response.is_ok());
+        assert_eq!(body, b"Wikipedia in\r\n\r\nchunks.");
+    }
+
+    #[test]
+    fn body_until_close() {
+        let (response, body) = parse(&[b"HTTP/1.0 200 OK\r\n\r\nall ", b"of it"], true);
+        assert_eq!(response.unwrap().status, 200);
+        assert_eq!(body, b"all of it");
+    }
+
+    #[test]
+    fn skips_informational_responses() {
+        let (response, body) = parse(&[b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n"], false);
+        assert_eq!(response.unwrap().status, 204);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let (response, _) = parse(&[b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort"], true);
+        assert!(matches!(response, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut parser = ResponseParser::new(Method::Get, true);
+        assert!(parser.feed(b"SSH-2.0-OpenSSH\r\n\r\n", &mut Vec::new()).is_err());
     }
-    Ok(())
 
+    #[test]
+    fn redirect_bodies_are_dropped() {
+        let (response, body) = parse(
+            &[b"HTTP/1.1 301 Moved Permanently\r\nLocation: /new\r\nContent-Length: 7\r\n\r\nMoved.\n"],
+            false,
+        );
+        assert_eq!(response.unwrap().location(), Some("/new"));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn redirect_bodies_are_kept_when_not_followed() {
+        let mut parser = ResponseParser::new(Method::Get, false);
+        let mut body = Vec::new();
+        parser.feed(b"HTTP/1.1 302 Found\r\nLocation: /new\r\nContent-Length: 7\r\n\r\nMoved.\n", &mut body).unwrap();
+        assert_eq!(parser.finish().unwrap().location(), Some("/new"));
+        assert_eq!(body, b"Moved.\n");
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let mut parser = ResponseParser::new(Method::Head, true);
+        let mut body = Vec::new();
+        parser.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 512\r\nServer: x\r\n\r\n", &mut body).unwrap();
+        assert!(parser.is_done());
+        let response = parser.finish().unwrap();
+        assert_eq!(response.to_string(), "HTTP/1.1 200 OK\r\nContent-Length: 512\r\nServer: x\r\n\r\n");
+        assert_eq!(response.location(), None);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn keep_alive_depends_on_the_version() {
+        let response = |head : &[u8]| parse(&[head], false).0.unwrap();
+        assert!(persistent(&response(b"HTTP/1.1 204 No Content\r\n\r\n")));
+        assert!(!persistent(&response(b"HTTP/1.1 204 No Content\r\nConnection: Close\r\n\r\n")));
+        assert!(!persistent(&response(b"HTTP/1.0 204 No Content\r\n\r\n")));
+        assert!(persistent(&response(b"HTTP/1.0 204 No Content\r\nConnection: keep-alive\r\n\r\n")));
+    }
+
+    #[test]
+    fn request_line() {
+        let url = Url::parse("http://example.com:8080/a/b?q=1#frag").unwrap();
+        assert_eq!(
+            request(&url, Method::Get, false).unwrap(),
+            "GET /a/b?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nUser-Agent: mget\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+        assert!(request(&url, Method::Head, true).unwrap().contains("\r\nConnection: keep-alive\r\n"));
+    }
+}
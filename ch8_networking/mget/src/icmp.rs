@@ -0,0 +1,425 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::raw;
+use smoltcp::wire::{
+    Icmpv4DstUnreachable, Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, IpVersion, Ipv4Address, Ipv4Packet, Ipv4Repr,
+};
+
+use crate::error::Error;
+use crate::phy::Phy;
+use crate::stack::Stack;
+
+/// Bytes of data in an echo request, as ping sends by default.
+pub const DEFAULT_SIZE : usize = 56;
+
+const IPV4_HEADER_LEN : usize = 20;
+const ICMP_HEADER_LEN : usize = 8;
+
+/// What came back about a probe.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// The destination answered with `len` bytes of ICMP.
+    Echo { from : Ipv4Address, ttl : u8, len : usize },
+    /// A router on the way dropped the probe as its TTL ran out.
+    TimeExceeded { from : Ipv4Address },
+    Unreachable { from : Ipv4Address, reason : Icmpv4DstUnreachable },
+}
+
+impl Reply {
+    pub fn from(&self) -> Ipv4Address {
+        match *self {
+            Reply::Echo { from, .. } | Reply::TimeExceeded { from } | Reply::Unreachable { from, .. } => from,
+        }
+    }
+}
+
+/// A reply and how long it took to arrive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub reply : Reply,
+    pub rtt : Duration,
+}
+
+/// An IPv4 packet holding echo request `seq_no` with `size` bytes of data.
+fn echo_request(src_addr : Ipv4Address, dst_addr : Ipv4Address, ttl : u8, ident : u16, seq_no : u16, size : usize)
+    -> Vec<u8>
+{
+    let data : Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let icmp_repr = Icmpv4Repr::EchoRequest { ident, seq_no, data : &data };
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header : IpProtocol::Icmp,
+        payload_len : icmp_repr.buffer_len(),
+        hop_limit : ttl,
+    };
+    let mut packet = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet);
+    ip_repr.emit(&mut ip_packet, &ChecksumCapabilities::default());
+    icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()), &ChecksumCapabilities::default());
+    packet
+}
+
+/// What `packet` says about echo request `seq_no` of `ident`, if anything.
+/// Errors quote the start of the packet they are about, which is where the
+/// request is found. Routers often quote only eight bytes past the IP
+/// header, which `Icmpv4Repr::parse` turns down, so quotes are read here.
+fn reply(packet : &[u8], ident : u16, seq_no : u16) -> Option<Reply> {
+    let ip_packet = Ipv4Packet::new_checked(packet).ok()?;
+    let from = ip_packet.src_addr();
+    let icmp_packet = Icmpv4Packet::new_checked(ip_packet.payload()).ok()?;
+    if !icmp_packet.verify_checksum() {
+        return None;
+    }
+    match icmp_packet.msg_type() {
+        Icmpv4Message::EchoReply => {
+            if icmp_packet.echo_ident() != ident || icmp_packet.echo_seq_no() != seq_no {
+                return None;
+            }
+            Some(Reply::Echo { from, ttl : ip_packet.hop_limit(), len : ip_packet.payload().len() })
+        }
+        message @ (Icmpv4Message::TimeExceeded | Icmpv4Message::DstUnreachable) => {
+            let quote = icmp_packet.data();
+            if quote.len() < IPV4_HEADER_LEN {
+                return None;
+            }
+            let header = Ipv4Packet::new_unchecked(quote);
+            let header_len = header.header_len() as usize;
+            if header.version() != 4 || header.next_header() != IpProtocol::Icmp || quote.len() < header_len + ICMP_HEADER_LEN {
+                return None;
+            }
+            let probe = Icmpv4Packet::new_unchecked(&quote[header_len..]);
+            if probe.msg_type() != Icmpv4Message::EchoRequest || probe.echo_ident() != ident || probe.echo_seq_no() != seq_no {
+                return None;
+            }
+            match message {
+                Icmpv4Message::TimeExceeded => Some(Reply::TimeExceeded { from }),
+                _ => Some(Reply::Unreachable { from, reason : icmp_packet.msg_code().into() }),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Sends echo requests from a raw socket rather than an ICMP one, so that
+/// probes can carry any TTL and the errors routers send about them are
+/// seen too.
+pub struct Pinger {
+    handle : SocketHandle,
+    src_addr : Ipv4Address,
+    ident : u16,
+    seq_no : u16,
+}
+
+impl Pinger {
+    /// Fails when the interface has no IPv4 address to send from.
+    pub fn new<D : Phy>(stack : &mut Stack<D>) -> Result<Self, Error> {
+        let src_addr = stack.iface.ipv4_addr()
+            .ok_or_else(|| Error::Usage("the interface has no IPv4 address to ping from".to_string()))?;
+        let rx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 16], vec![0; 16384]);
+        let tx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 4], vec![0; 16384]);
+        let handle = stack.sockets.add(raw::Socket::new(IpVersion::Ipv4, IpProtocol::Icmp, rx_buffer, tx_buffer));
+        Ok(Pinger { handle, src_addr, ident : rand::random(), seq_no : 0 })
+    }
+
+    /// The sequence number of the latest probe.
+    pub fn seq_no(&self) -> u16 {
+        self.seq_no
+    }
+
+    /// Sends the next echo request to `dst` with `ttl` and `size` bytes of
+    /// data, and waits up to `timeout` for what comes back about it.
+    pub fn probe<D : Phy>(&mut self, stack : &mut Stack<D>, dst : Ipv4Address, ttl : u8, size : usize,
+        timeout : Duration) -> Result<Option<Answer>, Error>
+    {
+        self.seq_no = self.seq_no.wrapping_add(1);
+        let packet = echo_request(self.src_addr, dst, ttl, self.ident, self.seq_no, size);
+        let socket = stack.sockets.get_mut::<raw::Socket>(self.handle);
+        // replies to earlier probes that came in too late
+        while socket.recv().is_ok() {}
+        socket.send_slice(&packet)
+            .map_err(|e| Error::Network { context : format!("unable to send an echo request: {:?}", e), source : None })?;
+        let sent = Instant::now();
+        loop {
+            let timestamp = smoltcp::time::Instant::now();
+            stack.poll(timestamp);
+            let socket = stack.sockets.get_mut::<raw::Socket>(self.handle);
+            while let Ok(packet) = socket.recv() {
+                if let Some(reply) = reply(packet, self.ident, self.seq_no) {
+                    return Ok(Some(Answer { reply, rtt : sent.elapsed() }));
+                }
+            }
+            let remaining = match timeout.checked_sub(sent.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Ok(None),
+            };
+            stack.wait(timestamp, Some(remaining.into())).map_err(|e| Error::io("unable to wait for the device", e))?;
+        }
+    }
+
+    /// Sends `queries` probes with `ttl` towards `dst`.
+    pub fn hop<D : Phy>(&mut self, stack : &mut Stack<D>, dst : Ipv4Address, ttl : u8, queries : usize,
+        timeout : Duration) -> Result<Hop, Error>
+    {
+        let mut answers = Vec::new();
+        for _ in 0..queries {
+            answers.push(self.probe(stack, dst, ttl, DEFAULT_SIZE, timeout)?);
+        }
+        Ok(Hop { ttl, answers })
+    }
+
+    pub fn close<D>(self, stack : &mut Stack<D>) {
+        stack.sockets.remove(self.handle);
+    }
+}
+
+/// The probes traceroute sent with one TTL and what came of them.
+#[derive(Debug)]
+pub struct Hop {
+    pub ttl : u8,
+    pub answers : Vec<Option<Answer>>,
+}
+
+impl Hop {
+    /// Whether the probes got as far as they go, to the destination or to
+    /// a router that can't take them further.
+    pub fn is_last(&self) -> bool {
+        self.answers.iter().flatten().any(|answer| !matches!(answer.reply, Reply::TimeExceeded { .. }))
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>2} ", self.ttl)?;
+        let mut last_from = None;
+        for answer in &self.answers {
+            match answer {
+                Some(answer) => {
+                    if last_from != Some(answer.reply.from()) {
+                        write!(f, " {}", answer.reply.from())?;
+                        last_from = Some(answer.reply.from());
+                    }
+                    write!(f, "  {:.3} ms", answer.rtt.as_secs_f64() * 1000.0)?;
+                    match answer.reply {
+                        Reply::Unreachable { reason : Icmpv4DstUnreachable::NetUnreachable, .. } => write!(f, " !N")?,
+                        Reply::Unreachable { reason : Icmpv4DstUnreachable::HostUnreachable, .. } => write!(f, " !H")?,
+                        Reply::Unreachable { .. } => write!(f, " !X")?,
+                        _ => {}
+                    }
+                }
+                None => write!(f, " *")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Round trip times of a ping run, for the summary at its end.
+#[derive(Debug)]
+pub struct Statistics {
+    pub transmitted : u64,
+    rtts : Vec<Duration>,
+    started : Instant,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Statistics { transmitted : 0, rtts : Vec::new(), started : Instant::now() }
+    }
+
+    pub fn record(&mut self, answer : Option<&Answer>) {
+        self.transmitted += 1;
+        if let Some(answer) = answer {
+            if matches!(answer.reply, Reply::Echo { .. }) {
+                self.rtts.push(answer.rtt);
+            }
+        }
+    }
+
+    pub fn received(&self) -> u64 {
+        self.rtts.len() as u64
+    }
+
+    /// Minimum, average, maximum and mean deviation, in milliseconds.
+    pub fn rtt(&self) -> Option<(f64, f64, f64, f64)> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let millis : Vec<f64> = self.rtts.iter().map(|rtt| rtt.as_secs_f64() * 1000.0).collect();
+        let min = millis.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = millis.iter().copied().fold(0.0, f64::max);
+        let avg = millis.iter().sum::<f64>() / millis.len() as f64;
+        let mdev = millis.iter().map(|rtt| (rtt - avg).abs()).sum::<f64>() / millis.len() as f64;
+        Some((min, avg, max, mdev))
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let loss = match self.transmitted {
+            0 => 0,
+            transmitted => (transmitted - self.received()) * 100 / transmitted,
+        };
+        write!(
+            f,
+            "{} packets transmitted, {} received, {}% packet loss, time {}ms",
+            self.transmitted,
+            self.received(),
+            loss,
+            self.started.elapsed().as_millis(),
+        )?;
+        if let Some((min, avg, max, mdev)) = self.rtt() {
+            write!(f, "\nrtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms", min, avg, max, mdev)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Statistics::new()
+    }
+}
+
+/// The size of a whole probe packet with `size` bytes of data.
+pub fn packet_len(size : usize) -> usize {
+    IPV4_HEADER_LEN + ICMP_HEADER_LEN + size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::iface::SocketSet;
+    use smoltcp::phy::{Loopback, Medium};
+    use smoltcp::wire::{IpAddress, Ipv4Cidr};
+
+    use crate::loopback::IPV4_ADDRESS;
+    use crate::stack::Service;
+
+    /// Beyond the loopback device, as far as the tests are concerned.
+    const FAR : Ipv4Address = Ipv4Address([192, 0, 2, 9]);
+
+    /// Plays `hops` routers in front of `FAR`: probes for it come back as
+    /// time exceeded from 198.51.100.<ttl> until their TTL would last.
+    struct Routers {
+        hops : u8,
+        handle : Option<SocketHandle>,
+    }
+
+    impl Routers {
+        fn answer(&self, packet : &[u8]) -> Option<Vec<u8>> {
+            let ip_packet = Ipv4Packet::new_checked(packet).ok()?;
+            let icmp_packet = Icmpv4Packet::new_checked(ip_packet.payload()).ok()?;
+            let request = Icmpv4Repr::parse(&icmp_packet, &ChecksumCapabilities::default()).ok()?;
+            let (ident, seq_no, data) = match request {
+                Icmpv4Repr::EchoRequest { ident, seq_no, data } if ip_packet.dst_addr() == FAR => (ident, seq_no, data),
+                _ => return None,
+            };
+            let ttl = ip_packet.hop_limit();
+            let (src_addr, icmp_repr) = if ttl <= self.hops {
+                let header = Ipv4Repr::parse(&ip_packet, &ChecksumCapabilities::default()).ok()?;
+                let repr = Icmpv4Repr::TimeExceeded {
+                    reason : smoltcp::wire::Icmpv4TimeExceeded::TtlExpired,
+                    header,
+                    data : &ip_packet.payload()[..8],
+                };
+                (Ipv4Address::new(198, 51, 100, ttl), repr)
+            } else {
+                (FAR, Icmpv4Repr::EchoReply { ident, seq_no, data })
+            };
+            let ip_repr = Ipv4Repr {
+                src_addr,
+                dst_addr : ip_packet.src_addr(),
+                next_header : IpProtocol::Icmp,
+                payload_len : icmp_repr.buffer_len(),
+                hop_limit : 64 - self.hops,
+            };
+            let mut reply = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+            let mut reply_packet = Ipv4Packet::new_unchecked(&mut reply);
+            ip_repr.emit(&mut reply_packet, &ChecksumCapabilities::default());
+            icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(reply_packet.payload_mut()), &ChecksumCapabilities::default());
+            Some(reply)
+        }
+    }
+
+    impl Service for Routers {
+        fn poll(&mut self, sockets : &mut SocketSet<'static>) {
+            let handle = *self.handle.get_or_insert_with(|| {
+                let rx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 16], vec![0; 16384]);
+                let tx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 16], vec![0; 16384]);
+                sockets.add(raw::Socket::new(IpVersion::Ipv4, IpProtocol::Icmp, rx_buffer, tx_buffer))
+            });
+            let mut replies = Vec::new();
+            let socket = sockets.get_mut::<raw::Socket>(handle);
+            while let Ok(packet) = socket.recv() {
+                replies.extend(self.answer(packet));
+            }
+            for reply in replies {
+                let _ = socket.send_slice(&reply);
+            }
+        }
+    }
+
+    fn stack() -> Stack<Loopback> {
+        let mut stack = Stack::new(Loopback::new(Medium::Ethernet), crate::ethernet::MacAddress::new().into());
+        // everything off the link goes back to the device itself, where the routers are
+        stack.configure(Ipv4Cidr::new(IPV4_ADDRESS, 8).into(), Some(IpAddress::Ipv4(IPV4_ADDRESS)));
+        stack.serve(Box::new(Routers { hops : 2, handle : None }));
+        stack
+    }
+
+    #[test]
+    fn pings_the_loopback_device() {
+        let mut stack = stack();
+        let mut pinger = Pinger::new(&mut stack).unwrap();
+        let mut statistics = Statistics::default();
+        for seq_no in 1..=3 {
+            let answer = pinger.probe(&mut stack, IPV4_ADDRESS, 64, DEFAULT_SIZE, Duration::from_secs(5)).unwrap();
+            assert_eq!(pinger.seq_no(), seq_no);
+            assert_eq!(answer.as_ref().unwrap().reply, Reply::Echo { from : IPV4_ADDRESS, ttl : 64, len : 64 });
+            statistics.record(answer.as_ref());
+        }
+        assert_eq!(statistics.received(), 3);
+        let summary = statistics.to_string();
+        assert!(summary.starts_with("3 packets transmitted, 3 received, 0% packet loss, time "), "{}", summary);
+        assert!(summary.contains("ms\nrtt min/avg/max/mdev = "), "{}", summary);
+        pinger.close(&mut stack);
+    }
+
+    #[test]
+    fn traces_through_the_routers() {
+        let mut stack = stack();
+        let mut pinger = Pinger::new(&mut stack).unwrap();
+        let mut hops = Vec::new();
+        for ttl in 1..=5 {
+            let hop = pinger.hop(&mut stack, FAR, ttl, 2, Duration::from_secs(5)).unwrap();
+            let last = hop.is_last();
+            hops.push(hop);
+            if last {
+                break;
+            }
+        }
+        let froms : Vec<Vec<Reply>> = hops.iter()
+            .map(|hop| hop.answers.iter().map(|answer| answer.as_ref().unwrap().reply.clone()).collect())
+            .collect();
+        assert_eq!(froms, vec![
+            vec![Reply::TimeExceeded { from : Ipv4Address::new(198, 51, 100, 1) }; 2],
+            vec![Reply::TimeExceeded { from : Ipv4Address::new(198, 51, 100, 2) }; 2],
+            vec![Reply::Echo { from : FAR, ttl : 62, len : 64 }; 2],
+        ]);
+        assert!(hops[0].to_string().starts_with(" 1  198.51.100.1  "));
+    }
+
+    #[test]
+    fn ignores_other_probes() {
+        let packet = echo_request(FAR, IPV4_ADDRESS, 64, 7, 1, 0);
+        assert_eq!(reply(&packet, 7, 1), None);
+        let mut statistics = Statistics::default();
+        statistics.record(None);
+        assert!(statistics.to_string().starts_with("1 packets transmitted, 0 received, 100% packet loss, time "));
+        assert!(!statistics.to_string().contains("rtt"));
+    }
+}
@@ -0,0 +1,13 @@
+pub mod cli;
+pub mod dns;
+pub mod error;
+pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod loopback;
+pub mod meter;
+pub mod phy;
+pub mod progress;
+pub mod stack;
+pub mod tap;
+pub mod tls;
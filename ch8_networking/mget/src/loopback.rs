@@ -0,0 +1,465 @@
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use rustls::{ServerConfig, ServerConnection};
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::tcp;
+use smoltcp::wire::{Ipv4Address, Ipv6Address};
+
+use crate::stack::Service;
+use crate::tls::SocketIo;
+
+pub const IPV4_ADDRESS : Ipv4Address = Ipv4Address([127, 0, 0, 1]);
+
+/// smoltcp never answers neighbor solicitations for ::1, so IPv6 over the
+/// loopback device uses a unique local address instead.
+pub const IPV6_ADDRESS : Ipv6Address = Ipv6Address([0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+const NOT_FOUND : &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Length: 10\r\n\r\nnot found\n";
+
+struct Connection {
+    handle : SocketHandle,
+    request : Vec<u8>,
+    response : Option<(Vec<u8>, usize)>,
+    /// Whether the client asked for the connection to be closed after the response.
+    close : bool,
+}
+
+/// A tiny HTTP server answering on port 80 of the stack it is served from,
+/// so that mget can be run without a network. Every route maps a path to
+/// the raw bytes of its response. Connections are kept open for further
+/// requests unless the client asks otherwise.
+pub struct HttpServer {
+    routes : Vec<(String, Vec<u8>)>,
+    listener : Option<SocketHandle>,
+    connections : Vec<Connection>,
+}
+
+impl HttpServer {
+    pub fn new() -> Self {
+        HttpServer { routes : Vec::new(), listener : None, connections : Vec::new() }
+    }
+
+    pub fn route<B : Into<Vec<u8>>>(mut self, path : &str, response : B) -> Self {
+        self.routes.push((path.to_string(), response.into()));
+        self
+    }
+
+    /// A page, a redirect to it and a chunked response, for trying mget out.
+    pub fn demo() -> Self {
+        let page = "<html><body><h1>mget</h1><p>Served from the loopback device.</p></body></html>\n";
+        HttpServer::new()
+            .route("/", format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+                page.len(),
+                page,
+            ))
+            .route("/old", "HTTP/1.1 301 Moved Permanently\r\nLocation: /\r\nContent-Length: 0\r\n\r\n")
+            .route("/chunked", "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                6\r\nhello \r\n6\r\nworld\n\r\n0\r\n\r\n")
+    }
+
+    /// Serves the same routes over TLS on port 443 instead, presenting the
+    /// certificate of `config`.
+    pub fn over_tls(self, config : Arc<ServerConfig>) -> HttpsServer {
+        HttpsServer { config, routes : self.routes, listener : None, connections : Vec::new() }
+    }
+}
+
+/// Hands over the socket listening on `port` once a client has connected to
+/// it, and keeps a fresh one listening.
+fn accept(listener : &mut Option<SocketHandle>, sockets : &mut SocketSet<'static>, port : u16) -> Option<SocketHandle> {
+    // a listening socket turns into the connection, so open a fresh one each time
+    let accepted = listener.filter(|&handle| sockets.get::<tcp::Socket>(handle).is_active());
+    if accepted.is_some() || listener.is_none() {
+        let rx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
+        let tx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
+        let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+        socket.listen(port).unwrap_or_else(|_| panic!("port {} is taken", port));
+        *listener = Some(sockets.add(socket));
+    }
+    accepted
+}
+
+fn respond(routes : &[(String, Vec<u8>)], request : &[u8]) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let path = path.split('?').next().unwrap_or(path);
+    match routes.iter().find(|(route, _)| route == path) {
+        Some((_, response)) => response.clone(),
+        None => NOT_FOUND.to_vec(),
+    }
+}
+
+fn closes(request : &[u8]) -> bool {
+    String::from_utf8_lossy(request)
+        .to_ascii_lowercase()
+        .lines()
+        .any(|line| line.starts_with("connection:") && line.contains("close"))
+}
+
+impl Default for HttpServer {
+    fn default() -> Self {
+        HttpServer::new()
+    }
+}
+
+impl Service for HttpServer {
+    fn poll(&mut self, sockets : &mut SocketSet<'static>) {
+        if let Some(handle) = accept(&mut self.listener, sockets, 80) {
+            self.connections.push(Connection { handle, request : Vec::new(), response : None, close : false });
+        }
+
+        for connection in self.connections.iter_mut() {
+            let socket = sockets.get_mut::<tcp::Socket>(connection.handle);
+            while socket.can_recv() {
+                let _ = socket.recv(|data| {
+                    connection.request.extend_from_slice(data);
+                    (data.len(), ())
+                });
+            }
+            if connection.response.is_none() {
+                match connection.request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(end) => {
+                        let request : Vec<u8> = connection.request.drain(..end + 4).collect();
+                        connection.close = closes(&request);
+                        connection.response = Some((respond(&self.routes, &request), 0));
+                    }
+                    // the client has nothing more to ask
+                    None if !socket.may_recv() => socket.close(),
+                    None => {}
+                }
+            }
+            if let Some((response, sent)) = connection.response.as_mut() {
+                if *sent < response.len() && socket.can_send() {
+                    *sent += socket.send_slice(&response[*sent..]).unwrap_or(0);
+                }
+                if *sent == response.len() {
+                    connection.response = None;
+                    if connection.close {
+                        connection.request.clear();
+                        socket.close();
+                    }
+                }
+            }
+        }
+        self.connections.retain(|connection| {
+            let open = sockets.get::<tcp::Socket>(connection.handle).is_open();
+            if !open {
+                sockets.remove(connection.handle);
+            }
+            open
+        });
+    }
+}
+
+struct TlsConnection {
+    handle : SocketHandle,
+    session : ServerConnection,
+    request : Vec<u8>,
+    /// Whether close_notify has been sent, after which the socket closes as
+    /// soon as everything is written.
+    closing : bool,
+}
+
+/// The routes of an `HttpServer` behind TLS, for trying https:// out without
+/// a network. See `HttpServer::over_tls`.
+pub struct HttpsServer {
+    config : Arc<ServerConfig>,
+    routes : Vec<(String, Vec<u8>)>,
+    listener : Option<SocketHandle>,
+    connections : Vec<TlsConnection>,
+}
+
+impl Service for HttpsServer {
+    fn poll(&mut self, sockets : &mut SocketSet<'static>) {
+        if let Some(handle) = accept(&mut self.listener, sockets, 443) {
+            let mut session = ServerConnection::new(self.config.clone()).expect("the server config is usable");
+            // responses are handed over whole, and go out as the socket takes them
+            session.set_buffer_limit(None);
+            self.connections.push(TlsConnection { handle, session, request : Vec::new(), closing : false });
+        }
+
+        for connection in self.connections.iter_mut() {
+            let socket = sockets.get_mut::<tcp::Socket>(connection.handle);
+            let session = &mut connection.session;
+            let mut hang_up = false;
+            let mut chunk = [0; 4096];
+            while !hang_up {
+                match session.reader().read(&mut chunk) {
+                    Ok(amt) if amt > 0 => {
+                        connection.request.extend_from_slice(&chunk[..amt]);
+                        continue;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    // the client sent close_notify, or closed without one
+                    _ => hang_up = true,
+                }
+                if !session.wants_read() || !socket.can_recv() {
+                    break;
+                }
+                hang_up = session.read_tls(&mut SocketIo(socket)).is_err() || session.process_new_packets().is_err();
+            }
+            while !connection.closing {
+                let end = match connection.request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(end) => end,
+                    None => break,
+                };
+                let request : Vec<u8> = connection.request.drain(..end + 4).collect();
+                session.writer().write_all(&respond(&self.routes, &request)).expect("TLS buffers are unlimited");
+                hang_up |= closes(&request);
+            }
+            if (hang_up || !socket.may_recv()) && !connection.closing {
+                // an alert about a failed handshake is already queued instead
+                if !session.is_handshaking() {
+                    session.send_close_notify();
+                }
+                connection.closing = true;
+            }
+
+            while session.wants_write() && socket.can_send() {
+                if session.write_tls(&mut SocketIo(socket)).is_err() {
+                    break;
+                }
+            }
+            if connection.closing && !session.wants_write() {
+                socket.close();
+            }
+        }
+        self.connections.retain(|connection| {
+            let open = sockets.get::<tcp::Socket>(connection.handle).is_open();
+            if !open {
+                sockets.remove(connection.handle);
+            }
+            open
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use smoltcp::phy::{Loopback, Medium, PcapMode, PcapWriter};
+    use smoltcp::wire::{Ipv4Cidr, Ipv6Cidr};
+    use url::Url;
+
+    use crate::http::{self, Method};
+    use crate::stack::Stack;
+
+    fn fetch<D : crate::phy::Phy>(stack : &mut Stack<D>, url : &str) -> (http::Response, Vec<u8>) {
+        let tls = crate::tls::config(None::<&str>).unwrap();
+        let mut body = Vec::new();
+        let url = Url::parse(url).unwrap();
+        let addr = match url.host() {
+            Some(url::Host::Ipv6(addr)) => addr.into(),
+            _ => std::net::Ipv4Addr::from(IPV4_ADDRESS).into(),
+        };
+        let mut pool = http::Pool::new(tls);
+        let (response, _) = pool.get(stack, addr, &url, Method::Get, &http::Options::default(), &mut body).unwrap();
+        pool.close(stack);
+        (response, body)
+    }
+
+    fn stack<D : crate::phy::Phy>(device : D) -> Stack<D> {
+        let mut stack = Stack::new(device, crate::ethernet::MacAddress::new().into());
+        stack.configure(Ipv4Cidr::new(IPV4_ADDRESS, 8).into(), None);
+        stack.configure(Ipv6Cidr::new(IPV6_ADDRESS, 64).into(), None);
+        stack.serve(Box::new(HttpServer::demo()
+            .route("/big", big_response())
+            .route("/moved", "HTTP/1.1 302 Found\r\nLocation: /\r\nContent-Length: 7\r\n\r\nMoved.\n")
+            .route("/last", "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")));
+        stack
+    }
+
+    /// Accepts connections and reads the requests, but never answers them.
+    struct Silent(Option<SocketHandle>);
+
+    impl Service for Silent {
+        fn poll(&mut self, sockets : &mut SocketSet<'static>) {
+            let handle = *self.0.get_or_insert_with(|| {
+                let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 4096]), tcp::SocketBuffer::new(vec![0; 4096]));
+                socket.listen(80).unwrap();
+                sockets.add(socket)
+            });
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            while socket.can_recv() {
+                let _ = socket.recv(|data| (data.len(), ()));
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_server() {
+        let mut stack = Stack::new(Loopback::new(Medium::Ethernet), crate::ethernet::MacAddress::new().into());
+        stack.configure(Ipv4Cidr::new(IPV4_ADDRESS, 8).into(), None);
+        stack.serve(Box::new(Silent(None)));
+        let options = http::Options { timeout : std::time::Duration::from_millis(300), ..http::Options::default() };
+        let mut pool = http::Pool::new(crate::tls::config(None::<&str>).unwrap());
+        let url = Url::parse("http://localhost/").unwrap();
+        let started = std::time::Instant::now();
+        let e = pool.get(&mut stack, std::net::Ipv4Addr::from(IPV4_ADDRESS).into(), &url, Method::Get, &options, &mut Vec::new()).unwrap_err();
+        assert_eq!(e.exit_code(), 9, "{}", e);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn fetches_over_tls() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("mget-loopback-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+        let tls = crate::tls::config(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        stack.serve(Box::new(HttpServer::demo().route("/big", big_response()).over_tls(Arc::new(server_config))));
+        let addr = std::net::Ipv4Addr::from(IPV4_ADDRESS).into();
+        let mut pool = http::Pool::new(tls);
+        let mut get = |stack : &mut Stack<_>, url : &str, keep_alive : bool| {
+            let options = http::Options { keep_alive, ..http::Options::default() };
+            let mut body = Vec::new();
+            pool.get(stack, addr, &Url::parse(url).unwrap(), Method::Get, &options, &mut body)
+                .map(|(response, transfer)| (response.status, body, transfer.reused))
+        };
+
+        assert_eq!(get(&mut stack, "https://localhost/chunked", false).unwrap(), (200, b"hello world\n".to_vec(), false));
+        assert_eq!(get(&mut stack, "https://localhost/big", true).unwrap(), (200, big_body(), false));
+        assert!(get(&mut stack, "https://localhost/", true).unwrap().2);
+        // the certificate is for localhost only
+        let e = get(&mut stack, "https://example.com/", false).unwrap_err();
+        assert_eq!(e.exit_code(), 5, "{}", e);
+        assert_eq!(get(&mut stack, "https://localhost/missing", false).unwrap().0, 404);
+        pool.close(&mut stack);
+    }
+
+    fn big_body() -> Vec<u8> {
+        (0..100000).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn big_response() -> Vec<u8> {
+        let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n".to_vec();
+        response.extend(big_body());
+        response
+    }
+
+    #[test]
+    fn fetches_from_the_loopback_server() {
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        let (response, body) = fetch(&mut stack, "http://localhost/old");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.location(), Some("/"));
+        assert!(body.is_empty());
+
+        let (response, body) = fetch(&mut stack, "http://localhost/chunked");
+        assert_eq!(response.status, 200);
+        assert_eq!(body, b"hello world\n");
+
+        let (response, body) = fetch(&mut stack, "http://localhost/big");
+        assert_eq!(response.status, 200);
+        assert!(body == big_body());
+
+        let (response, _) = fetch(&mut stack, "http://localhost/missing");
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn shows_redirects_it_does_not_follow() {
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        let options = http::Options { follow_redirects : false, ..http::Options::default() };
+        let mut pool = http::Pool::new(crate::tls::config(None::<&str>).unwrap());
+        let url = Url::parse("http://localhost/moved").unwrap();
+        let addr = std::net::Ipv4Addr::from(IPV4_ADDRESS).into();
+        let mut body = Vec::new();
+        let (response, _) = pool.get(&mut stack, addr, &url, Method::Get, &options, &mut body).unwrap();
+        pool.close(&mut stack);
+        assert_eq!(response.location(), Some("/"));
+        assert_eq!(body, b"Moved.\n");
+        assert!(fetch(&mut stack, "http://localhost/moved").1.is_empty());
+    }
+
+    #[test]
+    fn reports_the_transfer() {
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        let tls = crate::tls::config(None::<&str>).unwrap();
+        let url = Url::parse("http://localhost/big").unwrap();
+        let addr = std::net::Ipv4Addr::from(IPV4_ADDRESS).into();
+        let mut body = Vec::new();
+        let (_, transfer) = http::Pool::new(tls)
+            .get(&mut stack, addr, &url, Method::Get, &http::Options::default(), &mut body)
+            .unwrap();
+        assert_eq!(transfer.bytes, 100000);
+        assert_eq!(transfer.tcp.retransmissions, 0);
+        // at least the SYN and the request are acknowledged
+        assert!(transfer.tcp.rtt_samples >= 2);
+        assert!(transfer.tcp.segments > 4);
+    }
+
+    #[test]
+    fn keeps_the_connection_alive() {
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        let tls = crate::tls::config(None::<&str>).unwrap();
+        let options = http::Options { keep_alive : true, ..http::Options::default() };
+        let mut pool = http::Pool::new(tls);
+        let mut get = |stack : &mut Stack<_>, url : &str| {
+            let url = Url::parse(url).unwrap();
+            let addr = std::net::Ipv4Addr::from(IPV4_ADDRESS).into();
+            let mut body = Vec::new();
+            let (response, transfer) = pool.get(stack, addr, &url, Method::Get, &options, &mut body).unwrap();
+            (response.status, body, transfer.reused)
+        };
+
+        assert_eq!(get(&mut stack, "http://localhost/old"), (301, vec![], false));
+        assert_eq!(get(&mut stack, "http://localhost/chunked"), (200, b"hello world\n".to_vec(), true));
+        assert_eq!(get(&mut stack, "http://localhost/big"), (200, big_body(), true));
+        assert!(get(&mut stack, "http://localhost/missing").2);
+        // another name is another origin, even at the same address
+        assert!(!get(&mut stack, "http://127.0.0.1/").2);
+        assert!(get(&mut stack, "http://127.0.0.1/").2);
+        // the server closes after this one, so the next request connects again
+        assert_eq!(get(&mut stack, "http://localhost/last"), (204, vec![], true));
+        assert!(!get(&mut stack, "http://localhost/").2);
+        pool.close(&mut stack);
+    }
+
+    #[test]
+    fn fetches_over_ipv6() {
+        let mut stack = stack(Loopback::new(Medium::Ethernet));
+        let (response, body) = fetch(&mut stack, "http://[fd00::1]/chunked");
+        assert_eq!(response.status, 200);
+        assert_eq!(body, b"hello world\n");
+    }
+
+    #[test]
+    fn records_frames_to_pcap() {
+        let path = std::env::temp_dir().join(format!("mget-{}.pcap", std::process::id()));
+        {
+            let pcap = File::create(&path).unwrap();
+            let mut stack = stack(PcapWriter::new(Loopback::new(Medium::Ethernet), pcap, PcapMode::TxOnly));
+            let (response, _) = fetch(&mut stack, "http://localhost/");
+            assert_eq!(response.status, 200);
+        }
+        let pcap = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // little-endian magic, then LINKTYPE_ETHERNET in the global header
+        assert_eq!(&pcap[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&pcap[20..24], &[1, 0, 0, 0]);
+        let mut frames = 0;
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+            rest = &rest[16 + len..];
+            frames += 1;
+        }
+        // a handshake, the request, the response and the teardown at least
+        assert!(frames >= 6, "only {} frames", frames);
+    }
+}
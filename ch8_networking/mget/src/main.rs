@@ -1,33 +1,217 @@
-use clap::{App, Arg};
-use tun_tap_mac::Iface;
-use url::Url;
-mod dns;
-mod ethernet;
-mod http;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+
+use clap::{App, Arg, ArgMatches};
+use libmget::error::Error;
+use libmget::{cli, dns, http, loopback, phy, stack, tls};
+use trust_dns::rr::record_type::RecordType;
+use url::{Host, Url};
 
 fn main() {
     let app = App::new("mget")
         .about("GET a webpage, manually")
-        .arg(Arg::with_name("url").required(true))
-        .arg(Arg::with_name("tap-device").required(true))
-        .arg(Arg::with_name("dns-server").default_value("1.1.1.1"))
+        .arg(Arg::with_name("url")
+            .required(true)
+            .multiple(true)
+            .help("URLs to fetch one after the other, over one connection per host where possible"))
+        .args(&cli::interface_args())
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .help("Writes the body to a file instead of stdout"))
+        .arg(Arg::with_name("head")
+            .short("I")
+            .long("head")
+            .help("Sends a HEAD request and prints only the response headers"))
+        .arg(Arg::with_name("max-redirects")
+            .long("max-redirects")
+            .takes_value(true)
+            .default_value("10")
+            .help("Redirects to follow before giving up, 0 to not follow any"))
+        .arg(Arg::with_name("ca-file")
+            .long("ca-file")
+            .takes_value(true)
+            .help("PEM certificates to trust for https:// instead of the usual roots"))
+        .arg(Arg::with_name("ipv4")
+            .short("4")
+            .long("ipv4")
+            .conflicts_with("ipv6")
+            .help("Only uses IPv4"))
+        .arg(Arg::with_name("ipv6")
+            .short("6")
+            .long("ipv6")
+            .help("Only uses IPv6"))
+        .arg(Arg::with_name("stack-dns")
+            .long("stack-dns")
+            .conflicts_with("loopback")
+            .help("Sends DNS queries from the TAP interface instead of the host's network"))
+        .arg(Arg::with_name("recv-buffer")
+            .long("recv-buffer")
+            .takes_value(true)
+            .value_name("bytes")
+            .default_value("262144")
+            .help("Size of the TCP receive buffer, which bounds the advertised window"))
+        .arg(Arg::with_name("send-buffer")
+            .long("send-buffer")
+            .takes_value(true)
+            .value_name("bytes")
+            .default_value("16384")
+            .help("Size of the TCP send buffer"))
+        .arg(Arg::with_name("no-window-scaling")
+            .long("no-window-scaling")
+            .help("Keeps the receive window under 64 KiB so that no window scale is offered"))
+        .arg(Arg::with_name("no-keep-alive")
+            .long("no-keep-alive")
+            .help("Closes the connection after every request"))
         .get_matches();
-    let url_text = app.value_of("url").unwrap();
-    let dns_server_text = app.value_of("dns-server").unwrap();
-    let tap_text = app.value_of("tap-device").unwrap();
-
-    let url = Url::parse(url_text).expect("error: unable to parse <url> as a URL");
-    if url.scheme() != "http" {
-        eprintln!("error: only HTTP protocol supported");
-        return;
-    }
-    let tap = Iface::new(&tap_text, tun_tap_mac::Mode::Tap)
-        .expect("error: unable to use <tap-device> as a network interface");
-    let domain_name = url.host_str().expect("domain name required");
-    let _dns_server : std::net::Ipv4Addr = dns_server_text
-                .parse()
-                .expect("error: unable to parse <dns-server> as an IPv4 address");
-    let addr = dns::resolve(dns_server_text, domain_name).unwrap().unwrap();
-    let mac = ethernet::MacAddress::new().into();
-    http::get(tap, mac, addr, url).unwrap();
+
+    if let Err(e) = cli::with_device(&app, Mget) {
+        e.exit();
+    }
+}
+
+struct Mget;
+
+impl cli::Run for Mget {
+    fn run<D : phy::Phy>(self, app : &ArgMatches, device : D) -> Result<(), Error> {
+        run(app, device)
+    }
+}
+
+/// Where DNS queries go: out of the host's network, or with --stack-dns out
+/// of `stack` next to the HTTP traffic.
+fn upstream<'a, D : phy::Phy>(app : &ArgMatches, stack : &'a mut stack::Stack<D>, dns_server : IpAddr)
+    -> Box<dyn dns::Upstream + 'a>
+{
+    if app.is_present("stack-dns") {
+        Box::new(dns::StackUdp(stack, dns_server))
+    } else {
+        Box::new(dns::HostUdp(dns_server.to_string()))
+    }
+}
+
+fn run<D : phy::Phy>(app : &ArgMatches, device : D) -> Result<(), Error> {
+    let max_redirects : usize = cli::value(app, "max-redirects")?;
+    let method = if app.is_present("head") { http::Method::Head } else { http::Method::Get };
+    let mut options = http::Options {
+        rx_buffer : cli::value(app, "recv-buffer")?,
+        tx_buffer : cli::value(app, "send-buffer")?,
+        // with the body on the terminal too, the bar would garble it
+        progress : app.is_present("output") && std::io::stderr().is_terminal(),
+        keep_alive : !app.is_present("no-keep-alive"),
+        follow_redirects : max_redirects > 0,
+        ..http::Options::default()
+    };
+    if app.is_present("no-window-scaling") {
+        // smoltcp offers a window scale only for buffers of 64 KiB and more
+        options.rx_buffer = options.rx_buffer.min(65535);
+    }
+    if options.rx_buffer == 0 || options.rx_buffer > 1 << 30 || options.tx_buffer == 0 {
+        return Err(Error::Usage("buffers must hold between 1 byte and 1 GiB".to_string()));
+    }
+
+    let mut urls = Vec::new();
+    for url_text in app.values_of("url").unwrap() {
+        let url = Url::parse(url_text).map_err(|e| Error::parse(format!("{:?} as a URL", url_text), e))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(Error::Usage(format!("{}: only HTTP and HTTPS protocols supported", url)));
+        }
+        urls.push(url);
+    }
+    let tls = tls::config(app.value_of("ca-file"))?;
+    let mac = cli::mac_address(app)?.into();
+    let mut stack = stack::Stack::new(device, mac);
+    let loopback = app.is_present("loopback");
+    let ipv4 = !app.is_present("ipv6");
+    let ipv6 = !app.is_present("ipv4");
+    cli::configure(app, &mut stack, ipv4, ipv6)?;
+    if loopback {
+        stack.serve(Box::new(loopback::HttpServer::demo()));
+    }
+    let dns_server = cli::dns_server(app, &stack, ipv4)?;
+    let mut record_types = Vec::new();
+    if ipv6 {
+        record_types.push(RecordType::AAAA);
+    }
+    if ipv4 {
+        record_types.push(RecordType::A);
+    }
+    let mut out : Box<dyn Write> = match app.value_of("output") {
+        Some(path) => Box::new(File::create(path).map_err(|e| Error::io(format!("unable to create {}", path), e))?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    let mut cache = dns::Cache::new(&record_types);
+    let mut pool = http::Pool::new(tls);
+    let mut failed = None;
+    for mut url in urls {
+        let mut redirects = 0;
+        let response = loop {
+            let domain_name = url.host_str()
+                .ok_or_else(|| Error::Usage(format!("{}: domain name required", url)))?;
+            let (addr, names) = if loopback {
+                // every name is served by the demo server
+                let addr = if ipv4 {
+                    IpAddr::from(std::net::Ipv4Addr::from(loopback::IPV4_ADDRESS))
+                } else {
+                    IpAddr::from(std::net::Ipv6Addr::from(loopback::IPV6_ADDRESS))
+                };
+                (addr, Ok(vec![]))
+            } else {
+                let addrs = match url.host() {
+                    Some(Host::Ipv4(addr)) => vec![IpAddr::V4(addr)],
+                    Some(Host::Ipv6(addr)) => vec![IpAddr::V6(addr)],
+                    _ => cache.resolve(upstream(app, &mut stack, dns_server).as_mut(), domain_name)?,
+                };
+                let addr = match addrs.iter().find(|addr| (addr.is_ipv4() && ipv4 || addr.is_ipv6() && ipv6) && stack.reaches(**addr)) {
+                    Some(addr) => *addr,
+                    None if addrs.is_empty() => return Err(Error::NoAddresses(domain_name.to_string())),
+                    None => {
+                        let addrs : Vec<_> = addrs.iter().map(|addr| addr.to_string()).collect();
+                        return Err(Error::Unreachable(addrs.join(", ")));
+                    }
+                };
+                (addr, cache.reverse(upstream(app, &mut stack, dns_server).as_mut(), addr))
+            };
+            match names {
+                Ok(names) if !names.is_empty() => eprintln!("peer {} is {}", addr, names.join(", ")),
+                _ => eprintln!("peer {}", addr),
+            }
+            let (response, transfer) = pool.get(&mut stack, addr, &url, method, &options, &mut out)?;
+            eprintln!("{}", transfer);
+            if method == http::Method::Head {
+                write!(out, "{}", response).map_err(|e| Error::io("unable to write the headers", e))?;
+            }
+            let location = match response.location() {
+                Some(location) if max_redirects > 0 => location,
+                _ => break response,
+            };
+            redirects += 1;
+            if redirects > max_redirects {
+                return Err(Error::Protocol(format!("more than {} redirects", max_redirects)));
+            }
+            url = match url.join(location) {
+                Ok(next) if next.scheme() == "http" || next.scheme() == "https" => next,
+                Ok(next) => {
+                    return Err(Error::Usage(format!("redirected to {}, only HTTP and HTTPS protocols supported", next)));
+                }
+                Err(_) => return Err(Error::Protocol(format!("invalid redirect to {:?}", location))),
+            };
+            eprintln!("{} {}, following to {}", response.status, response.reason, url);
+        };
+        out.flush().map_err(|e| Error::io("unable to write the output", e))?;
+        eprintln!("{} {}", response.status, response.reason);
+        if response.status >= 400 && failed.is_none() {
+            failed = Some(Error::Status { status : response.status, reason : response.reason.clone() });
+        }
+    }
+    pool.close(&mut stack);
+
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
@@ -0,0 +1,262 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpAddress, IpEndpoint, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket,
+};
+
+use crate::phy::Phy;
+
+/// What one TCP connection did, as seen from the segments it exchanged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TcpStats {
+    pub segments : u64,
+    pub retransmissions : u64,
+    pub rtt_samples : u64,
+    pub rtt_min : Duration,
+    pub rtt_max : Duration,
+    rtt_total : Duration,
+}
+
+impl TcpStats {
+    pub fn rtt_avg(&self) -> Option<Duration> {
+        if self.rtt_samples == 0 {
+            return None;
+        }
+        Some(self.rtt_total / self.rtt_samples as u32)
+    }
+
+    fn sample(&mut self, rtt : Duration) {
+        if self.rtt_samples == 0 || rtt < self.rtt_min {
+            self.rtt_min = rtt;
+        }
+        if rtt > self.rtt_max {
+            self.rtt_max = rtt;
+        }
+        self.rtt_total += rtt;
+        self.rtt_samples += 1;
+    }
+}
+
+struct Sent {
+    end : u32,
+    at : Instant,
+    retransmitted : bool,
+}
+
+/// The connection being timed.
+struct Watch {
+    local_port : u16,
+    remote : IpEndpoint,
+    next_seq : Option<u32>,
+    in_flight : VecDeque<Sent>,
+    stats : TcpStats,
+}
+
+/// Whether sequence number `a` comes after `b`, modulo 2^32.
+fn after(a : u32, b : u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+impl Watch {
+    fn sent(&mut self, seq : u32, len : u32, at : Instant) {
+        self.stats.segments += 1;
+        if len == 0 {
+            return;
+        }
+        let end = seq.wrapping_add(len);
+        match self.next_seq {
+            Some(next_seq) if !after(end, next_seq) => {
+                self.stats.retransmissions += 1;
+                // Karn: an ACK can't tell which copy it is for, so these aren't timed
+                for sent in self.in_flight.iter_mut().filter(|sent| after(sent.end, seq)) {
+                    sent.retransmitted = true;
+                }
+            }
+            _ => {
+                self.in_flight.push_back(Sent { end, at, retransmitted : false });
+                self.next_seq = Some(end);
+            }
+        }
+    }
+
+    fn acked(&mut self, ack : u32, at : Instant) {
+        let mut newest = None;
+        while let Some(sent) = self.in_flight.front() {
+            if after(sent.end, ack) {
+                break;
+            }
+            newest = self.in_flight.pop_front();
+        }
+        if let Some(sent) = newest.filter(|sent| !sent.retransmitted) {
+            self.stats.sample(at - sent.at);
+        }
+    }
+}
+
+/// Passes frames on to the device underneath while timing the TCP segments
+/// of one connection. smoltcp keeps its RTT estimate and retransmissions
+/// to itself, so they are worked out from the frames instead.
+pub struct Meter<D> {
+    lower : D,
+    watch : RefCell<Option<Watch>>,
+}
+
+impl<D> Meter<D> {
+    pub fn new(lower : D) -> Self {
+        Meter { lower, watch : RefCell::new(None) }
+    }
+
+    /// Starts timing the connection from `local_port` to `remote`.
+    pub fn watch(&mut self, local_port : u16, remote : IpEndpoint) {
+        *self.watch.get_mut() = Some(Watch {
+            local_port,
+            remote,
+            next_seq : None,
+            in_flight : VecDeque::new(),
+            stats : TcpStats::default(),
+        });
+    }
+
+    /// Stops timing and returns what was seen since `watch`.
+    pub fn finish(&mut self) -> TcpStats {
+        self.watch.get_mut().take().map(|watch| watch.stats).unwrap_or_default()
+    }
+}
+
+/// Notes `frame` if it belongs to the watched connection. The clock is read
+/// here rather than taken from the poll, since one poll moves many frames.
+fn record(watch : &RefCell<Option<Watch>>, frame : &[u8], outgoing : bool) {
+    let timestamp = Instant::now();
+    let mut watch = watch.borrow_mut();
+    let watch = match watch.as_mut() {
+        Some(watch) => watch,
+        None => return,
+    };
+    let frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    let (src_addr, dst_addr, payload) : (IpAddress, IpAddress, &[u8]) = match frame.ethertype() {
+        EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+            Ok(ip) if ip.next_header() == IpProtocol::Tcp => (ip.src_addr().into(), ip.dst_addr().into(), ip.payload()),
+            _ => return,
+        },
+        EthernetProtocol::Ipv6 => match Ipv6Packet::new_checked(frame.payload()) {
+            Ok(ip) if ip.next_header() == IpProtocol::Tcp => (ip.src_addr().into(), ip.dst_addr().into(), ip.payload()),
+            _ => return,
+        },
+        _ => return,
+    };
+    let tcp = match TcpPacket::new_checked(payload) {
+        Ok(tcp) => tcp,
+        Err(_) => return,
+    };
+    if outgoing && tcp.src_port() == watch.local_port && IpEndpoint::new(dst_addr, tcp.dst_port()) == watch.remote {
+        let len = tcp.payload().len() as u32 + tcp.syn() as u32 + tcp.fin() as u32;
+        watch.sent(tcp.seq_number().0 as u32, len, timestamp);
+    } else if !outgoing && tcp.dst_port() == watch.local_port && IpEndpoint::new(src_addr, tcp.src_port()) == watch.remote {
+        watch.stats.segments += 1;
+        if tcp.ack() {
+            watch.acked(tcp.ack_number().0 as u32, timestamp);
+        }
+    }
+}
+
+impl<D : Device> Device for Meter<D> {
+    type RxToken<'a> = RxToken<'a, D::RxToken<'a>> where Self : 'a;
+    type TxToken<'a> = TxToken<'a, D::TxToken<'a>> where Self : 'a;
+
+    fn receive(&mut self, timestamp : Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let watch = &self.watch;
+        self.lower.receive(timestamp).map(move |(rx, tx)| (RxToken { token : rx, watch }, TxToken { token : tx, watch }))
+    }
+
+    fn transmit(&mut self, timestamp : Instant) -> Option<Self::TxToken<'_>> {
+        let watch = &self.watch;
+        self.lower.transmit(timestamp).map(move |token| TxToken { token, watch })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.lower.capabilities()
+    }
+}
+
+impl<D : Phy> Phy for Meter<D> {
+    fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()> {
+        self.lower.wait(timeout)
+    }
+}
+
+pub struct RxToken<'a, Rx> {
+    token : Rx,
+    watch : &'a RefCell<Option<Watch>>,
+}
+
+impl<'a, Rx : phy::RxToken> phy::RxToken for RxToken<'a, Rx> {
+    fn consume<R, F : FnOnce(&mut [u8]) -> R>(self, f : F) -> R {
+        self.token.consume(|buffer| {
+            record(self.watch, buffer, false);
+            f(buffer)
+        })
+    }
+}
+
+pub struct TxToken<'a, Tx> {
+    token : Tx,
+    watch : &'a RefCell<Option<Watch>>,
+}
+
+impl<'a, Tx : phy::TxToken> phy::TxToken for TxToken<'a, Tx> {
+    fn consume<R, F : FnOnce(&mut [u8]) -> R>(self, len : usize, f : F) -> R {
+        self.token.consume(len, |buffer| {
+            let result = f(buffer);
+            record(self.watch, buffer, true);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis : u64) -> Instant {
+        Instant::from_millis(millis as i64)
+    }
+
+    #[test]
+    fn times_acks_but_not_retransmissions() {
+        let mut watch = Watch {
+            local_port : 50000,
+            remote : IpEndpoint::new(IpAddress::v4(10, 0, 0, 1), 80),
+            next_seq : None,
+            in_flight : VecDeque::new(),
+            stats : TcpStats::default(),
+        };
+        // SYN, acked 5 ms later
+        watch.sent(u32::MAX, 1, at(0));
+        watch.acked(0, at(5));
+        // two segments across the wraparound, one ACK for both
+        watch.sent(0, 100, at(10));
+        watch.sent(100, 100, at(11));
+        watch.acked(200, at(18));
+        // a lost segment sent twice
+        watch.sent(200, 100, at(20));
+        watch.sent(200, 100, at(220));
+        watch.acked(300, at(225));
+        // a pure ACK takes no sequence space
+        watch.sent(300, 0, at(230));
+
+        let stats = watch.stats;
+        assert_eq!(stats.segments, 6);
+        assert_eq!(stats.retransmissions, 1);
+        assert_eq!(stats.rtt_samples, 2);
+        assert_eq!(stats.rtt_min, Duration::from_millis(5));
+        assert_eq!(stats.rtt_max, Duration::from_millis(7));
+        assert_eq!(stats.rtt_avg(), Some(Duration::from_millis(6)));
+    }
+}
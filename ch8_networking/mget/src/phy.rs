@@ -0,0 +1,37 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use smoltcp::phy::{wait as phy_wait, Device, Loopback, PcapSink, PcapWriter};
+use smoltcp::time::Duration;
+
+use crate::tap::TapDevice;
+
+/// A device the stack can run on: anything smoltcp can send frames through,
+/// plus a way to sleep until the next frame may have arrived.
+pub trait Phy : Device {
+    fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()>;
+}
+
+impl Phy for TapDevice {
+    fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()> {
+        phy_wait(self.as_raw_fd(), timeout)
+    }
+}
+
+/// A loopback device can't tell whether frames are queued, and smoltcp
+/// leaves some there, such as ARP requests it then backs off from for a
+/// second. So this only naps and lets the caller poll again.
+impl Phy for Loopback {
+    fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()> {
+        let nap = Duration::from_millis(1);
+        std::thread::sleep(timeout.map_or(nap, |timeout| timeout.min(nap)).into());
+        Ok(())
+    }
+}
+
+/// Records every frame of the device underneath in pcap format.
+impl<D : Phy, S : PcapSink> Phy for PcapWriter<D, S> {
+    fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()> {
+        self.get_mut().wait(timeout)
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Instant;
+
+use clap::{App, Arg, ArgMatches};
+use libmget::error::Error;
+use libmget::icmp::{self, Pinger, Reply, Statistics};
+use libmget::{cli, phy, stack};
+
+fn main() {
+    let app = App::new("ping")
+        .about("Sends ICMP echo requests from a TAP device and times the replies")
+        .arg(Arg::with_name("host").required(true))
+        .args(&cli::interface_args())
+        .arg(Arg::with_name("count")
+            .short("c")
+            .long("count")
+            .takes_value(true)
+            .default_value("4")
+            .help("Echo requests to send"))
+        .arg(Arg::with_name("interval")
+            .short("i")
+            .long("interval")
+            .takes_value(true)
+            .value_name("seconds")
+            .default_value("1")
+            .help("Time between echo requests"))
+        .arg(Arg::with_name("size")
+            .short("s")
+            .long("size")
+            .takes_value(true)
+            .value_name("bytes")
+            .default_value("56")
+            .help("Bytes of data in each echo request"))
+        .arg(Arg::with_name("ttl")
+            .short("t")
+            .long("ttl")
+            .takes_value(true)
+            .default_value("64")
+            .help("Time to live of the echo requests"))
+        .arg(Arg::with_name("timeout")
+            .short("W")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("seconds")
+            .default_value("1")
+            .help("Time to wait for each reply"))
+        .get_matches();
+
+    if let Err(e) = cli::with_device(&app, Ping) {
+        e.exit();
+    }
+}
+
+struct Ping;
+
+impl cli::Run for Ping {
+    fn run<D : phy::Phy>(self, app : &ArgMatches, device : D) -> Result<(), Error> {
+        run(app, device)
+    }
+}
+
+fn run<D : phy::Phy>(app : &ArgMatches, device : D) -> Result<(), Error> {
+    let host = app.value_of("host").unwrap();
+    let count : u64 = cli::value(app, "count")?;
+    let size : usize = cli::value(app, "size")?;
+    if size > 1472 {
+        return Err(Error::Usage("<size> must be at most 1472".to_string()));
+    }
+    let ttl : u8 = cli::value(app, "ttl")?;
+    if ttl == 0 {
+        return Err(Error::Usage("<ttl> must be between 1 and 255".to_string()));
+    }
+    let interval = cli::seconds(app, "interval")?;
+    let timeout = cli::seconds(app, "timeout")?;
+
+    let mac = cli::mac_address(app)?.into();
+    let mut stack = stack::Stack::new(device, mac);
+    cli::configure(app, &mut stack, true, false)?;
+    let dst = cli::ipv4_destination(app, &stack, host)?;
+    let mut pinger = Pinger::new(&mut stack)?;
+
+    println!("PING {} ({}) {}({}) bytes of data.", host, dst, size, icmp::packet_len(size));
+    let mut statistics = Statistics::new();
+    // whether a router answered for the host, when the host itself never does
+    let mut refused = false;
+    for i in 0..count {
+        let probed = Instant::now();
+        let answer = pinger.probe(&mut stack, dst, ttl, size, timeout)?;
+        let seq_no = pinger.seq_no();
+        match answer.as_ref() {
+            Some(icmp::Answer { reply : Reply::Echo { from, ttl, len }, rtt }) => println!(
+                "{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms",
+                len, from, seq_no, ttl, rtt.as_secs_f64() * 1000.0,
+            ),
+            Some(icmp::Answer { reply : Reply::TimeExceeded { from }, .. }) => {
+                println!("From {} icmp_seq={} Time to live exceeded", from, seq_no)
+            }
+            Some(icmp::Answer { reply : Reply::Unreachable { from, reason }, .. }) => {
+                println!("From {} icmp_seq={} {}", from, seq_no, reason)
+            }
+            None => println!("Request timeout for icmp_seq={}", seq_no),
+        }
+        statistics.record(answer.as_ref());
+        refused |= answer.is_some();
+
+        if i + 1 < count {
+            // keeps the interface answering ARP while it waits
+            while let Some(remaining) = interval.checked_sub(probed.elapsed()).filter(|d| !d.is_zero()) {
+                let timestamp = smoltcp::time::Instant::now();
+                stack.poll(timestamp);
+                stack.wait(timestamp, Some(remaining.into())).map_err(|e| Error::io("unable to wait for the device", e))?;
+            }
+        }
+    }
+    pinger.close(&mut stack);
+
+    println!();
+    println!("--- {} ping statistics ---", host);
+    println!("{}", statistics);
+    if statistics.received() > 0 {
+        Ok(())
+    } else if refused {
+        Err(Error::Unreachable(dst.to_string()))
+    } else {
+        Err(Error::Timeout(format!("echo requests to {}", dst)))
+    }
+}
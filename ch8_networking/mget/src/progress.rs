@@ -0,0 +1,117 @@
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+const WIDTH : usize = 30;
+
+/// `amount` bytes with a binary unit, e.g. "1.5 MiB".
+pub fn human_bytes(amount : u64) -> String {
+    const UNITS : [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if amount < 1024 {
+        return format!("{} B", amount);
+    }
+    let mut value = amount as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn human_rate(amount : u64, elapsed : Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    if seconds <= 0.0 {
+        return "-".to_string();
+    }
+    format!("{}/s", human_bytes((amount as f64 / seconds) as u64))
+}
+
+/// The progress line for `done` out of `total` bytes after `elapsed`.
+fn render(done : u64, total : Option<u64>, elapsed : Duration) -> String {
+    match total {
+        Some(total) if total > 0 => {
+            let filled = ((done.min(total) as u128 * WIDTH as u128) / total as u128) as usize;
+            let bar = match filled {
+                WIDTH => "=".repeat(WIDTH),
+                _ => format!("{}>{}", "=".repeat(filled), " ".repeat(WIDTH - filled - 1)),
+            };
+            format!(
+                "[{}] {:>3}% {} of {} {}",
+                bar,
+                done.min(total) * 100 / total,
+                human_bytes(done),
+                human_bytes(total),
+                human_rate(done, elapsed),
+            )
+        }
+        _ => format!("{} {}", human_bytes(done), human_rate(done, elapsed)),
+    }
+}
+
+/// A progress bar on stderr, redrawn in place at most ten times a second.
+pub struct Progress {
+    started : Instant,
+    drawn : Option<Instant>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress { started : Instant::now(), drawn : None }
+    }
+
+    pub fn update(&mut self, done : u64, total : Option<u64>) {
+        let now = Instant::now();
+        if self.drawn.is_some_and(|drawn| now - drawn < Duration::from_millis(100)) {
+            return;
+        }
+        self.drawn = Some(now);
+        self.draw(done, total);
+    }
+
+    /// Draws the final state and moves to the next line.
+    pub fn finish(&mut self, done : u64, total : Option<u64>) {
+        if self.drawn.is_some() {
+            self.draw(done, total);
+            eprintln!();
+        }
+    }
+
+    fn draw(&self, done : u64, total : Option<u64>) {
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", render(done, total, self.started.elapsed()));
+        let _ = stderr.flush();
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(human_rate(2048, Duration::from_secs(2)), "1.0 KiB/s");
+    }
+
+    #[test]
+    fn bars() {
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            render(512, Some(1024), second),
+            format!("[{}>{}]  50% 512 B of 1.0 KiB 512 B/s", "=".repeat(15), " ".repeat(14)),
+        );
+        assert_eq!(
+            render(1024, Some(1024), second),
+            format!("[{}] 100% 1.0 KiB of 1.0 KiB 1.0 KiB/s", "=".repeat(30)),
+        );
+        assert_eq!(render(2048, None, second), "2.0 KiB 2.0 KiB/s");
+    }
+}
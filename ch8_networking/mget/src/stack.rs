@@ -0,0 +1,688 @@
+use std::io;
+use std::net::IpAddr;
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::{dhcpv4, raw, udp};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetAddress, HardwareAddress, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion,
+    Ipv4Address, Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr,
+};
+
+use crate::meter::Meter;
+use crate::phy::Phy;
+
+/// RFC 4861 waits this long between router solicitations.
+const RTR_SOLICITATION_INTERVAL : std::time::Duration = std::time::Duration::from_secs(4);
+
+/// How much longer to wait for the other address family once one is up.
+const AUTOCONF_GRACE : std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Something that answers peers from the interface itself, such as the HTTP
+/// server of a loopback run. It gets a turn after every poll.
+pub trait Service {
+    fn poll(&mut self, sockets : &mut SocketSet<'static>);
+}
+
+/// A smoltcp interface together with the device it runs on and its sockets.
+/// Neighbors are found with ARP and NDP by the interface itself.
+pub struct Stack<D> {
+    pub device : Meter<D>,
+    pub iface : Interface,
+    pub sockets : SocketSet<'static>,
+    pub dns_servers : Vec<Ipv4Address>,
+    services : Vec<Box<dyn Service>>,
+}
+
+impl<D : Phy> Stack<D> {
+    /// Brings up an interface with `mac` and only its IPv6 link-local
+    /// address, which NDP and router solicitations are sent from.
+    pub fn new(device : D, mac : EthernetAddress) -> Self {
+        let mut device = Meter::new(device);
+        let mut config = Config::new();
+        config.random_seed = rand::random();
+        config.hardware_addr = Some(mac.into());
+        let mut iface = Interface::new(config, &mut device);
+        iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.push(IpCidr::Ipv6(Ipv6Cidr::new(link_local(mac), 64))).unwrap();
+        });
+        Stack { device, iface, sockets : SocketSet::new(vec![]), dns_servers : Vec::new(), services : Vec::new() }
+    }
+
+    fn mac(&self) -> EthernetAddress {
+        match self.iface.hardware_addr() {
+            HardwareAddress::Ethernet(mac) => mac,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("stacks are always Ethernet"),
+        }
+    }
+
+    pub fn serve(&mut self, service : Box<dyn Service>) {
+        self.services.push(service);
+    }
+
+    /// Moves frames between the device and the sockets.
+    pub fn poll(&mut self, timestamp : Instant) {
+        self.iface.poll(timestamp, &mut self.device, &mut self.sockets);
+        for service in self.services.iter_mut() {
+            service.poll(&mut self.sockets);
+        }
+    }
+
+    /// Sleeps until the stack has something to do, or `limit` has passed.
+    pub fn wait(&mut self, timestamp : Instant, limit : Option<Duration>) -> io::Result<()> {
+        let delay = match (self.iface.poll_delay(timestamp, &self.sockets), limit) {
+            (Some(delay), Some(limit)) => Some(delay.min(limit)),
+            (delay, limit) => delay.or(limit),
+        };
+        self.device.wait(delay)
+    }
+
+    /// Gives the interface `address` in place of any other address of its
+    /// family, except the link-local one, and sends everything off-link
+    /// for that family through `gateway`.
+    pub fn configure(&mut self, address : IpCidr, gateway : Option<IpAddress>) {
+        self.iface.update_ip_addrs(|ip_addrs| {
+            let kept : Vec<IpCidr> = ip_addrs.iter()
+                .filter(|cidr| match (cidr, address) {
+                    (IpCidr::Ipv4(_), IpCidr::Ipv4(_)) => false,
+                    (IpCidr::Ipv6(cidr), IpCidr::Ipv6(_)) => cidr.address().is_link_local(),
+                    _ => true,
+                })
+                .copied()
+                .collect();
+            // smoltcp sends from the first address of a family, which must not be the link-local one
+            ip_addrs.clear();
+            ip_addrs.push(address).unwrap();
+            for cidr in kept {
+                ip_addrs.push(cidr).unwrap();
+            }
+        });
+        let routes = self.iface.routes_mut();
+        match address {
+            IpCidr::Ipv4(_) => routes.remove_default_ipv4_route(),
+            IpCidr::Ipv6(_) => routes.remove_default_ipv6_route(),
+        };
+        match gateway {
+            Some(IpAddress::Ipv4(gateway)) => routes.add_default_ipv4_route(gateway).unwrap(),
+            Some(IpAddress::Ipv6(gateway)) => routes.add_default_ipv6_route(gateway).unwrap(),
+            None => None,
+        };
+    }
+
+    /// Whether the interface has an address to talk to `addr` from. IPv6
+    /// peers beyond the link need more than the link-local address.
+    pub fn reaches(&self, addr : IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(_) => self.iface.ipv4_addr().is_some(),
+            IpAddr::V6(addr) if Ipv6Address::from(addr).is_link_local() => true,
+            IpAddr::V6(_) => self.iface.ip_addrs().iter().any(|cidr| match cidr {
+                IpCidr::Ipv6(cidr) => !cidr.address().is_link_local(),
+                #[allow(unreachable_patterns)]
+                _ => false,
+            }),
+        }
+    }
+
+    /// Asks a DHCP server for an address, a gateway and DNS servers, and
+    /// routers for an on-link IPv6 prefix to form an address in as SLAAC
+    /// does, side by side. Once one family is up the other gets a short
+    /// grace period, and only when neither comes up is it an error.
+    pub fn autoconfigure(&mut self, ipv4 : bool, ipv6 : bool, timeout : std::time::Duration) -> io::Result<()> {
+        let dhcp_handle = if ipv4 { Some(self.sockets.add(dhcpv4::Socket::new())) } else { None };
+        let slaac_handle = if ipv6 {
+            let rx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 4], vec![0; 4096]);
+            let tx_buffer = raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY; 4], vec![0; 1024]);
+            Some(self.sockets.add(raw::Socket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer)))
+        } else {
+            None
+        };
+        let result = self.autoconfigure_with(dhcp_handle, slaac_handle, timeout);
+        for handle in dhcp_handle.into_iter().chain(slaac_handle) {
+            self.sockets.remove(handle);
+        }
+        result
+    }
+
+    fn autoconfigure_with(&mut self, mut dhcp_handle : Option<SocketHandle>, mut slaac_handle : Option<SocketHandle>,
+        timeout : std::time::Duration) -> io::Result<()>
+    {
+        let started = std::time::Instant::now();
+        let mut deadline = started + timeout;
+        let mut next_solicitation = started;
+        let mut configured = false;
+        loop {
+            let timestamp = Instant::now();
+            self.poll(timestamp);
+            if dhcp_handle.is_some_and(|handle| self.lease(handle)) {
+                dhcp_handle = None;
+                configured = true;
+            }
+            if let Some(handle) = slaac_handle {
+                if self.advertisement(handle) {
+                    slaac_handle = None;
+                    configured = true;
+                } else if std::time::Instant::now() >= next_solicitation {
+                    self.solicit(handle)?;
+                    next_solicitation += RTR_SOLICITATION_INTERVAL;
+                }
+            }
+            if dhcp_handle.is_none() && slaac_handle.is_none() {
+                return Ok(());
+            }
+            let now = std::time::Instant::now();
+            if configured {
+                deadline = deadline.min(now + AUTOCONF_GRACE);
+            }
+
+            if now >= deadline {
+                let missing = match (dhcp_handle, slaac_handle) {
+                    (Some(_), Some(_)) => "no DHCP lease or router advertisement",
+                    (Some(_), None) => "no DHCP lease",
+                    _ => "no router advertisement",
+                };
+                if !configured {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, missing));
+                }
+                eprintln!("warning: {}", missing);
+                return Ok(());
+            }
+            let mut limit = deadline - now;
+            if slaac_handle.is_some() {
+                limit = limit.min(next_solicitation.saturating_duration_since(now));
+            }
+            self.wait(timestamp, Some(Duration::from(limit)))?;
+        }
+    }
+
+    /// Takes the lease once the DHCP socket has one.
+    fn lease(&mut self, dhcp_handle : SocketHandle) -> bool {
+        let lease = match self.sockets.get_mut::<dhcpv4::Socket>(dhcp_handle).poll() {
+            Some(dhcpv4::Event::Configured(config)) => (config.address, config.router, config.dns_servers.to_vec()),
+            _ => return false,
+        };
+        let (address, router, dns_servers) = lease;
+        match router {
+            Some(router) => eprintln!("dhcp: address {} via {}", address, router),
+            None => eprintln!("dhcp: address {}, no gateway", address),
+        }
+        self.configure(address.into(), router.map(IpAddress::Ipv4));
+        self.dns_servers = dns_servers;
+        true
+    }
+
+    fn solicit(&mut self, slaac_handle : SocketHandle) -> io::Result<()> {
+        let solicitation = router_solicitation(self.mac());
+        self.sockets.get_mut::<raw::Socket>(slaac_handle)
+            .send_slice(&solicitation)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+
+    /// Takes the first usable prefix that routers have advertised.
+    fn advertisement(&mut self, slaac_handle : SocketHandle) -> bool {
+        let socket = self.sockets.get_mut::<raw::Socket>(slaac_handle);
+        let mut advertised = None;
+        while let Ok(packet) = socket.recv() {
+            advertised = advertised.or_else(|| router_advertisement(packet));
+        }
+        let (router, prefix, default) = match advertised {
+            Some(advertised) => advertised,
+            None => return false,
+        };
+        let address = slaac_address(prefix, self.mac());
+        if default {
+            eprintln!("slaac: address {}/64 via {}", address, router);
+        } else {
+            eprintln!("slaac: address {}/64, no gateway", address);
+        }
+        let gateway = if default { Some(IpAddress::Ipv6(router)) } else { None };
+        self.configure(IpCidr::Ipv6(Ipv6Cidr::new(address, 64)), gateway);
+        true
+    }
+
+    /// Sends `request` to `server` and returns the first datagram back from
+    /// it that `accept` takes, skipping any others until `timeout`.
+    pub fn udp_exchange(&mut self, server : IpEndpoint, request : &[u8], accept : &dyn Fn(&[u8]) -> bool,
+        timeout : std::time::Duration) -> io::Result<Vec<u8>>
+    {
+        let rx_buffer = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 4096]);
+        let tx_buffer = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 4096]);
+        let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+        socket
+            .bind(49152 + rand::random::<u16>() % 16384)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        let udp_handle = self.sockets.add(socket);
+        let result = self.exchange(udp_handle, server, request, accept, timeout);
+        self.sockets.remove(udp_handle);
+        result
+    }
+
+    fn exchange(&mut self, udp_handle : SocketHandle, server : IpEndpoint, request : &[u8],
+        accept : &dyn Fn(&[u8]) -> bool, timeout : std::time::Duration) -> io::Result<Vec<u8>>
+    {
+        let started = std::time::Instant::now();
+        let mut sent = false;
+        loop {
+            let timestamp = Instant::now();
+            self.poll(timestamp);
+            let socket = self.sockets.get_mut::<udp::Socket>(udp_handle);
+            if !sent && socket.can_send() {
+                socket.send_slice(request, server).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+                sent = true;
+            }
+            while socket.can_recv() {
+                match socket.recv() {
+                    Ok((response, remote)) if remote == server && accept(response) => return Ok(response.to_vec()),
+                    _ => {}
+                }
+            }
+
+            let remaining = match timeout.checked_sub(started.elapsed()) {
+                Some(remaining) => Duration::from(remaining),
+                None => return Err(io::Error::new(io::ErrorKind::TimedOut, "no UDP response")),
+            };
+            self.wait(timestamp, Some(remaining))?;
+        }
+    }
+}
+
+/// The modified EUI-64 interface identifier of `mac`.
+fn interface_id(mac : EthernetAddress) -> [u8; 8] {
+    let mac = mac.0;
+    [mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]
+}
+
+/// The fe80::/64 address an interface with `mac` always has.
+pub fn link_local(mac : EthernetAddress) -> Ipv6Address {
+    slaac_address(Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), mac)
+}
+
+fn slaac_address(prefix : Ipv6Address, mac : EthernetAddress) -> Ipv6Address {
+    let mut address = prefix.0;
+    address[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Address(address)
+}
+
+/// An IPv6 packet asking all routers on the link to advertise themselves.
+fn router_solicitation(mac : EthernetAddress) -> Vec<u8> {
+    let src_addr = link_local(mac);
+    let dst_addr = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+    let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr : Some(mac.into()) });
+    let ip_repr = Ipv6Repr {
+        src_addr,
+        dst_addr,
+        next_header : IpProtocol::Icmpv6,
+        payload_len : icmp_repr.buffer_len(),
+        // NDP messages from beyond the link are dropped by checking this
+        hop_limit : 255,
+    };
+    let mut packet = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    let mut ip_packet = Ipv6Packet::new_unchecked(&mut packet);
+    ip_repr.emit(&mut ip_packet);
+    icmp_repr.emit(
+        &src_addr.into(),
+        &dst_addr.into(),
+        &mut Icmpv6Packet::new_unchecked(ip_packet.payload_mut()),
+        &ChecksumCapabilities::default(),
+    );
+    packet
+}
+
+/// The router, the /64 prefix to form an address in and whether the router
+/// is a default router, if `packet` is an advertisement with such a prefix.
+fn router_advertisement(packet : &[u8]) -> Option<(Ipv6Address, Ipv6Address, bool)> {
+    let ip_packet = Ipv6Packet::new_checked(packet).ok()?;
+    let ip_repr = Ipv6Repr::parse(&ip_packet).ok()?;
+    if ip_repr.hop_limit != 255 {
+        return None;
+    }
+    let icmp_packet = Icmpv6Packet::new_checked(ip_packet.payload()).ok()?;
+    let icmp_repr = Icmpv6Repr::parse(
+        &ip_repr.src_addr.into(),
+        &ip_repr.dst_addr.into(),
+        &icmp_packet,
+        &ChecksumCapabilities::default(),
+    )
+    .ok()?;
+    match icmp_repr {
+        Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert { router_lifetime, prefix_info : Some(prefix_info), .. })
+            if prefix_info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                && prefix_info.prefix_len == 64
+                && prefix_info.valid_lifetime > Duration::ZERO =>
+        {
+            Some((ip_repr.src_addr, prefix_info.prefix, router_lifetime > Duration::ZERO))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::thread;
+    use smoltcp::phy::{self, ChecksumCapabilities, Device, DeviceCapabilities, Medium};
+    use smoltcp::wire::{
+        ArpOperation, ArpPacket, ArpRepr, DhcpMessageType, DhcpPacket, DhcpRepr, EthernetFrame, EthernetProtocol, EthernetRepr,
+        Ipv4Cidr, Ipv4Packet, Ipv4Repr, NdiscPrefixInformation, NdiscRouterFlags, UdpPacket, UdpRepr,
+    };
+
+    /// Carries frames over one end of a socket pair.
+    struct PairDevice(UnixDatagram);
+
+    struct Rx(Vec<u8>);
+
+    struct Tx<'a>(&'a UnixDatagram);
+
+    impl phy::RxToken for Rx {
+        fn consume<R, F : FnOnce(&mut [u8]) -> R>(mut self, f : F) -> R {
+            f(&mut self.0)
+        }
+    }
+
+    impl<'a> phy::TxToken for Tx<'a> {
+        fn consume<R, F : FnOnce(&mut [u8]) -> R>(self, len : usize, f : F) -> R {
+            let mut frame = vec![0; len];
+            let result = f(&mut frame);
+            self.0.send(&frame).unwrap();
+            result
+        }
+    }
+
+    impl Device for PairDevice {
+        type RxToken<'a> = Rx;
+        type TxToken<'a> = Tx<'a>;
+
+        fn receive(&mut self, _timestamp : Instant) -> Option<(Rx, Tx<'_>)> {
+            let mut frame = vec![0; 1514];
+            let len = self.0.recv(&mut frame).ok()?;
+            frame.truncate(len);
+            Some((Rx(frame), Tx(&self.0)))
+        }
+
+        fn transmit(&mut self, _timestamp : Instant) -> Option<Tx<'_>> {
+            Some(Tx(&self.0))
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut caps = DeviceCapabilities::default();
+            caps.medium = Medium::Ethernet;
+            caps.max_transmission_unit = 1514;
+            caps
+        }
+    }
+
+    impl Phy for PairDevice {
+        fn wait(&mut self, timeout : Option<Duration>) -> io::Result<()> {
+            smoltcp::phy::wait(self.0.as_raw_fd(), timeout)
+        }
+    }
+
+    const SERVER_MAC : EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const SERVER_IP : Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    const LEASED_IP : Ipv4Address = Ipv4Address([10, 0, 0, 7]);
+    const DNS_IP : Ipv4Address = Ipv4Address([10, 0, 0, 53]);
+
+    /// Answers a DISCOVER with an OFFER and a REQUEST with an ACK.
+    fn dhcp_reply(frame : &[u8]) -> Option<Vec<u8>> {
+        let eth = EthernetFrame::new_checked(frame).ok()?;
+        let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+        let udp = UdpPacket::new_checked(ip.payload()).ok()?;
+        if udp.dst_port() != 67 {
+            return None;
+        }
+        let dhcp = DhcpPacket::new_checked(udp.payload()).ok()?;
+        let request = DhcpRepr::parse(&dhcp).ok()?;
+        let message_type = match request.message_type {
+            DhcpMessageType::Discover => DhcpMessageType::Offer,
+            DhcpMessageType::Request => DhcpMessageType::Ack,
+            _ => return None,
+        };
+        let mut dns_servers = heapless::Vec::new();
+        dns_servers.push(DNS_IP).unwrap();
+        let reply = DhcpRepr {
+            message_type,
+            transaction_id : request.transaction_id,
+            secs : 0,
+            client_hardware_address : request.client_hardware_address,
+            client_ip : Ipv4Address::UNSPECIFIED,
+            your_ip : LEASED_IP,
+            server_ip : SERVER_IP,
+            router : Some(SERVER_IP),
+            subnet_mask : Some(Ipv4Address([255, 255, 255, 0])),
+            relay_agent_ip : Ipv4Address::UNSPECIFIED,
+            broadcast : false,
+            requested_ip : None,
+            client_identifier : None,
+            server_identifier : Some(SERVER_IP),
+            parameter_request_list : None,
+            dns_servers : Some(dns_servers),
+            max_size : None,
+            lease_duration : Some(3600),
+            renew_duration : None,
+            rebind_duration : None,
+            additional_options : &[],
+        };
+
+        let dhcp_len = reply.buffer_len();
+        let caps = ChecksumCapabilities::default();
+        let mut frame = vec![0; 14 + 20 + 8 + dhcp_len];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame);
+        EthernetRepr { src_addr : SERVER_MAC, dst_addr : EthernetAddress::BROADCAST, ethertype : EthernetProtocol::Ipv4 }
+            .emit(&mut eth);
+        let mut ip = Ipv4Packet::new_unchecked(eth.payload_mut());
+        Ipv4Repr {
+            src_addr : SERVER_IP,
+            dst_addr : Ipv4Address::BROADCAST,
+            next_header : IpProtocol::Udp,
+            payload_len : 8 + dhcp_len,
+            hop_limit : 64,
+        }
+        .emit(&mut ip, &caps);
+        let mut udp = UdpPacket::new_unchecked(ip.payload_mut());
+        UdpRepr { src_port : 67, dst_port : 68 }.emit(
+            &mut udp,
+            &IpAddress::Ipv4(SERVER_IP),
+            &IpAddress::Ipv4(Ipv4Address::BROADCAST),
+            dhcp_len,
+            |payload| reply.emit(&mut DhcpPacket::new_unchecked(payload)).unwrap(),
+            &caps,
+        );
+        Some(frame)
+    }
+
+    #[test]
+    fn leases_address_gateway_and_dns() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let mut frame = vec![0; 1514];
+            while let Ok(len) = server.recv(&mut frame) {
+                if let Some(reply) = dhcp_reply(&frame[..len]) {
+                    server.send(&reply).unwrap();
+                }
+            }
+        });
+
+        let mac = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+        let mut stack = Stack::new(PairDevice(client), mac);
+        stack.autoconfigure(true, false, std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(stack.iface.ipv4_addr(), Some(LEASED_IP));
+        assert_eq!(stack.dns_servers, vec![DNS_IP]);
+    }
+
+    /// Answers ARP requests for SERVER_IP and echoes UDP datagrams sent to it.
+    fn echo_reply(frame : &[u8]) -> Option<Vec<u8>> {
+        let eth = EthernetFrame::new_checked(frame).ok()?;
+        let caps = ChecksumCapabilities::default();
+        match eth.ethertype() {
+            EthernetProtocol::Arp => {
+                let arp = ArpRepr::parse(&ArpPacket::new_checked(eth.payload()).ok()?).ok()?;
+                let (client_mac, client_ip) = match arp {
+                    ArpRepr::EthernetIpv4 { operation : ArpOperation::Request, source_hardware_addr,
+                        source_protocol_addr, target_protocol_addr, .. } if target_protocol_addr == SERVER_IP => {
+                        (source_hardware_addr, source_protocol_addr)
+                    }
+                    _ => return None,
+                };
+                let reply = ArpRepr::EthernetIpv4 {
+                    operation : ArpOperation::Reply,
+                    source_hardware_addr : SERVER_MAC,
+                    source_protocol_addr : SERVER_IP,
+                    target_hardware_addr : client_mac,
+                    target_protocol_addr : client_ip,
+                };
+                let mut frame = vec![0; 14 + reply.buffer_len()];
+                let mut eth = EthernetFrame::new_unchecked(&mut frame);
+                EthernetRepr { src_addr : SERVER_MAC, dst_addr : client_mac, ethertype : EthernetProtocol::Arp }
+                    .emit(&mut eth);
+                reply.emit(&mut ArpPacket::new_unchecked(eth.payload_mut()));
+                Some(frame)
+            }
+            EthernetProtocol::Ipv4 => {
+                let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+                let udp = UdpPacket::new_checked(ip.payload()).ok()?;
+                let payload = udp.payload();
+                let mut frame = vec![0; 14 + 20 + 8 + payload.len()];
+                let mut reply = EthernetFrame::new_unchecked(&mut frame);
+                EthernetRepr { src_addr : SERVER_MAC, dst_addr : eth.src_addr(), ethertype : EthernetProtocol::Ipv4 }
+                    .emit(&mut reply);
+                let mut reply_ip = Ipv4Packet::new_unchecked(reply.payload_mut());
+                Ipv4Repr {
+                    src_addr : ip.dst_addr(),
+                    dst_addr : ip.src_addr(),
+                    next_header : IpProtocol::Udp,
+                    payload_len : 8 + payload.len(),
+                    hop_limit : 64,
+                }
+                .emit(&mut reply_ip, &caps);
+                let mut reply_udp = UdpPacket::new_unchecked(reply_ip.payload_mut());
+                UdpRepr { src_port : udp.dst_port(), dst_port : udp.src_port() }.emit(
+                    &mut reply_udp,
+                    &IpAddress::Ipv4(ip.dst_addr()),
+                    &IpAddress::Ipv4(ip.src_addr()),
+                    payload.len(),
+                    |buffer| buffer.copy_from_slice(payload),
+                    &caps,
+                );
+                Some(frame)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn udp_exchange_resolves_the_neighbor_first() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let mut frame = vec![0; 1514];
+            while let Ok(len) = server.recv(&mut frame) {
+                if let Some(reply) = echo_reply(&frame[..len]) {
+                    server.send(&reply).unwrap();
+                }
+            }
+        });
+
+        let mut stack = Stack::new(PairDevice(client), EthernetAddress([0x02, 0, 0, 0, 0, 0x04]));
+        stack.configure(Ipv4Cidr::new(LEASED_IP, 24).into(), None);
+        let server = IpEndpoint::new(IpAddress::Ipv4(SERVER_IP), 53);
+        let response = stack.udp_exchange(server, b"ping", &|_| true, std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(response, b"ping");
+    }
+
+    const ROUTER_IP : Ipv6Address = Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    const PREFIX : Ipv6Address = Ipv6Address([0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    /// Answers a router solicitation with an advertisement of PREFIX.
+    fn router_reply(frame : &[u8]) -> Option<Vec<u8>> {
+        let eth = EthernetFrame::new_checked(frame).ok()?;
+        let ip = Ipv6Packet::new_checked(eth.payload()).ok()?;
+        let icmp = Icmpv6Packet::new_checked(ip.payload()).ok()?;
+        let caps = ChecksumCapabilities::default();
+        match Icmpv6Repr::parse(&ip.src_addr().into(), &ip.dst_addr().into(), &icmp, &caps).ok()? {
+            Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { .. }) if ip.hop_limit() == 255 => {}
+            _ => return None,
+        }
+        let advert = Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            hop_limit : 64,
+            flags : NdiscRouterFlags::empty(),
+            router_lifetime : Duration::from_secs(1800),
+            reachable_time : Duration::ZERO,
+            retrans_time : Duration::ZERO,
+            lladdr : Some(SERVER_MAC.into()),
+            mtu : None,
+            prefix_info : Some(NdiscPrefixInformation {
+                prefix_len : 64,
+                flags : NdiscPrefixInfoFlags::ON_LINK | NdiscPrefixInfoFlags::ADDRCONF,
+                valid_lifetime : Duration::from_secs(86400),
+                preferred_lifetime : Duration::from_secs(14400),
+                prefix : PREFIX,
+            }),
+        });
+        let dst_addr = Ipv6Address::LINK_LOCAL_ALL_NODES;
+        let mut frame = vec![0; 14 + 40 + advert.buffer_len()];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame);
+        EthernetRepr {
+            src_addr : SERVER_MAC,
+            dst_addr : EthernetAddress([0x33, 0x33, 0, 0, 0, 1]),
+            ethertype : EthernetProtocol::Ipv6,
+        }
+        .emit(&mut eth);
+        let mut ip = Ipv6Packet::new_unchecked(eth.payload_mut());
+        Ipv6Repr {
+            src_addr : ROUTER_IP,
+            dst_addr,
+            next_header : IpProtocol::Icmpv6,
+            payload_len : advert.buffer_len(),
+            hop_limit : 255,
+        }
+        .emit(&mut ip);
+        advert.emit(&ROUTER_IP.into(), &dst_addr.into(), &mut Icmpv6Packet::new_unchecked(ip.payload_mut()), &caps);
+        Some(frame)
+    }
+
+    #[test]
+    fn slaac_takes_the_advertised_prefix() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let mut frame = vec![0; 1514];
+            while let Ok(len) = server.recv(&mut frame) {
+                if let Some(reply) = router_reply(&frame[..len]) {
+                    server.send(&reply).unwrap();
+                }
+            }
+        });
+
+        let mac = EthernetAddress([0x02, 0, 0, 0, 0, 0x05]);
+        let mut stack = Stack::new(PairDevice(client), mac);
+        assert!(!stack.reaches("2001:db8:1::1".parse().unwrap()));
+        stack.autoconfigure(false, true, std::time::Duration::from_secs(5)).unwrap();
+        let address : Ipv6Address = "2001:db8:1::ff:fe00:5".parse().unwrap();
+        assert!(stack.iface.has_ip_addr(address));
+        assert_eq!(stack.iface.ipv6_addr(), Some(address));
+        assert!(stack.iface.has_ip_addr(link_local(mac)));
+        assert!(stack.reaches("2001:db8:1::1".parse().unwrap()));
+        assert!(!stack.reaches("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn interface_ids_flip_the_universal_bit() {
+        let mac = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(link_local(mac).to_string(), "fe80::5054:ff:fe12:3456");
+        let solicitation = router_solicitation(mac);
+        assert!(router_advertisement(&solicitation).is_none());
+    }
+
+    #[test]
+    fn gives_up_without_a_server() {
+        let (client, _server) = UnixDatagram::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut stack = Stack::new(PairDevice(client), EthernetAddress([0x02, 0, 0, 0, 0, 0x03]));
+        let e = stack.autoconfigure(true, true, std::time::Duration::from_millis(200)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use tun_tap_mac::Iface;
+
+const MTU : usize = 1514;
+
+/// Lets smoltcp drive a `tun_tap_mac` TAP device. The device must be opened
+/// without packet info so that every read and write is a bare Ethernet frame.
+pub struct TapDevice {
+    iface : Iface,
+}
+
+impl TapDevice {
+    pub fn new(iface : Iface) -> io::Result<Self> {
+        let fd = iface.as_raw_fd();
+        // smoltcp keeps asking for frames until there are none left, so reads must not block
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(TapDevice { iface })
+    }
+}
+
+impl AsRawFd for TapDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+pub struct RxToken(Vec<u8>);
+
+pub struct TxToken<'a>(&'a Iface);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f : F) -> R
+        where F : FnOnce(&mut [u8]) -> R
+    {
+        f(&mut self.0)
+    }
+}
+
+impl<'a> phy::TxToken for TxToken<'a> {
+    fn consume<R, F>(self, len : usize, f : F) -> R
+        where F : FnOnce(&mut [u8]) -> R
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        if let Err(e) = self.0.send(&frame) {
+            eprintln!("error: unable to send frame: {}", e);
+        }
+        result
+    }
+}
+
+impl Device for TapDevice {
+    type RxToken<'a> = RxToken where Self : 'a;
+    type TxToken<'a> = TxToken<'a> where Self : 'a;
+
+    fn receive(&mut self, _timestamp : Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let mut frame = vec![0; MTU];
+        match self.iface.recv(&mut frame) {
+            Ok(len) => {
+                frame.truncate(len);
+                Some((RxToken(frame), TxToken(&self.iface)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                eprintln!("error: unable to receive frame: {}", e);
+                None
+            }
+        }
+    }
+
+    fn transmit(&mut self, _timestamp : Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&self.iface))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+}
@@ -0,0 +1,134 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName};
+use smoltcp::socket::tcp;
+
+use crate::error::Error;
+
+/// Client settings trusting the Mozilla root certificates, or only the PEM
+/// certificates in `ca_file` when one is given.
+pub fn config<P : AsRef<Path>>(ca_file : Option<P>) -> Result<Arc<ClientConfig>, Error> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let context = format!("unable to read {}", path.as_ref().display());
+            let mut reader = BufReader::new(File::open(path).map_err(|e| Error::io(context.clone(), e))?);
+            for cert in rustls_pemfile::certs(&mut reader).map_err(|e| Error::io(context.clone(), e))? {
+                roots.add(&Certificate(cert)).map_err(Error::tls)?;
+            }
+        }
+        None => {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Starts a session for `host`, which is sent as SNI and checked against the
+/// server's certificate.
+pub fn connect(config : &Arc<ClientConfig>, host : &str) -> Result<ClientConnection, Error> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host).map_err(|e| Error::parse(format!("{:?} as a TLS server name", host), e))?;
+    ClientConnection::new(config.clone(), name).map_err(Error::tls)
+}
+
+/// Lets rustls read and write TLS records straight from a smoltcp socket's
+/// buffers. Running out of room or data shows up as `WouldBlock`.
+pub struct SocketIo<'a, 'b>(pub &'a mut tcp::Socket<'b>);
+
+impl<'a, 'b> Read for SocketIo<'a, 'b> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        if !self.0.may_recv() {
+            return Ok(0);
+        }
+        match self.0.recv_slice(buf) {
+            Ok(0) => Err(io::ErrorKind::WouldBlock.into()),
+            Ok(amt) => Ok(amt),
+            Err(e) => Err(io::Error::other(format!("{:?}", e))),
+        }
+    }
+}
+
+impl<'a, 'b> Write for SocketIo<'a, 'b> {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        match self.0.send_slice(buf) {
+            Ok(0) => Err(io::ErrorKind::WouldBlock.into()),
+            Ok(amt) => Ok(amt),
+            Err(e) => Err(io::Error::other(format!("{:?}", e))),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+    use std::error::Error as StdError;
+
+    /// Runs a handshake against a server with a self-signed certificate for
+    /// `localhost`, passing the records through memory.
+    fn handshake(ca_file : bool, host : &str) -> Result<(), Box<dyn StdError>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let path = std::env::temp_dir().join(format!("mget-ca-{}-{}.pem", std::process::id(), host));
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+        let client_config = config(if ca_file { Some(&path) } else { None });
+        std::fs::remove_file(&path).unwrap();
+        let mut client = connect(&client_config?, host)?;
+
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )?;
+        let mut server = ServerConnection::new(Arc::new(server_config))?;
+        while client.is_handshaking() {
+            let mut records = Vec::new();
+            client.write_tls(&mut records)?;
+            server.read_tls(&mut records.as_slice())?;
+            server.process_new_packets()?;
+            records.clear();
+            server.write_tls(&mut records)?;
+            client.read_tls(&mut records.as_slice())?;
+            client.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trusts_the_given_ca_file() {
+        assert!(handshake(true, "localhost").is_ok());
+    }
+
+    #[test]
+    fn checks_the_server_name() {
+        assert!(handshake(true, "example.com").is_err());
+        assert!(connect(&config(None::<&str>).unwrap(), "not a host name").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_issuers_by_default() {
+        assert!(handshake(false, "localhost").is_err());
+    }
+}
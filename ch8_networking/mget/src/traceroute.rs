@@ -0,0 +1,81 @@
+use clap::{App, Arg, ArgMatches};
+use libmget::error::Error;
+use libmget::icmp::{self, Pinger, Reply};
+use libmget::{cli, phy, stack};
+
+fn main() {
+    let app = App::new("traceroute")
+        .about("Finds the routers on the way to a host with ICMP echo requests of growing TTL")
+        .arg(Arg::with_name("host").required(true))
+        .args(&cli::interface_args())
+        .arg(Arg::with_name("max-hops")
+            .short("m")
+            .long("max-hops")
+            .takes_value(true)
+            .default_value("30")
+            .help("Largest TTL to probe with"))
+        .arg(Arg::with_name("queries")
+            .short("q")
+            .long("queries")
+            .takes_value(true)
+            .default_value("3")
+            .help("Probes to send per hop"))
+        .arg(Arg::with_name("wait")
+            .short("w")
+            .long("wait")
+            .takes_value(true)
+            .value_name("seconds")
+            .default_value("1")
+            .help("Time to wait for each probe's answer"))
+        .get_matches();
+
+    if let Err(e) = cli::with_device(&app, Traceroute) {
+        e.exit();
+    }
+}
+
+struct Traceroute;
+
+impl cli::Run for Traceroute {
+    fn run<D : phy::Phy>(self, app : &ArgMatches, device : D) -> Result<(), Error> {
+        run(app, device)
+    }
+}
+
+fn run<D : phy::Phy>(app : &ArgMatches, device : D) -> Result<(), Error> {
+    let host = app.value_of("host").unwrap();
+    let max_hops : u8 = cli::value(app, "max-hops")?;
+    if max_hops == 0 {
+        return Err(Error::Usage("<max-hops> must be between 1 and 255".to_string()));
+    }
+    let queries : usize = cli::value(app, "queries")?;
+    if queries == 0 {
+        return Err(Error::Usage("<queries> must be a positive number".to_string()));
+    }
+    let wait = cli::seconds(app, "wait")?;
+
+    let mac = cli::mac_address(app)?.into();
+    let mut stack = stack::Stack::new(device, mac);
+    cli::configure(app, &mut stack, true, false)?;
+    let dst = cli::ipv4_destination(app, &stack, host)?;
+    let mut pinger = Pinger::new(&mut stack)?;
+
+    println!(
+        "traceroute to {} ({}), {} hops max, {} byte packets",
+        host, dst, max_hops, icmp::packet_len(icmp::DEFAULT_SIZE),
+    );
+    let mut reached = false;
+    for ttl in 1..=max_hops {
+        let hop = pinger.hop(&mut stack, dst, ttl, queries, wait)?;
+        println!("{}", hop);
+        if hop.is_last() {
+            reached = hop.answers.iter().flatten().any(|answer| matches!(answer.reply, Reply::Echo { .. }));
+            break;
+        }
+    }
+    pinger.close(&mut stack);
+    if !reached {
+        return Err(Error::Unreachable(format!("{} within {} hops", dst, max_hops)));
+    }
+    Ok(())
+}
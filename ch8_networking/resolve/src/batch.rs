@@ -0,0 +1,311 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+use trust_dns::op::Message;
+
+use crate::transport::{encode, EDNS_PAYLOAD};
+
+const RETRIES : usize = 2;
+const POLL_INTERVAL : Duration = Duration::from_millis(20);
+/// Well below the 65536 message IDs, so that a free one is always close by.
+pub const MAX_CONCURRENCY : usize = 1024;
+
+/// Keeps up to `concurrency` queries in flight over a single UDP socket and
+/// pairs the responses with their queries by message ID.
+pub struct Pipeline {
+    server : SocketAddr,
+    concurrency : usize,
+    timeout : Duration,
+}
+
+struct InFlight {
+    index : usize,
+    sent_at : Instant,
+    attempts : usize,
+}
+
+impl Pipeline {
+    pub fn new(server : SocketAddr, concurrency : usize, timeout : Duration) -> Self {
+        Pipeline { server, concurrency : concurrency.clamp(1, MAX_CONCURRENCY), timeout }
+    }
+
+    /// Answers come back in the order of `requests`. The IDs of the requests
+    /// are replaced so that no two queries in flight share one.
+    pub fn run(&self, mut requests : Vec<Message>) -> io::Result<Vec<Result<Message, String>>> {
+        let bind = if self.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let mut results : Vec<Option<Result<Message, String>>> = requests.iter().map(|_| None).collect();
+        let mut pending : VecDeque<(usize, usize)> = (0..requests.len()).map(|index| (index, 0)).collect();
+        let mut in_flight : HashMap<u16, InFlight> = HashMap::new();
+        let mut response_buffer = vec![0; EDNS_PAYLOAD as usize];
+
+        while !pending.is_empty() || !in_flight.is_empty() {
+            while in_flight.len() < self.concurrency {
+                let (index, attempts) = match pending.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let id = free_id(&in_flight).expect("fewer queries in flight than IDs");
+                requests[index].set_id(id);
+                let request_bytes = match encode(&requests[index]) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        results[index] = Some(Err(e.to_string()));
+                        continue;
+                    }
+                };
+                if let Err(e) = socket.send_to(&request_bytes, self.server) {
+                    results[index] = Some(Err(e.to_string()));
+                    continue;
+                }
+                in_flight.insert(id, InFlight { index, sent_at : Instant::now(), attempts : attempts + 1 });
+            }
+
+            match socket.recv_from(&mut response_buffer) {
+                Ok((amt, remote)) if remote == self.server => {
+                    if let Ok(response) = Message::from_vec(&response_buffer[..amt]) {
+                        let matches = in_flight
+                            .get(&response.id())
+                            .map(|query| requests[query.index].queries() == response.queries())
+                            .unwrap_or(false);
+                        if matches {
+                            let query = in_flight.remove(&response.id()).unwrap();
+                            results[query.index] = Some(Ok(response));
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+
+            let expired : Vec<u16> = in_flight
+                .iter()
+                .filter(|(_, query)| query.sent_at.elapsed() >= self.timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in expired {
+                let query = in_flight.remove(&id).unwrap();
+                if query.attempts <= RETRIES {
+                    pending.push_back((query.index, query.attempts));
+                } else {
+                    results[query.index] = Some(Err("timeout reached".to_string()));
+                }
+            }
+        }
+        Ok(results.into_iter().map(|result| result.expect("every query is answered or failed")).collect())
+    }
+}
+
+/// Looks for an ID not in flight from a random one onwards, so that IDs
+/// stay hard to guess, and gives up after trying all of them.
+fn free_id(in_flight : &HashMap<u16, InFlight>) -> Option<u16> {
+    let start = rand::random::<u16>();
+    (0..=u16::MAX)
+        .map(|i| start.wrapping_add(i))
+        .find(|id| !in_flight.contains_key(id))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Plain,
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown output format: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub name : String,
+    pub answers : Vec<String>,
+    pub error : Option<String>,
+}
+
+pub fn write<W : Write>(out : &mut W, format : Format, outcomes : &[Outcome]) -> io::Result<()> {
+    match format {
+        Format::Plain => {
+            for outcome in outcomes {
+                match &outcome.error {
+                    Some(error) => writeln!(out, "{}\terror: {}", outcome.name, error)?,
+                    None => {
+                        for answer in &outcome.answers {
+                            writeln!(out, "{}\t{}", outcome.name, answer)?;
+                        }
+                    }
+                }
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "name,answer,error")?;
+            for outcome in outcomes {
+                match &outcome.error {
+                    Some(error) => writeln!(out, "{},,{}", csv_field(&outcome.name), csv_field(error))?,
+                    None => {
+                        for answer in &outcome.answers {
+                            writeln!(out, "{},{},", csv_field(&outcome.name), csv_field(answer))?;
+                        }
+                    }
+                }
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, outcomes)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(field : &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use trust_dns::op::{MessageType, OpCode, Query};
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    fn request(name : &str) -> Message {
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        msg
+    }
+
+    /// Answers `host<n>.test.` with 10.0.0.<n>, replying to every four
+    /// queries in reverse order of arrival. The first query for `host0.test.`
+    /// is dropped to force a retry.
+    fn stand_in() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = vec![0; 512];
+            let mut dropped = false;
+            let mut held = Vec::new();
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((amt, client)) => {
+                        let request = Message::from_vec(&buffer[..amt]).unwrap();
+                        if !dropped && request.queries()[0].name().to_string() == "host0.test." {
+                            dropped = true;
+                            continue;
+                        }
+                        held.push((request, client));
+                        if held.len() < 4 {
+                            continue;
+                        }
+                    }
+                    Err(_) if held.is_empty() => continue,
+                    Err(_) => {}
+                }
+                for (request, client) in held.drain(..).rev() {
+                    let name = request.queries()[0].name().clone();
+                    let n : u8 = name.to_string()[4..].split('.').next().unwrap().parse().unwrap();
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .add_queries(request.queries().to_vec())
+                        .add_answer(Record::from_rdata(name, 60, RData::A(Ipv4Addr::new(10, 0, 0, n))));
+                    socket.send_to(&encode(&response).unwrap(), client).unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn matches_out_of_order_responses() {
+        let names : Vec<String> = (0..20).map(|n| format!("host{}.test.", n)).collect();
+        let server = stand_in();
+        let pipeline = Pipeline::new(server, 8, Duration::from_millis(300));
+        let results = pipeline.run(names.iter().map(|name| request(name)).collect()).unwrap();
+        for (n, result) in results.iter().enumerate() {
+            let response = result.as_ref().unwrap();
+            assert_eq!(response.queries()[0].name().to_string(), names[n]);
+            let ip = response.answers()[0].rdata().to_ip_addr().unwrap();
+            assert_eq!(ip.to_string(), format!("10.0.0.{}", n));
+        }
+    }
+
+    #[test]
+    fn concurrency_is_clamped() {
+        let server = "127.0.0.1:53".parse().unwrap();
+        assert_eq!(Pipeline::new(server, 70000, Duration::from_millis(30)).concurrency, MAX_CONCURRENCY);
+        assert_eq!(Pipeline::new(server, 0, Duration::from_millis(30)).concurrency, 1);
+    }
+
+    #[test]
+    fn ids_run_out_instead_of_spinning() {
+        let in_flight = |ids : std::ops::RangeInclusive<u16>| ids
+            .map(|id| (id, InFlight { index : 0, sent_at : Instant::now(), attempts : 0 }))
+            .collect::<HashMap<u16, InFlight>>();
+        assert_eq!(free_id(&in_flight(0..=u16::MAX)), None);
+        assert_eq!(free_id(&in_flight(1..=u16::MAX)), Some(0));
+    }
+
+    #[test]
+    fn unanswered_queries_time_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let pipeline = Pipeline::new(silent.local_addr().unwrap(), 4, Duration::from_millis(30));
+        let results = pipeline.run(vec![request("a.test."), request("b.test.")]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.is_err()));
+    }
+
+    #[test]
+    fn failed_sends_fail_only_their_query() {
+        // Nothing can be sent to port 0, so every send fails at once.
+        let pipeline = Pipeline::new("127.0.0.1:0".parse().unwrap(), 4, Duration::from_millis(30));
+        let results = pipeline.run(vec![request("a.test."), request("b.test.")]).unwrap();
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            assert_ne!(result.as_ref().unwrap_err(), "timeout reached");
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let outcomes = vec![
+            Outcome { name : "a.test".to_string(), answers : vec!["10.0.0.1".to_string()], error : None },
+            Outcome { name : "b,test".to_string(), answers : vec![], error : Some("timeout reached".to_string()) },
+        ];
+        let render = |format| {
+            let mut out = Vec::new();
+            write(&mut out, format, &outcomes).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(render(Format::Plain), "a.test\t10.0.0.1\nb,test\terror: timeout reached\n");
+        assert_eq!(render(Format::Csv), "name,answer,error\na.test,10.0.0.1,\n\"b,test\",,timeout reached\n");
+        let json : serde_json::Value = serde_json::from_str(&render(Format::Json)).unwrap();
+        assert_eq!(json[0]["answers"][0], "10.0.0.1");
+        assert_eq!(json[1]["error"], "timeout reached");
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use data_encoding::{BASE32_DNSSEC, BASE64, HEXUPPER};
+use trust_dns::op::{Message, MessageType};
+use trust_dns::rr::rdata::DNSSECRData;
+use trust_dns::rr::{RData, Record};
+use trust_dns::serialize::binary::*;
+
+/// Where a rendered message came from, for the footer.
+pub struct Exchange<'a> {
+    pub server : &'a str,
+    pub elapsed : Duration,
+}
+
+/// Renders `message` the way dig prints it: header, flags, the EDNS
+/// pseudosection and every section with TTLs, followed by timing details.
+pub fn render(message : &Message, size : usize, exchange : Option<Exchange>) -> String {
+    let mut out = String::new();
+    let edns_count = if message.edns().is_some() { 1 } else { 0 };
+    let _ = writeln!(
+        out,
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+        format!("{:?}", message.op_code()).to_uppercase(),
+        rcode_mnemonic(message),
+        message.id()
+    );
+    let _ = writeln!(
+        out,
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        flags(message).join(" "),
+        message.queries().len(),
+        message.answers().len(),
+        message.name_servers().len(),
+        message.additionals().len() + edns_count
+    );
+
+    if let Some(edns) = message.edns() {
+        let _ = writeln!(out, "\n;; OPT PSEUDOSECTION:");
+        let _ = writeln!(
+            out,
+            "; EDNS: version: {}, flags:{}; udp: {}",
+            edns.version(),
+            if edns.dnssec_ok() { " do" } else { "" },
+            edns.max_payload()
+        );
+    }
+
+    let _ = writeln!(out, "\n;; QUESTION SECTION:");
+    for query in message.queries() {
+        let _ = writeln!(out, ";{}\t\t\t{}\t{}", query.name(), query.query_class(), query.query_type());
+    }
+    for (title, records) in [
+        ("ANSWER", message.answers()),
+        ("AUTHORITY", message.name_servers()),
+        ("ADDITIONAL", message.additionals()),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "\n;; {} SECTION:", title);
+        for record in records {
+            let _ = writeln!(out, "{}", record_line(record));
+        }
+    }
+
+    let _ = writeln!(out);
+    if let Some(exchange) = exchange {
+        let _ = writeln!(out, ";; Query time: {} msec", exchange.elapsed.as_millis());
+        let _ = writeln!(out, ";; SERVER: {}", exchange.server);
+    }
+    let _ = writeln!(out, ";; MSG SIZE  rcvd: {}", size);
+    out
+}
+
+fn rcode_mnemonic(message : &Message) -> String {
+    let code : u16 = message.response_code().into();
+    match code {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+fn flags(message : &Message) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    let set = [
+        (message.message_type() == MessageType::Response, "qr"),
+        (message.authoritative(), "aa"),
+        (message.truncated(), "tc"),
+        (message.recursion_desired(), "rd"),
+        (message.recursion_available(), "ra"),
+        (message.authentic_data(), "ad"),
+        (message.checking_disabled(), "cd"),
+    ];
+    for (is_set, flag) in set.iter() {
+        if *is_set {
+            flags.push(*flag);
+        }
+    }
+    flags
+}
+
+pub fn record_line(record : &Record) -> String {
+    format!(
+        "{}\t\t{}\t{}\t{}\t{}",
+        record.name(),
+        record.ttl(),
+        record.dns_class(),
+        record.record_type(),
+        rdata_text(record.rdata())
+    )
+}
+
+/// Presentation format of the common record types. Anything else is shown
+/// in the generic `\# <length> <hex>` form of RFC 3597.
+pub fn rdata_text(rdata : &RData) -> String {
+    match rdata {
+        RData::A(ip) => ip.to_string(),
+        RData::AAAA(ip) => ip.to_string(),
+        RData::ANAME(name) | RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => name.to_string(),
+        RData::MX(mx) => format!("{} {}", mx.preference(), mx.exchange()),
+        RData::SRV(srv) => format!("{} {} {} {}", srv.priority(), srv.weight(), srv.port(), srv.target()),
+        RData::SOA(soa) => format!(
+            "{} {} {} {} {} {} {}",
+            soa.mname(),
+            soa.rname(),
+            soa.serial(),
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum()
+        ),
+        RData::TXT(txt) => txt
+            .iter()
+            .map(|data| format!("{:?}", String::from_utf8_lossy(data)))
+            .collect::<Vec<String>>()
+            .join(" "),
+        RData::DNSSEC(DNSSECRData::DNSKEY(key)) => {
+            let flags = (key.zone_key() as u16) << 8 | (key.revoke() as u16) << 7 | key.secure_entry_point() as u16;
+            format!("{} 3 {} {}", flags, u8::from(key.algorithm()), BASE64.encode(key.public_key()))
+        }
+        RData::DNSSEC(DNSSECRData::DS(ds)) => format!(
+            "{} {} {} {}",
+            ds.key_tag(),
+            u8::from(*ds.algorithm()),
+            u8::from(ds.digest_type()),
+            HEXUPPER.encode(ds.digest())
+        ),
+        RData::DNSSEC(DNSSECRData::SIG(sig)) => format!(
+            "{} {} {} {} {} {} {} {} {}",
+            sig.type_covered(),
+            u8::from(sig.algorithm()),
+            sig.num_labels(),
+            sig.original_ttl(),
+            sig.sig_expiration(),
+            sig.sig_inception(),
+            sig.key_tag(),
+            sig.signer_name(),
+            BASE64.encode(sig.sig())
+        ),
+        RData::DNSSEC(DNSSECRData::NSEC(nsec)) => {
+            let types : Vec<String> = nsec.type_bit_maps().iter().map(|t| t.to_string()).collect();
+            format!("{} {}", nsec.next_domain_name(), types.join(" "))
+        }
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => {
+            let types : Vec<String> = nsec3.type_bit_maps().iter().map(|t| t.to_string()).collect();
+            let salt = if nsec3.salt().is_empty() { "-".to_string() } else { HEXUPPER.encode(nsec3.salt()) };
+            format!(
+                "{} {} {} {} {} {}",
+                u8::from(nsec3.hash_algorithm()),
+                nsec3.opt_out() as u8,
+                nsec3.iterations(),
+                salt,
+                BASE32_DNSSEC.encode(nsec3.next_hashed_owner_name()).to_uppercase(),
+                types.join(" ")
+            )
+        }
+        other => {
+            let mut bytes = Vec::new();
+            {
+                let mut encoder = BinEncoder::new(&mut bytes);
+                if other.emit(&mut encoder).is_err() {
+                    return format!("{:?}", other);
+                }
+            }
+            format!("\\# {} {}", bytes.len(), HEXUPPER.encode(&bytes))
+        }
+    }
+}
+
+/// Decodes a captured DNS payload. Captures of DNS over TCP keep the two
+/// byte length in front of the message, which is skipped when present.
+pub fn decode_packet(raw : &[u8]) -> Result<Message, String> {
+    if raw.len() > 2 && u16::from_be_bytes([raw[0], raw[1]]) as usize == raw.len() - 2 {
+        if let Ok(message) = Message::from_vec(&raw[2..]) {
+            return Ok(message);
+        }
+    }
+    Message::from_vec(raw).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::encode;
+    use std::net::Ipv4Addr;
+    use trust_dns::op::{Edns, OpCode, Query, ResponseCode};
+    use trust_dns::rr::rdata::{MX, SOA};
+    use trust_dns::rr::{Name, RecordType};
+
+    fn response() -> Message {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        edns.set_dnssec_ok(true);
+        let mut message = Message::new();
+        message
+            .set_id(4660)
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .set_recursion_available(true)
+            .set_response_code(ResponseCode::NoError)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))))
+            .add_name_server(Record::from_rdata(
+                name.clone(),
+                3600,
+                RData::SOA(SOA::new(
+                    Name::from_ascii("ns.example.com.").unwrap(),
+                    Name::from_ascii("hostmaster.example.com.").unwrap(),
+                    2024010101,
+                    7200,
+                    3600,
+                    1209600,
+                    300,
+                )),
+            ))
+            .add_additional(Record::from_rdata(
+                name.clone(),
+                60,
+                RData::MX(MX::new(10, Name::from_ascii("mail.example.com.").unwrap())),
+            ))
+            .set_edns(edns);
+        message
+    }
+
+    #[test]
+    fn renders_every_section() {
+        let exchange = Exchange { server : "192.0.2.53#53 (udp)", elapsed : Duration::from_millis(12) };
+        let text = render(&response(), 120, Some(exchange));
+        assert!(text.contains(";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660"));
+        assert!(text.contains(";; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 2"));
+        assert!(text.contains("; EDNS: version: 0, flags: do; udp: 1232"));
+        assert!(text.contains(";example.com.\t\t\tIN\tA"));
+        assert!(text.contains("example.com.\t\t300\tIN\tA\t192.0.2.1"));
+        assert!(text.contains("IN\tSOA\tns.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300"));
+        assert!(text.contains("IN\tMX\t10 mail.example.com."));
+        assert!(text.contains(";; Query time: 12 msec"));
+        assert!(text.contains(";; SERVER: 192.0.2.53#53 (udp)"));
+        assert!(text.contains(";; MSG SIZE  rcvd: 120"));
+    }
+
+    #[test]
+    fn decodes_raw_and_tcp_framed_packets() {
+        let raw = encode(&response()).unwrap();
+        assert_eq!(decode_packet(&raw).unwrap().id(), 4660);
+        let mut framed = (raw.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&raw);
+        assert_eq!(decode_packet(&framed).unwrap().answers().len(), 1);
+        assert!(decode_packet(&raw[..5]).is_err());
+    }
+
+    #[test]
+    fn unknown_types_use_generic_form() {
+        let rdata = RData::Unknown { code : 65280, rdata : trust_dns::rr::rdata::NULL::with(vec![0xde, 0xad]) };
+        assert_eq!(rdata_text(&rdata), "\\# 2 DEAD");
+    }
+}
@@ -0,0 +1,889 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use data_encoding::{BASE32_DNSSEC, BASE64, HEXLOWER_PERMISSIVE};
+use ring::{digest, signature};
+use trust_dns::op::Message;
+use trust_dns::rr::dnssec::{Algorithm, DigestType};
+use trust_dns::rr::rdata::sig::emit_pre_sig;
+use trust_dns::rr::rdata::{DNSSECRData, DNSSECRecordType, DNSKEY, DS, NSEC3, SIG};
+use trust_dns::rr::{Name, RData, Record, RecordType};
+use trust_dns::serialize::binary::*;
+
+use crate::error::Error;
+
+// IANA root zone KSK-2017 and KSK-2024, see https://data.iana.org/root-anchors/root-anchors.xml
+const ROOT_ANCHORS : &str = "
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+const DNSKEY_TYPE : RecordType = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+const DS_TYPE : RecordType = RecordType::DNSSEC(DNSSECRecordType::DS);
+const NSEC_TYPE : RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC);
+const NSEC3_TYPE : RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC3);
+const RRSIG_TYPE : RecordType = RecordType::DNSSEC(DNSSECRecordType::RRSIG);
+
+pub trait Lookup {
+    fn lookup(&mut self, name : &Name, record_type : RecordType) -> Result<Message, Error>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Secure,
+    Insecure,
+    Bogus(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Secure => write!(f, "secure"),
+            Status::Insecure => write!(f, "insecure"),
+            Status::Bogus(reason) => write!(f, "bogus ({})", reason),
+        }
+    }
+}
+
+/// What a DS lookup says about the zone cut at a name.
+enum Delegation {
+    Signed(Vec<DS>),
+    Unsigned,
+    NotACut,
+    Bogus(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustAnchor {
+    zone : Name,
+    ds : Vec<DS>,
+    keys : Vec<DNSKEY>,
+}
+
+impl TrustAnchor {
+    pub fn root() -> Self {
+        TrustAnchor::parse(ROOT_ANCHORS).expect("built-in root anchors are valid")
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path : P) -> Result<Self, Error> {
+        let text = fs::read_to_string(path.as_ref())
+            .map_err(|e| Error::io(format!("unable to read {}", path.as_ref().display()), e))?;
+        TrustAnchor::parse(&text)
+    }
+
+    /// Reads DS and DNSKEY lines in zone file presentation format. Every
+    /// record has to belong to the same zone.
+    pub fn parse(text : &str) -> Result<Self, Error> {
+        let mut zone : Option<Name> = None;
+        let mut ds = Vec::new();
+        let mut keys = Vec::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let owner = Name::from_ascii(fields[0])
+                .map_err(|e| Error::parse(format!("{:?} as a trust anchor owner", fields[0]), e.to_string()))?;
+            let owner = canonical(&owner);
+            let rest : Vec<&str> = fields[1..]
+                .iter()
+                .skip_while(|field| field.parse::<u32>().is_ok() || field.eq_ignore_ascii_case("IN"))
+                .cloned()
+                .collect();
+            anchor_record(&rest, &mut ds, &mut keys)
+                .map_err(|e| Error::parse(format!("trust anchor line {:?}", line.trim()), e))?;
+            match &zone {
+                Some(zone) if *zone != owner => {
+                    return Err(Error::Usage(format!("trust anchor mixes zones {} and {}", zone, owner)));
+                }
+                _ => zone = Some(owner),
+            }
+        }
+        let zone = zone.ok_or_else(|| Error::Usage("trust anchor is empty".to_string()))?;
+        Ok(TrustAnchor { zone, ds, keys })
+    }
+}
+
+/// Adds the DS or DNSKEY record of a trust anchor line, given the fields
+/// after its owner, TTL and class.
+fn anchor_record(rest : &[&str], ds : &mut Vec<DS>, keys : &mut Vec<DNSKEY>)
+    -> Result<(), Box<dyn StdError + Send + Sync>>
+{
+    match rest {
+        [kind, tag, algorithm, digest_type, digest @ ..] if kind.eq_ignore_ascii_case("DS") => {
+            ds.push(DS::new(
+                tag.parse()?,
+                Algorithm::from_u8(algorithm.parse()?).map_err(|e| e.to_string())?,
+                DigestType::from_u8(digest_type.parse()?).map_err(|e| e.to_string())?,
+                HEXLOWER_PERMISSIVE.decode(digest.concat().as_bytes())?,
+            ));
+        }
+        [kind, flags, _protocol, algorithm, key @ ..] if kind.eq_ignore_ascii_case("DNSKEY") => {
+            let flags : u16 = flags.parse()?;
+            keys.push(DNSKEY::new(
+                flags & 0x0100 != 0,
+                flags & 0x0001 != 0,
+                flags & 0x0080 != 0,
+                Algorithm::from_u8(algorithm.parse()?).map_err(|e| e.to_string())?,
+                BASE64.decode(key.concat().as_bytes())?,
+            ));
+        }
+        _ => return Err("not a DS or DNSKEY record".into()),
+    }
+    Ok(())
+}
+
+/// Validates answers by walking the chain of DS and DNSKEY records from the
+/// signer of each RRset up to the configured trust anchor.
+pub struct Validator<L : Lookup> {
+    lookup : L,
+    anchor : TrustAnchor,
+    keys : HashMap<Name, (Status, Vec<DNSKEY>)>,
+    now : u32,
+}
+
+impl<L : Lookup> Validator<L> {
+    pub fn new(lookup : L, anchor : TrustAnchor) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or(0);
+        Validator { lookup, anchor, keys : HashMap::new(), now }
+    }
+
+    /// Returns every answer record, other than the signatures themselves,
+    /// paired with the status of the RRset it belongs to.
+    pub fn validate(&mut self, response : &Message) -> Result<Vec<(Record, Status)>, Error> {
+        let mut results = Vec::new();
+        for (rrset, sigs) in rrsets(response.answers()) {
+            let status = self.verify_rrset(&rrset, &sigs)?;
+            for record in rrset {
+                results.push((record.clone(), status.clone()));
+            }
+        }
+        Ok(results)
+    }
+
+    fn verify_rrset(&mut self, rrset : &[&Record], sigs : &[&SIG]) -> Result<Status, Error> {
+        let owner = rrset[0].name();
+        if sigs.is_empty() {
+            return self.unsigned(owner);
+        }
+        let mut reason = String::from("no signature could be verified");
+        for sig in sigs {
+            if !sig.signer_name().zone_of(owner) {
+                reason = format!("signer {} is not an ancestor of {}", sig.signer_name(), owner);
+                continue;
+            }
+            if !self.in_validity_period(sig) {
+                reason = format!("signature by {} is outside its validity period", sig.signer_name());
+                continue;
+            }
+            let (status, keys) = self.zone_keys(sig.signer_name())?;
+            if status != Status::Secure {
+                return Ok(status);
+            }
+            for key in keys.iter().filter(|key| key_tag(key) == sig.key_tag()) {
+                match verify_signature(key, sig, rrset) {
+                    Ok(()) => return Ok(Status::Secure),
+                    Err(e) => reason = e,
+                }
+            }
+        }
+        Ok(Status::Bogus(reason))
+    }
+
+    /// An RRset without signatures is only acceptable below an insecure
+    /// delegation, so look for one between the anchor and the owner.
+    fn unsigned(&mut self, owner : &Name) -> Result<Status, Error> {
+        if !self.anchor.zone.zone_of(owner) {
+            return Ok(Status::Insecure);
+        }
+        let first = self.anchor.zone.num_labels() as usize + 1;
+        for labels in first..=owner.num_labels() as usize {
+            match self.delegation(&owner.trim_to(labels))? {
+                Delegation::Signed(_) | Delegation::NotACut => continue,
+                Delegation::Unsigned => return Ok(Status::Insecure),
+                Delegation::Bogus(reason) => return Ok(Status::Bogus(reason)),
+            }
+        }
+        Ok(Status::Bogus(format!("{} is in a signed zone but has no RRSIG", owner)))
+    }
+
+    fn zone_keys(&mut self, zone : &Name) -> Result<(Status, Vec<DNSKEY>), Error> {
+        let zone = canonical(zone);
+        if let Some(cached) = self.keys.get(&zone) {
+            return Ok(cached.clone());
+        }
+        // guards against signatures that point back at the zone being validated
+        self.keys.insert(zone.clone(), (Status::Bogus(format!("validation loop at {}", zone)), vec![]));
+        let result = self.fetch_zone_keys(&zone)?;
+        self.keys.insert(zone, result.clone());
+        Ok(result)
+    }
+
+    fn fetch_zone_keys(&mut self, zone : &Name) -> Result<(Status, Vec<DNSKEY>), Error> {
+        let ds = if *zone == self.anchor.zone {
+            self.anchor.ds.clone()
+        } else {
+            match self.delegation(zone)? {
+                Delegation::Signed(ds) => ds,
+                Delegation::Unsigned => return Ok((Status::Insecure, vec![])),
+                Delegation::NotACut => {
+                    return Ok((Status::Bogus(format!("{} is not a zone cut", zone)), vec![]));
+                }
+                Delegation::Bogus(reason) => return Ok((Status::Bogus(reason), vec![])),
+            }
+        };
+        let response = self.lookup.lookup(zone, DNSKEY_TYPE)?;
+        let mut records = Vec::new();
+        let mut sigs = Vec::new();
+        for (rrset, rrsigs) in rrsets(response.answers()) {
+            if rrset[0].name() == zone && rrset[0].record_type() == DNSKEY_TYPE {
+                records = rrset;
+                sigs = rrsigs;
+            }
+        }
+        let keys : Vec<DNSKEY> = records
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::DNSSEC(DNSSECRData::DNSKEY(key)) if key.zone_key() && !key.revoke() => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let entry_points : Vec<&DNSKEY> = keys
+            .iter()
+            .filter(|key| {
+                self.anchor.zone == *zone && self.anchor.keys.contains(key)
+                    || ds.iter().any(|ds| ds_matches(ds, zone, key))
+            })
+            .collect();
+        if entry_points.is_empty() {
+            return Ok((Status::Bogus(format!("no DNSKEY of {} matches its DS set", zone)), vec![]));
+        }
+        for sig in sigs.iter().filter(|sig| sig.signer_name() == zone && self.in_validity_period(sig)) {
+            for key in entry_points.iter().filter(|key| key_tag(key) == sig.key_tag()) {
+                if verify_signature(key, sig, &records).is_ok() {
+                    return Ok((Status::Secure, keys));
+                }
+            }
+        }
+        Ok((Status::Bogus(format!("DNSKEY set of {} is not signed by a trusted key", zone)), vec![]))
+    }
+
+    fn delegation(&mut self, name : &Name) -> Result<Delegation, Error> {
+        let response = self.lookup.lookup(name, DS_TYPE)?;
+        let mut denials = Vec::new();
+        for (rrset, sigs) in rrsets(response.answers()) {
+            if rrset[0].name() != name || rrset[0].record_type() != DS_TYPE {
+                continue;
+            }
+            // the DS set belongs to the parent, so only the parent can sign it
+            let sigs : Vec<&SIG> = sigs.into_iter().filter(|sig| sig.signer_name() != name).collect();
+            let status = if sigs.is_empty() {
+                self.unsigned(&name.base_name())?
+            } else {
+                self.verify_rrset(&rrset, &sigs)?
+            };
+            return Ok(match status {
+                Status::Secure => Delegation::Signed(
+                    rrset
+                        .iter()
+                        .filter_map(|record| match record.rdata() {
+                            RData::DNSSEC(DNSSECRData::DS(ds)) => Some(ds.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                ),
+                Status::Insecure => Delegation::Unsigned,
+                Status::Bogus(reason) => Delegation::Bogus(reason),
+            });
+        }
+        for (rrset, sigs) in rrsets(response.name_servers()) {
+            let record_type = rrset[0].record_type();
+            if record_type != NSEC_TYPE && record_type != NSEC3_TYPE {
+                continue;
+            }
+            match self.verify_rrset(&rrset, &sigs)? {
+                Status::Secure => denials.extend(rrset),
+                Status::Insecure => return Ok(Delegation::Unsigned),
+                Status::Bogus(reason) => return Ok(Delegation::Bogus(reason)),
+            }
+        }
+        if denials.is_empty() {
+            return Ok(Delegation::Bogus(format!("DS for {} was denied without NSEC or NSEC3 proof", name)));
+        }
+        Ok(denial(name, &denials))
+    }
+
+    fn in_validity_period(&self, sig : &SIG) -> bool {
+        // RFC 4034 section 3.1.5 compares the timestamps with serial number arithmetic
+        let since_inception = self.now.wrapping_sub(sig.sig_inception()) as i32;
+        let until_expiration = sig.sig_expiration().wrapping_sub(self.now) as i32;
+        since_inception >= 0 && until_expiration >= 0
+    }
+}
+
+fn canonical(name : &Name) -> Name {
+    let mut name = name.to_lowercase();
+    name.set_fqdn(true);
+    name
+}
+
+fn rrsets(records : &[Record]) -> Vec<(Vec<&Record>, Vec<&SIG>)> {
+    let mut sets : Vec<(Vec<&Record>, Vec<&SIG>)> = Vec::new();
+    for record in records.iter().filter(|record| record.record_type() != RRSIG_TYPE) {
+        match sets
+            .iter_mut()
+            .find(|(set, _)| set[0].name() == record.name() && set[0].record_type() == record.record_type())
+        {
+            Some((set, _)) => set.push(record),
+            None => sets.push((vec![record], vec![])),
+        }
+    }
+    for record in records.iter().filter(|record| record.record_type() == RRSIG_TYPE) {
+        if let RData::DNSSEC(DNSSECRData::SIG(sig)) = record.rdata() {
+            if let Some((_, sigs)) = sets
+                .iter_mut()
+                .find(|(set, _)| set[0].name() == record.name() && set[0].record_type() == sig.type_covered())
+            {
+                sigs.push(sig);
+            }
+        }
+    }
+    sets
+}
+
+/// What the NSEC or NSEC3 records that came with a denied DS lookup prove
+/// about `name`: an unsigned delegation, none at all, or nothing.
+fn denial(name : &Name, denials : &[&Record]) -> Delegation {
+    let name = canonical(name);
+    let mut nsec3s = Vec::new();
+    for record in denials {
+        match record.rdata() {
+            RData::DNSSEC(DNSSECRData::NSEC(nsec)) => {
+                let owner = canonical(record.name());
+                let next = canonical(nsec.next_domain_name());
+                if owner == name {
+                    return cut(nsec.type_bit_maps());
+                }
+                // the parent's NSEC at a cut above the name says nothing about what is below it
+                if owner.zone_of(&name) && is_delegation(nsec.type_bit_maps()) {
+                    continue;
+                }
+                // the last NSEC of a zone points back at the apex
+                if covers(&owner, &name, &next) && (owner < next || next.zone_of(&name)) {
+                    return Delegation::NotACut;
+                }
+            }
+            RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => nsec3s.push((*record, nsec3)),
+            _ => {}
+        }
+    }
+    if nsec3s.is_empty() {
+        return Delegation::Bogus(format!("no NSEC record matches or covers {}", name));
+    }
+    nsec3_denial(&name, &nsec3s)
+}
+
+/// RFC 5155 section 8.6: a matching NSEC3 shows the types at `name`.
+/// Otherwise the closest encloser has to be proven by a matching NSEC3, and
+/// the next closer name by one covering its hash, which with opt-out leaves
+/// room for an unsigned delegation.
+fn nsec3_denial(name : &Name, nsec3s : &[(&Record, &NSEC3)]) -> Delegation {
+    let matching = |name : &Name| nsec3s.iter().find(|(record, nsec3)| {
+        record.name().base_name().zone_of(name)
+            && nsec3_hash(name, nsec3.salt(), nsec3.iterations()).is_some_and(|hashed| nsec3_owner(record) == Some(hashed))
+    });
+    if let Some((_, nsec3)) = matching(name) {
+        return cut(nsec3.type_bit_maps());
+    }
+    let mut next_closer = name.clone();
+    for labels in (0..name.num_labels() as usize).rev() {
+        let encloser = name.trim_to(labels);
+        let (closest, nsec3) = match matching(&encloser) {
+            Some(found) => found,
+            None => {
+                next_closer = encloser;
+                continue;
+            }
+        };
+        if is_delegation(nsec3.type_bit_maps()) {
+            return Delegation::Bogus(format!("NSEC3 of {} is from above the zone cut at {}", name, encloser));
+        }
+        let zone = closest.name().base_name();
+        let covering = nsec3s.iter().find(|(record, nsec3)| {
+            record.name().base_name() == zone
+                && match (nsec3_owner(record), nsec3_hash(&next_closer, nsec3.salt(), nsec3.iterations())) {
+                    (Some(owner), Some(hashed)) => covers(&owner[..], &hashed[..], nsec3.next_hashed_owner_name()),
+                    _ => false,
+                }
+        });
+        return match covering {
+            Some((_, nsec3)) if nsec3.opt_out() => Delegation::Unsigned,
+            Some(_) => Delegation::NotACut,
+            None => Delegation::Bogus(format!("no NSEC3 record covers the next closer name {}", next_closer)),
+        };
+    }
+    Delegation::Bogus(format!("no NSEC3 record proves the closest encloser of {}", name))
+}
+
+/// Whether `item` falls strictly between `owner` and `next`, where a `next`
+/// that isn't after `owner` wraps around to the start.
+fn covers<T : PartialOrd + ?Sized>(owner : &T, item : &T, next : &T) -> bool {
+    if owner < next {
+        owner < item && item < next
+    } else {
+        owner < item || item < next
+    }
+}
+
+/// The hash an NSEC3 record is for, from the first label of its owner.
+fn nsec3_owner(record : &Record) -> Option<Vec<u8>> {
+    let label = record.name().iter().next()?.to_ascii_lowercase();
+    BASE32_DNSSEC.decode(&label).ok()
+}
+
+fn is_delegation(types : &[RecordType]) -> bool {
+    types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA)
+}
+
+// a delegation has NS in the type bitmap, a secure one also has DS
+fn cut(types : &[RecordType]) -> Delegation {
+    if types.contains(&RecordType::NS) && !types.contains(&DS_TYPE) {
+        Delegation::Unsigned
+    } else {
+        Delegation::NotACut
+    }
+}
+
+// RFC 5155 section 5, only SHA-1 is defined
+fn nsec3_hash(name : &Name, salt : &[u8], iterations : u16) -> Option<Vec<u8>> {
+    let mut wire = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut wire);
+        canonical(name).emit_as_canonical(&mut encoder, true).ok()?;
+    }
+    let mut hashed = wire;
+    for _ in 0..=iterations {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(&hashed);
+        context.update(salt);
+        hashed = context.finish().as_ref().to_vec();
+    }
+    Some(hashed)
+}
+
+fn dnskey_rdata(key : &DNSKEY) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut bytes);
+        trust_dns::rr::rdata::dnskey::emit(&mut encoder, key).expect("DNSKEY is always encodable");
+    }
+    bytes
+}
+
+fn key_tag(key : &DNSKEY) -> u16 {
+    DNSKEY::calculate_key_tag_internal(&dnskey_rdata(key))
+}
+
+fn ds_matches(ds : &DS, owner : &Name, key : &DNSKEY) -> bool {
+    if ds.key_tag() != key_tag(key) || *ds.algorithm() != key.algorithm() {
+        return false;
+    }
+    let algorithm = match ds.digest_type() {
+        DigestType::SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DigestType::SHA256 => &digest::SHA256,
+        DigestType::SHA384 => &digest::SHA384,
+        _ => return false,
+    };
+    let mut input = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut input);
+        if canonical(owner).emit_as_canonical(&mut encoder, true).is_err() {
+            return false;
+        }
+    }
+    input.extend(dnskey_rdata(key));
+    digest::digest(algorithm, &input).as_ref() == ds.digest()
+}
+
+/// Builds the data an RRSIG signs, as laid out in RFC 4034 section 3.1.8.1.
+pub fn signed_data(sig : &SIG, rrset : &[&Record]) -> Result<Vec<u8>, String> {
+    let mut owner = canonical(rrset[0].name());
+    if sig.num_labels() < owner.num_labels() {
+        let wildcard = Name::from_labels(vec!["*"]).map_err(|e| e.to_string())?;
+        owner = wildcard.append_name(&owner.trim_to(sig.num_labels() as usize));
+    }
+    let mut rdatas = Vec::with_capacity(rrset.len());
+    for record in rrset {
+        let mut rdata = Vec::new();
+        {
+            let mut encoder = BinEncoder::new(&mut rdata);
+            encoder.set_canonical_names(true);
+            record.rdata().emit(&mut encoder).map_err(|e| e.to_string())?;
+        }
+        rdatas.push(rdata);
+    }
+    rdatas.sort();
+    rdatas.dedup();
+
+    let mut data = Vec::new();
+    {
+        let mut encoder = BinEncoder::new(&mut data);
+        encoder.set_canonical_names(true);
+        emit_pre_sig(
+            &mut encoder,
+            sig.type_covered(),
+            sig.algorithm(),
+            sig.num_labels(),
+            sig.original_ttl(),
+            sig.sig_expiration(),
+            sig.sig_inception(),
+            sig.key_tag(),
+            &canonical(sig.signer_name()),
+        )
+        .map_err(|e| e.to_string())?;
+        for rdata in &rdatas {
+            owner.emit_as_canonical(&mut encoder, true).map_err(|e| e.to_string())?;
+            encoder.emit_u16(rrset[0].record_type().into()).map_err(|e| e.to_string())?;
+            encoder.emit_u16(rrset[0].dns_class().into()).map_err(|e| e.to_string())?;
+            encoder.emit_u32(sig.original_ttl()).map_err(|e| e.to_string())?;
+            encoder.emit_u16(rdata.len() as u16).map_err(|e| e.to_string())?;
+            encoder.emit_vec(rdata).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(data)
+}
+
+fn verify_signature(key : &DNSKEY, sig : &SIG, rrset : &[&Record]) -> Result<(), String> {
+    if key.algorithm() != sig.algorithm() {
+        return Err(format!("key algorithm {} does not match the signature", key.algorithm().as_str()));
+    }
+    let message = signed_data(sig, rrset)?;
+    let public_key = key.public_key();
+    let verified = match key.algorithm() {
+        Algorithm::RSASHA256 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, public_key, &message, sig.sig()),
+        Algorithm::RSASHA512 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY, public_key, &message, sig.sig()),
+        Algorithm::ECDSAP256SHA256 => verify_ecdsa(&signature::ECDSA_P256_SHA256_FIXED, public_key, &message, sig.sig()),
+        Algorithm::ECDSAP384SHA384 => verify_ecdsa(&signature::ECDSA_P384_SHA384_FIXED, public_key, &message, sig.sig()),
+        Algorithm::ED25519 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&message, sig.sig())
+            .is_ok(),
+        other => return Err(format!("unsupported algorithm {}", other.as_str())),
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(format!("signature by {} (key tag {}) does not verify", sig.signer_name(), sig.key_tag()))
+    }
+}
+
+// RFC 3110 section 2: exponent length, exponent, modulus
+fn verify_rsa(params : &'static signature::RsaParameters, key : &[u8], message : &[u8], sig : &[u8]) -> bool {
+    let (exponent_len, rest) = match key {
+        [0, high, low, rest @ ..] => ((*high as usize) << 8 | *low as usize, rest),
+        [len, rest @ ..] => (*len as usize, rest),
+        [] => return false,
+    };
+    if rest.len() <= exponent_len {
+        return false;
+    }
+    let (e, n) = rest.split_at(exponent_len);
+    signature::RsaPublicKeyComponents { n, e }.verify(params, message, sig).is_ok()
+}
+
+// DNSKEYs carry the bare x and y coordinates, ring expects an uncompressed SEC1 point
+fn verify_ecdsa(algorithm : &'static signature::EcdsaVerificationAlgorithm, key : &[u8], message : &[u8], sig : &[u8]) -> bool {
+    let mut point = Vec::with_capacity(key.len() + 1);
+    point.push(0x04);
+    point.extend_from_slice(key);
+    signature::UnparsedPublicKey::new(algorithm, point).verify(message, sig).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use trust_dns::rr::dnssec::Nsec3HashAlgorithm;
+    use trust_dns::rr::rdata::NSEC;
+    use std::net::Ipv4Addr;
+    use trust_dns::op::ResponseCode;
+
+    const TTL : u32 = 3600;
+
+    struct Zone {
+        name : Name,
+        key : Ed25519KeyPair,
+    }
+
+    impl Zone {
+        fn new(name : &str, seed : u8) -> Self {
+            Zone {
+                name : Name::from_ascii(name).unwrap(),
+                key : Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap(),
+            }
+        }
+
+        fn dnskey(&self) -> DNSKEY {
+            DNSKEY::new(true, true, false, Algorithm::ED25519, self.key.public_key().as_ref().to_vec())
+        }
+
+        fn ds(&self) -> DS {
+            let mut input = Vec::new();
+            {
+                let mut encoder = BinEncoder::new(&mut input);
+                self.name.emit_as_canonical(&mut encoder, true).unwrap();
+            }
+            input.extend(dnskey_rdata(&self.dnskey()));
+            let digest = digest::digest(&digest::SHA256, &input).as_ref().to_vec();
+            DS::new(key_tag(&self.dnskey()), Algorithm::ED25519, DigestType::SHA256, digest)
+        }
+
+        fn sign(&self, rrset : &[Record]) -> Record {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+            let owner = rrset[0].name().clone();
+            let unsigned = SIG::new(
+                rrset[0].record_type(),
+                Algorithm::ED25519,
+                owner.num_labels(),
+                TTL,
+                now + TTL,
+                now - TTL,
+                key_tag(&self.dnskey()),
+                self.name.clone(),
+                vec![],
+            );
+            let refs : Vec<&Record> = rrset.iter().collect();
+            let signature = self.key.sign(&signed_data(&unsigned, &refs).unwrap());
+            let sig = unsigned.set_sig(signature.as_ref().to_vec());
+            let mut record = Record::from_rdata(owner, TTL, RData::DNSSEC(DNSSECRData::SIG(sig)));
+            record.set_rr_type(RRSIG_TYPE);
+            record
+        }
+    }
+
+    /// A signed local zone fixture: the root delegates to a signed
+    /// `example.` and to an unsigned `unsigned.`. `example.` delegates to a
+    /// signed `secure.example.` and, proven with opt-out NSEC3, to an
+    /// unsigned `optout.example.`.
+    struct Fixture {
+        answers : HashMap<(Name, RecordType), Message>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let root = Zone::new(".", 1);
+            let example = Zone::new("example.", 2);
+            let secure = Zone::new("secure.example.", 3);
+            let mut fixture = Fixture { answers : HashMap::new() };
+
+            fixture.signed_answer(&root, vec![record(".", root.dnskey().into())]);
+            fixture.signed_answer(&root, vec![record("example.", RData::DNSSEC(DNSSECRData::DS(example.ds())))]);
+            fixture.signed_answer(&example, vec![record("example.", example.dnskey().into())]);
+            fixture.signed_answer(&example, vec![record("www.example.", RData::A(Ipv4Addr::new(192, 0, 2, 1)))]);
+            fixture.unsigned_answer(vec![record("nosig.example.", RData::A(Ipv4Addr::new(192, 0, 2, 2)))]);
+            fixture.unsigned_answer(vec![record("www.unsigned.", RData::A(Ipv4Addr::new(192, 0, 2, 3)))]);
+            fixture.signed_answer(&example, vec![record("secure.example.", RData::DNSSEC(DNSSECRData::DS(secure.ds())))]);
+            fixture.signed_answer(&secure, vec![record("secure.example.", secure.dnskey().into())]);
+            fixture.signed_answer(&secure, vec![record("www.secure.example.", RData::A(Ipv4Addr::new(192, 0, 2, 4)))]);
+            fixture.unsigned_answer(vec![record("www.optout.example.", RData::A(Ipv4Addr::new(192, 0, 2, 5)))]);
+
+            fixture.denial(&root, "unsigned.", ".", vec![RecordType::NS, NSEC_TYPE, RRSIG_TYPE]);
+            fixture.denial(&example, "nosig.example.", "www.example.", vec![RecordType::A, NSEC_TYPE, RRSIG_TYPE]);
+            // a real zone would have more NSEC3 records in between
+            fixture.nsec3_denial(&example, "optout.example.", vec![
+                apex_nsec3(&example),
+                nsec3(&example, &[0; 20], vec![0xff; 20], true, vec![RecordType::NS]),
+            ]);
+            fixture
+        }
+
+        fn signed_answer(&mut self, zone : &Zone, rrset : Vec<Record>) {
+            let sig = zone.sign(&rrset);
+            let mut message = Message::new();
+            message.add_answers(rrset.clone()).add_answer(sig);
+            self.answers.insert((rrset[0].name().clone(), rrset[0].record_type()), message);
+        }
+
+        fn unsigned_answer(&mut self, rrset : Vec<Record>) {
+            let mut message = Message::new();
+            message.add_answers(rrset.clone());
+            self.answers.insert((rrset[0].name().clone(), rrset[0].record_type()), message);
+        }
+
+        fn denial(&mut self, zone : &Zone, name : &str, next : &str, types : Vec<RecordType>) {
+            let nsec = NSEC::new(Name::from_ascii(next).unwrap(), types);
+            let rrset = vec![record(name, RData::DNSSEC(DNSSECRData::NSEC(nsec)))];
+            let sig = zone.sign(&rrset);
+            let mut message = Message::new();
+            message.add_name_servers(rrset).add_name_server(sig);
+            self.answers.insert((Name::from_ascii(name).unwrap(), DS_TYPE), message);
+        }
+
+        fn nsec3_denial(&mut self, zone : &Zone, name : &str, records : Vec<Record>) {
+            let mut message = Message::new();
+            for record in records {
+                let sig = zone.sign(std::slice::from_ref(&record));
+                message.add_name_server(record).add_name_server(sig);
+            }
+            self.answers.insert((Name::from_ascii(name).unwrap(), DS_TYPE), message);
+        }
+
+        fn anchor(&self) -> TrustAnchor {
+            TrustAnchor { zone : Name::root(), ds : vec![Zone::new(".", 1).ds()], keys : vec![] }
+        }
+    }
+
+    impl Lookup for &Fixture {
+        fn lookup(&mut self, name : &Name, record_type : RecordType) -> Result<Message, Error> {
+            match self.answers.get(&(canonical(name), record_type)) {
+                Some(message) => Ok(message.clone()),
+                None => Ok(Message::error_msg(0, trust_dns::op::OpCode::Query, ResponseCode::NXDomain)),
+            }
+        }
+    }
+
+    fn record(name : &str, rdata : RData) -> Record {
+        Record::from_rdata(Name::from_ascii(name).unwrap(), TTL, rdata)
+    }
+
+    fn hashed(name : &str) -> Vec<u8> {
+        nsec3_hash(&Name::from_ascii(name).unwrap(), &[], 0).unwrap()
+    }
+
+    /// An NSEC3 record of `zone` for the hash `owner`, without salt or extra
+    /// iterations.
+    fn nsec3(zone : &Zone, owner : &[u8], next : Vec<u8>, opt_out : bool, types : Vec<RecordType>) -> Record {
+        let name = Name::from_ascii(BASE32_DNSSEC.encode(owner)).unwrap().append_name(&zone.name);
+        let nsec3 = NSEC3::new(Nsec3HashAlgorithm::SHA1, opt_out, 0, vec![], next, types);
+        Record::from_rdata(name, TTL, RData::DNSSEC(DNSSECRData::NSEC3(nsec3)))
+    }
+
+    /// The NSEC3 record matching the apex of `zone`, which proves it the
+    /// closest encloser of the names below.
+    fn apex_nsec3(zone : &Zone) -> Record {
+        let types = vec![RecordType::SOA, RecordType::NS, DNSKEY_TYPE, RRSIG_TYPE];
+        let owner = hashed(&zone.name.to_string());
+        // no hash falls between a hash and itself followed by a zero
+        let mut next = owner.clone();
+        next.push(0);
+        nsec3(zone, &owner, next, false, types)
+    }
+
+    /// A range of hashes that leaves out `hashed`.
+    fn elsewhere(hashed : &[u8]) -> (Vec<u8>, Vec<u8>) {
+        if hashed[0] < 0x80 {
+            (vec![0x80; 20], vec![0xc0; 20])
+        } else {
+            (vec![0x10; 20], vec![0x40; 20])
+        }
+    }
+
+    fn statuses(fixture : &Fixture, name : &str) -> Vec<Status> {
+        let response = fixture.answers[&(Name::from_ascii(name).unwrap(), RecordType::A)].clone();
+        let mut validator = Validator::new(fixture, fixture.anchor());
+        validator.validate(&response).unwrap().into_iter().map(|(_, status)| status).collect()
+    }
+
+    #[test]
+    fn signed_chain_is_secure() {
+        let fixture = Fixture::new();
+        assert_eq!(statuses(&fixture, "www.example."), vec![Status::Secure]);
+        assert_eq!(statuses(&fixture, "www.secure.example."), vec![Status::Secure]);
+    }
+
+    #[test]
+    fn unsigned_delegation_is_insecure() {
+        let fixture = Fixture::new();
+        assert_eq!(statuses(&fixture, "www.unsigned."), vec![Status::Insecure]);
+        assert_eq!(statuses(&fixture, "www.optout.example."), vec![Status::Insecure]);
+    }
+
+    #[test]
+    fn replayed_opt_out_nsec3_is_bogus() {
+        let example = Zone::new("example.", 2);
+        let (owner, next) = elsewhere(&hashed("secure.example."));
+        let replays = vec![
+            // covers other names only
+            vec![apex_nsec3(&example), nsec3(&example, &owner, next, true, vec![RecordType::NS])],
+            // covers the name, but nothing proves where the zone it is in starts
+            vec![nsec3(&example, &[0; 20], vec![0xff; 20], true, vec![RecordType::NS])],
+        ];
+        for records in replays {
+            let mut fixture = Fixture::new();
+            fixture.nsec3_denial(&example, "secure.example.", records);
+            match &statuses(&fixture, "www.secure.example.")[..] {
+                [Status::Bogus(_)] => {}
+                other => panic!("expected bogus, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn nsec_has_to_match_or_cover_the_name() {
+        let name = Name::from_ascii("b.example.").unwrap();
+        let nsec = |owner : &str, next : &str, types : Vec<RecordType>| {
+            record(owner, RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(Name::from_ascii(next).unwrap(), types))))
+        };
+        let delegation = nsec("b.example.", "c.example.", vec![RecordType::NS, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&name, &[&delegation]), Delegation::Unsigned));
+        let covering = nsec("a.example.", "c.example.", vec![RecordType::A, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&name, &[&covering]), Delegation::NotACut));
+        let last = nsec("a.example.", "example.", vec![RecordType::A, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&name, &[&last]), Delegation::NotACut));
+        let elsewhere = nsec("c.example.", "d.example.", vec![RecordType::A, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&name, &[&elsewhere]), Delegation::Bogus(_)));
+        let outside = nsec("x.example.", "example.", vec![RecordType::A, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&Name::from_ascii("a.").unwrap(), &[&outside]), Delegation::Bogus(_)));
+        let above = nsec("example.", "z.example.", vec![RecordType::NS, NSEC_TYPE, RRSIG_TYPE]);
+        assert!(matches!(denial(&name, &[&above]), Delegation::Bogus(_)));
+    }
+
+    #[test]
+    fn missing_signature_in_signed_zone_is_bogus() {
+        let fixture = Fixture::new();
+        match &statuses(&fixture, "nosig.example.")[..] {
+            [Status::Bogus(_)] => {}
+            other => panic!("expected bogus, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tampered_answer_is_bogus() {
+        let mut fixture = Fixture::new();
+        let key = (Name::from_ascii("www.example.").unwrap(), RecordType::A);
+        let mut answers = fixture.answers[&key].answers().to_vec();
+        answers[0].set_rdata(RData::A(Ipv4Addr::new(203, 0, 113, 66)));
+        let mut message = Message::new();
+        message.add_answers(answers);
+        fixture.answers.insert(key, message);
+        match &statuses(&fixture, "www.example.")[..] {
+            [Status::Bogus(_)] => {}
+            other => panic!("expected bogus, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn untrusted_anchor_is_bogus() {
+        let fixture = Fixture::new();
+        let response = fixture.answers[&(Name::from_ascii("www.example.").unwrap(), RecordType::A)].clone();
+        let anchor = TrustAnchor { zone : Name::root(), ds : vec![Zone::new(".", 9).ds()], keys : vec![] };
+        let mut validator = Validator::new(&fixture, anchor);
+        let results = validator.validate(&response).unwrap();
+        assert!(matches!(results[0].1, Status::Bogus(_)));
+    }
+
+    #[test]
+    fn parses_root_anchors() {
+        let anchor = TrustAnchor::root();
+        assert!(anchor.zone.is_root());
+        assert_eq!(anchor.ds.len(), 2);
+        assert_eq!(anchor.ds[0].key_tag(), 20326);
+    }
+}
@@ -0,0 +1,119 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use libmget::error::{self as mget_error, Kind};
+use trust_dns::op::ResponseCode;
+
+/// Everything that can go wrong in resolve.
+#[derive(Debug)]
+pub enum Error {
+    /// A command line or server spec that asks for something impossible.
+    Usage(String),
+    /// A name, address, record type or file that doesn't parse.
+    Parse { what : String, source : Box<dyn StdError + Send + Sync> },
+    /// Reading CA files, trust anchors, packets and name lists, and writing results.
+    Io { context : String, source : io::Error },
+    Network { context : String, source : io::Error },
+    /// Says what timed out, e.g. "query to 1.1.1.1#53 (udp)".
+    Timeout(String),
+    Refused(String),
+    NxDomain(String),
+    DnsServer { name : String, code : ResponseCode },
+    /// A server that doesn't speak DNS, or HTTP for DoH, the way it should.
+    Protocol(String),
+    Tls(rustls::Error),
+    /// The status line of a DoH response other than 200.
+    Status(String),
+}
+
+impl Error {
+    pub fn io(context : impl Into<String>, source : io::Error) -> Error {
+        Error::Io { context : context.into(), source }
+    }
+
+    /// Wraps the failure of talking to a server about `what`. A timeout or a
+    /// refusal gets a variant, and an exit status, of its own.
+    pub fn network(what : impl Into<String>, source : io::Error) -> Error {
+        let what = what.into();
+        match source.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout(what),
+            io::ErrorKind::ConnectionRefused => Error::Refused(what),
+            _ => Error::Network { context : format!("{} failed", what), source },
+        }
+    }
+
+    pub fn parse<E : Into<Box<dyn StdError + Send + Sync>>>(what : impl Into<String>, source : E) -> Error {
+        Error::Parse { what : what.into(), source : source.into() }
+    }
+
+    /// Which of libmget's exit statuses this error ends the process with.
+    /// Lookup failures and server trouble get different ones, so that
+    /// scripts can tell a missing name from a missing server.
+    pub fn kind(&self) -> Kind {
+        match self {
+            Error::Usage(_) | Error::Parse { .. } => Kind::Usage,
+            Error::Io { .. } => Kind::Io,
+            Error::Network { .. } => Kind::Network,
+            Error::Tls(_) => Kind::Tls,
+            Error::NxDomain(_) | Error::DnsServer { .. } => Kind::Dns,
+            Error::Protocol(_) => Kind::Protocol,
+            Error::Status(_) => Kind::Status,
+            Error::Timeout(_) => Kind::Timeout,
+            Error::Refused(_) => Kind::Refused,
+        }
+    }
+
+    pub fn exit(&self) -> ! {
+        mget_error::exit(self, self.kind())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Parse { what, .. } => write!(f, "unable to parse {}", what),
+            Error::Io { context, .. } => write!(f, "{}", context),
+            Error::Network { context, .. } => write!(f, "{}", context),
+            Error::Timeout(what) => write!(f, "{} timed out", what),
+            Error::Refused(what) => write!(f, "{} refused", what),
+            Error::NxDomain(name) => write!(f, "{} does not exist", name),
+            Error::DnsServer { name, code } => write!(f, "DNS server answered {} for {}", code, name),
+            Error::Protocol(message) => write!(f, "malformed response: {}", message),
+            Error::Tls(_) => write!(f, "TLS error"),
+            Error::Status(status_line) => write!(f, "DoH server answered {}", status_line),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Parse { source, .. } => Some(source.as_ref()),
+            Error::Io { source, .. } | Error::Network { source, .. } => Some(source),
+            Error::Tls(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_network_errors() {
+        let timeout = Error::network("query to 192.0.2.53#53 (udp)", io::ErrorKind::WouldBlock.into());
+        assert_eq!(timeout.to_string(), "query to 192.0.2.53#53 (udp) timed out");
+        assert_eq!(timeout.kind().exit_code(), 9);
+        let refused = Error::network("query to 192.0.2.53#53 (tcp)", io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(refused.kind().exit_code(), 10);
+        let other = Error::network("query to 192.0.2.53#53 (tcp)", io::Error::other("no route"));
+        assert_eq!(other.to_string(), "query to 192.0.2.53#53 (tcp) failed");
+        assert_eq!(other.source().unwrap().to_string(), "no route");
+        let bad = Error::parse("\"x\" as a record type", "unknown record type");
+        assert_eq!(bad.source().unwrap().to_string(), "unknown record type");
+        assert_eq!(bad.kind().exit_code(), 2);
+    }
+}
@@ -1,52 +1,308 @@
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
-use clap::{App, Arg};
-use rand;
-use trust_dns::op::{Message, MessageType, OpCode, Query};
+use clap::{App, Arg, ArgMatches};
+use trust_dns::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
 use trust_dns::rr::domain::Name;
+use trust_dns::rr::record_data::RData;
 use trust_dns::rr::record_type::RecordType;
-use trust_dns::serialize::binary::*;
+use trust_dns::rr::resource::Record;
 
-fn main() {
-    let app = App::new("resolve")
-            .about("A simple app to use DNS resolver")
-            .arg(Arg::with_name("dns-server").short("s").default_value("1.1.1.1"))
-            .arg(Arg::with_name("domain-name").required(true))
-            .get_matches();
-    let domain_name_raw = app.value_of("domain-name").unwrap();
-    let domain_name = Name::from_ascii(&domain_name_raw).unwrap();
-    let dns_server_raw = app.value_of("dns-server").unwrap();
-    let dns_server : SocketAddr = format!("{}:53", dns_server_raw)
-            .parse().expect("invalid address");
-    let mut request_as_bytes : Vec<u8> = Vec::with_capacity(512);
-    let mut response_as_bytes : Vec<u8> = vec![0;512];
+mod batch;
+mod dig;
+mod dnssec;
+mod error;
+mod transport;
+
+use error::Error;
+
+fn query(domain_name : Name, record_type : RecordType, dnssec_ok : bool) -> Message {
     let mut msg = Message::new();
     msg.set_id(rand::random::<u16>())
         .set_message_type(MessageType::Query)
-        .add_query(Query::query(domain_name, RecordType::A))
+        .add_query(Query::query(domain_name, record_type))
         .set_op_code(OpCode::Query)
         .set_recursion_desired(true);
+    if dnssec_ok {
+        let mut edns = Edns::new();
+        edns.set_max_payload(transport::EDNS_PAYLOAD);
+        edns.set_dnssec_ok(true);
+        msg.set_edns(edns);
+    }
+    msg
+}
+
+/// Builds the in-addr.arpa or ip6.arpa name that holds the PTR records for `ip`.
+fn reverse_name(ip : IpAddr) -> Name {
+    let labels : Vec<String> = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().map(|octet| octet.to_string())
+            .chain(vec!["in-addr".to_string(), "arpa".to_string()])
+            .collect(),
+        IpAddr::V6(ip) => ip.octets().iter().rev()
+            .flat_map(|octet| vec![format!("{:x}", octet & 0x0f), format!("{:x}", octet >> 4)])
+            .chain(vec!["ip6".to_string(), "arpa".to_string()])
+            .collect(),
+    };
+    Name::from_labels(labels).expect("reverse names are always valid")
+}
+
+fn display(answer : &Record) -> Option<String> {
+    match answer.rdata() {
+        RData::A(_) => answer.rdata().to_ip_addr().map(|ip| ip.to_string()),
+        RData::PTR(name) => Some(name.to_string().trim_end_matches('.').to_string()),
+        other => Some(dig::rdata_text(other)),
+    }
+}
+
+/// Resolves every name listed in `input`, one per line, over a single
+/// pipelined UDP socket.
+fn resolve_batch(upstream : &transport::Transport, input : &str, concurrency : usize, format : batch::Format)
+    -> Result<(), Error>
+{
+    let dns_server = match upstream.server() {
+        transport::Server::Udp(address) => *address,
+        _ => return Err(Error::Usage("--input needs a plain udp:// server".to_string())),
+    };
+    let unreadable = |e| Error::io(format!("unable to read {}", input), e);
+    let reader : Box<dyn BufRead> = match input {
+        "-" => Box::new(BufReader::new(std::io::stdin())),
+        path => Box::new(BufReader::new(File::open(path).map_err(unreadable)?)),
+    };
+    let mut outcomes = Vec::new();
+    let mut requests = Vec::new();
+    let mut queried = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(unreadable)?;
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        outcomes.push(batch::Outcome { name : name.to_string(), answers : vec![], error : None });
+        match Name::from_ascii(name) {
+            Ok(mut domain_name) => {
+                domain_name.set_fqdn(true);
+                requests.push(query(domain_name, RecordType::A, false));
+                queried.push(outcomes.len() - 1);
+            }
+            Err(e) => outcomes.last_mut().unwrap().error = Some(e.to_string()),
+        }
+    }
+
+    let pipeline = batch::Pipeline::new(dns_server, concurrency, Duration::from_secs(3));
+    let results = pipeline.run(requests.clone())
+        .map_err(|e| Error::network(format!("queries to {}", upstream.server()), e))?;
+    for ((index, request), result) in queried.into_iter().zip(requests).zip(results) {
+        let outcome = &mut outcomes[index];
+        let response = match result {
+            // too big for UDP, so this one goes over TCP on its own
+            Ok(response) if response.truncated() => upstream.exchange(&request).map_err(|e| e.to_string()),
+            other => other,
+        };
+        match response {
+            Ok(response) if response.response_code() != ResponseCode::NoError => {
+                outcome.error = Some(response.response_code().to_string());
+            }
+            Ok(response) => {
+                outcome.answers = response.answers()
+                    .iter()
+                    .filter(|answer| answer.record_type() == RecordType::A)
+                    .filter_map(display)
+                    .collect();
+            }
+            Err(e) => outcome.error = Some(e),
+        }
+    }
+    batch::write(&mut std::io::stdout().lock(), format, &outcomes)
+        .map_err(|e| Error::io("unable to write the results", e))
+}
+
+impl dnssec::Lookup for transport::Transport {
+    fn lookup(&mut self, name : &Name, record_type : RecordType) -> Result<Message, Error> {
+        self.exchange(&query(name.clone(), record_type, true))
+    }
+}
+
+fn main() {
+    let app = App::new("resolve")
+            .about("A simple app to use DNS resolver")
+            .arg(Arg::with_name("dns-server")
+                .short("s")
+                .default_value("1.1.1.1")
+                .help("Server as an address or a URL: udp://, tcp://, tls://, https:// or https+get://"))
+            .arg(Arg::with_name("ca-file")
+                .long("ca-file")
+                .takes_value(true)
+                .help("PEM certificates to trust for tls:// and https:// servers"))
+            .arg(Arg::with_name("dnssec")
+                .long("dnssec")
+                .help("Validates answers and reports secure, insecure or bogus"))
+            .arg(Arg::with_name("trust-anchor")
+                .long("trust-anchor")
+                .takes_value(true)
+                .requires("dnssec")
+                .help("File of DS or DNSKEY records to use instead of the root anchors"))
+            .arg(Arg::with_name("reverse")
+                .short("x")
+                .takes_value(true)
+                .value_name("ip")
+                .conflicts_with("domain-name")
+                .help("Looks up the host names of <ip> through its PTR records"))
+            .arg(Arg::with_name("input")
+                .long("input")
+                .takes_value(true)
+                .conflicts_with_all(&["domain-name", "reverse", "dnssec"])
+                .help("Resolves every name in a file, one per line, or - for stdin"))
+            .arg(Arg::with_name("concurrency")
+                .long("concurrency")
+                .takes_value(true)
+                .default_value("100")
+                .help("Queries kept in flight at once with --input"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["plain", "csv", "json"])
+                .default_value("plain"))
+            .arg(Arg::with_name("dig")
+                .long("dig")
+                .conflicts_with("input")
+                .help("Prints the whole response: header, flags, every section and timing"))
+            .arg(Arg::with_name("type")
+                .short("t")
+                .long("type")
+                .takes_value(true)
+                .conflicts_with("reverse")
+                .help("Record type to ask for, A unless given"))
+            .arg(Arg::with_name("packet")
+                .long("packet")
+                .takes_value(true)
+                .conflicts_with_all(&["domain-name", "reverse", "input", "dnssec"])
+                .help("Decodes a captured DNS message from a file instead of querying"))
+            .arg(Arg::with_name("domain-name").required_unless_one(&["reverse", "input", "packet"]))
+            .get_matches();
+    if let Err(e) = run(&app) {
+        e.exit();
+    }
+}
+
+fn run(app : &ArgMatches) -> Result<(), Error> {
+    if let Some(path) = app.value_of("packet") {
+        let raw = std::fs::read(path).map_err(|e| Error::io(format!("unable to read {}", path), e))?;
+        let dns_message = dig::decode_packet(&raw).map_err(|e| Error::parse(format!("{} as a DNS message", path), e))?;
+        print!("{}", dig::render(&dns_message, raw.len(), None));
+        return Ok(());
+    }
+
+    let dns_server : transport::Server = app.value_of("dns-server").unwrap().parse()?;
+    let mut upstream = transport::Transport::new(dns_server, Duration::from_secs(3));
+    if let Some(ca_file) = app.value_of("ca-file") {
+        upstream = upstream.with_ca_file(ca_file)?;
+    }
 
-    let mut encoder = BinEncoder::new(&mut request_as_bytes);
-    msg.emit(&mut encoder).unwrap();
+    if let Some(input) = app.value_of("input") {
+        let concurrency : usize = app.value_of("concurrency").unwrap()
+            .parse()
+            .map_err(|e| Error::parse("<concurrency>", e))?;
+        if !(1..=batch::MAX_CONCURRENCY).contains(&concurrency) {
+            return Err(Error::Usage(format!("<concurrency> must be from 1 to {}", batch::MAX_CONCURRENCY)));
+        }
+        let format : batch::Format = app.value_of("format").unwrap().parse().unwrap();
+        return resolve_batch(&upstream, input, concurrency, format);
+    }
 
-    let localhost = UdpSocket::bind("0.0.0.0:0").expect("Can not bind to local socket");
-    let timeout = Duration::from_secs(3);
-    localhost.set_read_timeout(Some(timeout)).unwrap();
-    localhost.set_nonblocking(false).unwrap();
+    let (domain_name, record_type) = match app.value_of("reverse") {
+        Some(ip) => {
+            let ip : IpAddr = ip.parse().map_err(|e| Error::parse(format!("{:?} as an IP address", ip), e))?;
+            (reverse_name(ip), RecordType::PTR)
+        }
+        None => {
+            let domain_name_raw = app.value_of("domain-name").unwrap();
+            let mut domain_name = Name::from_ascii(domain_name_raw)
+                .map_err(|e| Error::parse(format!("{:?} as a domain name", domain_name_raw), e.to_string()))?;
+            domain_name.set_fqdn(true);
+            let record_type = match app.value_of("type") {
+                Some(record_type) => record_type.to_uppercase().parse::<RecordType>()
+                    .map_err(|e| Error::parse(format!("{:?} as a record type", record_type), e.to_string()))?,
+                None => RecordType::A,
+            };
+            (domain_name, record_type)
+        }
+    };
+    let validate = app.is_present("dnssec");
 
-    let _amt = localhost.send_to(&request_as_bytes, dns_server)
-        .expect("socket misconfigured");
-    let (_amt, _remote) = localhost.recv_from(&mut response_as_bytes).expect("timeout reached");
-    let dns_message = Message::from_vec(&response_as_bytes).expect("unable to parse response");
+    let msg = query(domain_name.clone(), record_type, validate);
+    let started = Instant::now();
+    let (dns_message, raw) = upstream.exchange_raw(&msg)?;
 
-    for answer in dns_message.answers() {
-        if answer.record_type() == RecordType::A {
-            let resource = answer.rdata();
-            let ip = resource.to_ip_addr()
-                    .expect("invalid IP address received");
-            println!("{}", ip.to_string());
+    if app.is_present("dig") {
+        let server = upstream.server().to_string();
+        let exchange = dig::Exchange { server : &server, elapsed : started.elapsed() };
+        print!("{}", dig::render(&dns_message, raw.len(), Some(exchange)));
+    } else {
+        // dig shows the response code in the header, everything else exits with it
+        let name = domain_name.to_string().trim_end_matches('.').to_string();
+        match dns_message.response_code() {
+            ResponseCode::NoError => {}
+            ResponseCode::NXDomain => return Err(Error::NxDomain(name)),
+            code => return Err(Error::DnsServer { name, code }),
         }
     }
+
+    if !validate {
+        if app.is_present("dig") {
+            return Ok(());
+        }
+        for answer in dns_message.answers() {
+            if answer.record_type() == record_type {
+                if let Some(text) = display(answer) {
+                    println!("{}", text);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let anchor = match app.value_of("trust-anchor") {
+        Some(path) => dnssec::TrustAnchor::from_file(path)?,
+        None => dnssec::TrustAnchor::root(),
+    };
+    let mut validator = dnssec::Validator::new(upstream, anchor);
+    let answers = validator.validate(&dns_message)?;
+    if app.is_present("dig") {
+        println!(";; DNSSEC:");
+        for (answer, status) in answers {
+            println!("{}\t; {}", dig::record_line(&answer), status);
+        }
+        return Ok(());
+    }
+    for (answer, status) in answers {
+        if answer.record_type() == record_type {
+            if let Some(text) = display(&answer) {
+                println!("{}\t{}", text, status);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_name_v4() {
+        let ip : IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(reverse_name(ip).to_string(), "10.2.0.192.in-addr.arpa.");
+    }
+
+    #[test]
+    fn reverse_name_v6() {
+        let ip : IpAddr = "2001:db8::567:89ab".parse().unwrap();
+        assert_eq!(
+            reverse_name(ip).to_string(),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa."
+        );
+    }
 }
@@ -0,0 +1,481 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use data_encoding::BASE64URL_NOPAD;
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName, StreamOwned};
+use trust_dns::op::Message;
+use trust_dns::serialize::binary::*;
+use url::Url;
+
+use crate::error::Error;
+
+pub const EDNS_PAYLOAD : u16 = 4096;
+const DNS_MESSAGE : &str = "application/dns-message";
+
+/// Where queries go and how they get there, parsed from a URL-style spec:
+///
+/// * `1.1.1.1` or `udp://1.1.1.1:53`, plain DNS that falls back to TCP
+/// * `tcp://1.1.1.1`
+/// * `tls://1.1.1.1` or `tls://dns.google:853`, DNS over TLS (RFC 7858)
+/// * `https://cloudflare-dns.com/dns-query`, DNS over HTTPS with POST (RFC 8484)
+/// * `https+get://cloudflare-dns.com/dns-query`, the same with GET
+#[derive(Debug, Clone, PartialEq)]
+pub enum Server {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Tls { address : SocketAddr, name : String },
+    Https { address : SocketAddr, name : String, url : Url, get : bool },
+}
+
+impl std::str::FromStr for Server {
+    type Err = Error;
+
+    fn from_str(spec : &str) -> Result<Self, Self::Err> {
+        if !spec.contains("://") {
+            return match spec.parse::<SocketAddr>() {
+                Ok(address) => Ok(Server::Udp(address)),
+                Err(_) => Ok(Server::Udp(socket_address(spec, 53)?)),
+            };
+        }
+        let url = Url::parse(spec).map_err(|e| Error::parse(format!("{:?} as a server", spec), e))?;
+        let host = url.host_str().ok_or_else(|| Error::Usage(format!("server {:?} has no host", spec)))?;
+        let name = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        let address = |default : u16| socket_address(&name, url.port().unwrap_or(default));
+        match url.scheme() {
+            "udp" => Ok(Server::Udp(address(53)?)),
+            "tcp" => Ok(Server::Tcp(address(53)?)),
+            "tls" => Ok(Server::Tls { address : address(853)?, name }),
+            "https" | "https+get" => {
+                let get = url.scheme() == "https+get";
+                let address = address(443)?;
+                let url = Url::parse(&spec.replacen("https+get://", "https://", 1))
+                    .map_err(|e| Error::parse(format!("{:?} as a server", spec), e))?;
+                Ok(Server::Https { address, name, url, get })
+            }
+            other => Err(Error::Usage(format!("unsupported transport: {}", other))),
+        }
+    }
+}
+
+impl std::fmt::Display for Server {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Server::Udp(address) => write!(f, "{}#{} (udp)", address.ip(), address.port()),
+            Server::Tcp(address) => write!(f, "{}#{} (tcp)", address.ip(), address.port()),
+            Server::Tls { address, name } => write!(f, "{}#{} ({}) (tls)", address.ip(), address.port(), name),
+            Server::Https { address, url, .. } => write!(f, "{}#{} ({}) (https)", address.ip(), address.port(), url),
+        }
+    }
+}
+
+fn socket_address(host : &str, port : u16) -> Result<SocketAddr, Error> {
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| Error::network(format!("resolving {}", host), e))?
+        .next()
+        .ok_or_else(|| Error::NxDomain(host.to_string()))
+}
+
+pub struct Transport {
+    server : Server,
+    timeout : Duration,
+    tls : Arc<ClientConfig>,
+}
+
+impl Transport {
+    pub fn new(server : Server, timeout : Duration) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Transport { server, timeout, tls : Arc::new(tls_config(roots)) }
+    }
+
+    /// Trusts only the certificates in `path`, for servers with private or
+    /// self-signed certificates.
+    pub fn with_ca_file<P : AsRef<Path>>(mut self, path : P) -> Result<Self, Error> {
+        let unreadable = |e| Error::io(format!("unable to read {}", path.as_ref().display()), e);
+        let mut roots = RootCertStore::empty();
+        let mut reader = BufReader::new(File::open(path.as_ref()).map_err(unreadable)?);
+        for cert in rustls_pemfile::certs(&mut reader).map_err(unreadable)? {
+            roots.add(&Certificate(cert)).map_err(Error::Tls)?;
+        }
+        self.tls = Arc::new(tls_config(roots));
+        Ok(self)
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn exchange(&self, request : &Message) -> Result<Message, Error> {
+        self.exchange_raw(request).map(|(response, _)| response)
+    }
+
+    /// Like `exchange`, but also hands back the response as it came off the
+    /// wire.
+    pub fn exchange_raw(&self, request : &Message) -> Result<(Message, Vec<u8>), Error> {
+        let request_as_bytes = encode(request)?;
+        let response_as_bytes = match &self.server {
+            Server::Udp(address) => {
+                let (response, raw) = self.udp(*address, &request_as_bytes, request.id())?;
+                if !response.truncated() {
+                    return Ok((response, raw));
+                }
+                self.tcp(*address, &request_as_bytes)?
+            }
+            Server::Tcp(address) => self.tcp(*address, &request_as_bytes)?,
+            Server::Tls { address, name } => {
+                let mut conn = self.tls_connect(*address, name)?;
+                send_framed(&mut conn, &request_as_bytes).map_err(|e| self.failed(e))?
+            }
+            Server::Https { address, name, url, get } => {
+                let mut conn = self.tls_connect(*address, name)?;
+                self.https(&mut conn, url, *get, request)?
+            }
+        };
+        let response = Message::from_vec(&response_as_bytes)
+            .map_err(|e| Error::Protocol(format!("undecodable DNS message ({})", e)))?;
+        match &self.server {
+            // RFC 8484 section 4.1 asks GET requests to use ID 0 so caches can share them
+            Server::Https { get : true, .. } => Ok((response, response_as_bytes)),
+            _ if response.id() != request.id() => Err(Error::Protocol("response ID does not match the request".to_string())),
+            _ => Ok((response, response_as_bytes)),
+        }
+    }
+
+    /// Sorts out an error of the exchange with the server.
+    fn failed(&self, e : std::io::Error) -> Error {
+        Error::network(format!("query to {}", self.server), e)
+    }
+
+    fn udp(&self, address : SocketAddr, request : &[u8], id : u16) -> Result<(Message, Vec<u8>), Error> {
+        let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let localhost = UdpSocket::bind(bind).map_err(|e| Error::io("unable to open a UDP socket", e))?;
+        localhost.set_read_timeout(Some(self.timeout)).map_err(|e| Error::io("unable to open a UDP socket", e))?;
+        localhost.set_nonblocking(false).map_err(|e| Error::io("unable to open a UDP socket", e))?;
+        localhost.send_to(request, address).map_err(|e| self.failed(e))?;
+        let mut response_as_bytes : Vec<u8> = vec![0; EDNS_PAYLOAD as usize];
+        loop {
+            let (amt, remote) = localhost.recv_from(&mut response_as_bytes).map_err(|e| self.failed(e))?;
+            if remote != address {
+                continue;
+            }
+            let response = Message::from_vec(&response_as_bytes[..amt])
+                .map_err(|e| Error::Protocol(format!("undecodable DNS message ({})", e)))?;
+            if response.id() == id {
+                response_as_bytes.truncate(amt);
+                return Ok((response, response_as_bytes));
+            }
+        }
+    }
+
+    fn tcp(&self, address : SocketAddr, request : &[u8]) -> Result<Vec<u8>, Error> {
+        let mut conn = TcpStream::connect_timeout(&address, self.timeout).map_err(|e| self.failed(e))?;
+        conn.set_read_timeout(Some(self.timeout)).map_err(|e| self.failed(e))?;
+        send_framed(&mut conn, request).map_err(|e| self.failed(e))
+    }
+
+    fn tls_connect(&self, address : SocketAddr, name : &str)
+        -> Result<StreamOwned<ClientConnection, TcpStream>, Error>
+    {
+        let server_name = ServerName::try_from(name).map_err(|e| Error::parse(format!("{:?} as a TLS server name", name), e))?;
+        let conn = ClientConnection::new(self.tls.clone(), server_name).map_err(Error::Tls)?;
+        let sock = TcpStream::connect_timeout(&address, self.timeout).map_err(|e| self.failed(e))?;
+        sock.set_read_timeout(Some(self.timeout)).map_err(|e| self.failed(e))?;
+        sock.set_write_timeout(Some(self.timeout)).map_err(|e| self.failed(e))?;
+        Ok(StreamOwned::new(conn, sock))
+    }
+
+    fn https<S : Read + Write>(&self, conn : &mut S, url : &Url, get : bool, request : &Message)
+        -> Result<Vec<u8>, Error>
+    {
+        let host = url.host_str().ok_or_else(|| Error::Usage(format!("DoH URL {} has no host", url)))?;
+        let head = if get {
+            let mut cacheable = request.clone();
+            cacheable.set_id(0);
+            let mut target = url.clone();
+            target.query_pairs_mut().append_pair("dns", &BASE64URL_NOPAD.encode(&encode(&cacheable)?));
+            format!("GET {}", &target[url::Position::BeforePath..])
+        } else {
+            format!("POST {}", &url[url::Position::BeforePath..])
+        };
+        let mut http_request = format!(
+            "{} HTTP/1.1\r\nHost: {}\r\nAccept: {}\r\nConnection: close\r\n",
+            head, host, DNS_MESSAGE
+        )
+        .into_bytes();
+        if get {
+            http_request.extend_from_slice(b"\r\n");
+        } else {
+            let body = encode(request)?;
+            http_request.extend(format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n", DNS_MESSAGE, body.len()).bytes());
+            http_request.extend(body);
+        }
+        conn.write_all(&http_request).map_err(|e| self.failed(e))?;
+        conn.flush().map_err(|e| self.failed(e))?;
+
+        let mut raw = Vec::new();
+        read_until_close(conn, &mut raw).map_err(|e| self.failed(e))?;
+        http_body(&raw)
+    }
+}
+
+fn tls_config(roots : RootCertStore) -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+pub fn encode(message : &Message) -> Result<Vec<u8>, Error> {
+    let mut bytes : Vec<u8> = Vec::with_capacity(512);
+    let mut encoder = BinEncoder::new(&mut bytes);
+    message.emit(&mut encoder).map_err(|e| Error::Usage(format!("unable to encode the query: {}", e)))?;
+    Ok(bytes)
+}
+
+/// TCP and TLS carry each message behind a two byte length (RFC 1035 section 4.2.2).
+fn send_framed<S : Read + Write>(conn : &mut S, request : &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(request);
+    conn.write_all(&framed)?;
+    conn.flush()?;
+    let mut len = [0; 2];
+    conn.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    conn.read_exact(&mut response)?;
+    Ok(response)
+}
+
+// servers that skip TLS close_notify are common enough to treat as a clean end
+fn read_until_close<S : Read>(conn : &mut S, raw : &mut Vec<u8>) -> std::io::Result<()> {
+    match conn.read_to_end(raw) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !raw.is_empty() => Ok(()),
+        other => other.map(|_| ()),
+    }
+}
+
+fn http_body(raw : &[u8]) -> Result<Vec<u8>, Error> {
+    let malformed = |message : &str| Error::Protocol(message.to_string());
+    let split = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| malformed("incomplete HTTP response"))?;
+    let head = std::str::from_utf8(&raw[..split]).map_err(|_| malformed("HTTP head is not UTF-8"))?;
+    let body = &raw[split + 4..];
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let status = status_line.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(Error::Status(status_line.to_string()));
+    }
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse::<usize>().map_err(|_| malformed("invalid Content-Length"))?);
+        }
+    }
+    if chunked {
+        return dechunk(body);
+    }
+    match length {
+        Some(length) if length <= body.len() => Ok(body[..length].to_vec()),
+        Some(_) => Err(malformed("HTTP body shorter than Content-Length")),
+        None => Ok(body.to_vec()),
+    }
+}
+
+fn dechunk(mut body : &[u8]) -> Result<Vec<u8>, Error> {
+    let malformed = || Error::Protocol("malformed chunk".to_string());
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(malformed)?;
+        let size_field = std::str::from_utf8(&body[..line_end]).map_err(|_| malformed())?;
+        let size = usize::from_str_radix(size_field.split(';').next().unwrap_or("").trim(), 16).map_err(|_| malformed())?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err(Error::Protocol("truncated chunk".to_string()));
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+    use trust_dns::op::{MessageType, OpCode, Query};
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    /// A local TLS stand-in with a self-signed certificate for `127.0.0.1`,
+    /// answering one DoT or DoH exchange with 192.0.2.1.
+    fn stand_in(pem_path : &Path, http : bool) -> SocketAddr {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        std::fs::write(pem_path, cert.serialize_pem().unwrap()).unwrap();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = StreamOwned::new(conn, sock);
+            if http {
+                serve_https(&mut tls);
+            } else {
+                let mut len = [0; 2];
+                tls.read_exact(&mut len).unwrap();
+                let mut request = vec![0; u16::from_be_bytes(len) as usize];
+                tls.read_exact(&mut request).unwrap();
+                let response = answer(&request);
+                tls.write_all(&(response.len() as u16).to_be_bytes()).unwrap();
+                tls.write_all(&response).unwrap();
+            }
+            tls.conn.send_close_notify();
+            tls.flush().unwrap();
+        });
+        address
+    }
+
+    fn serve_https<S : Read + Write>(tls : &mut S) {
+        let mut raw = Vec::new();
+        let mut byte = [0; 1];
+        while !raw.ends_with(b"\r\n\r\n") {
+            tls.read_exact(&mut byte).unwrap();
+            raw.push(byte[0]);
+        }
+        let head = String::from_utf8(raw).unwrap();
+        let request = if head.starts_with("GET ") {
+            let target = head.split_whitespace().nth(1).unwrap();
+            let dns = target.split("dns=").nth(1).unwrap();
+            BASE64URL_NOPAD.decode(dns.as_bytes()).unwrap()
+        } else {
+            assert!(head.starts_with("POST /dns-query HTTP/1.1\r\n"));
+            assert!(head.contains("Content-Type: application/dns-message"));
+            let length : usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            tls.read_exact(&mut body).unwrap();
+            body
+        };
+        let response = answer(&request);
+        // chunked on purpose, to cover the decoder
+        let (first, second) = response.split_at(response.len() / 2);
+        write!(tls, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\r\n", DNS_MESSAGE).unwrap();
+        write!(tls, "{:x}\r\n", first.len()).unwrap();
+        tls.write_all(first).unwrap();
+        write!(tls, "\r\n{:x}\r\n", second.len()).unwrap();
+        tls.write_all(second).unwrap();
+        tls.write_all(b"\r\n0\r\n\r\n").unwrap();
+    }
+
+    fn answer(request : &[u8]) -> Vec<u8> {
+        let request = Message::from_vec(request).unwrap();
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .add_queries(request.queries().to_vec())
+            .add_answer(Record::from_rdata(
+                request.queries()[0].name().clone(),
+                60,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+        encode(&response).unwrap()
+    }
+
+    fn request() -> Message {
+        let mut msg = Message::new();
+        msg.set_id(4242)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+        msg
+    }
+
+    fn exchange_with(spec : &str, http : bool) -> Message {
+        let pem = std::env::temp_dir().join(format!("resolve-{}-{}.pem", std::process::id(), spec.split(':').next().unwrap()));
+        let address = stand_in(&pem, http);
+        let spec = spec.replace("PORT", &address.port().to_string());
+        let transport = Transport::new(spec.parse().unwrap(), Duration::from_secs(5))
+            .with_ca_file(&pem)
+            .unwrap();
+        let response = transport.exchange(&request()).unwrap();
+        std::fs::remove_file(pem).unwrap();
+        response
+    }
+
+    #[test]
+    fn parses_server_specs() {
+        assert_eq!("1.1.1.1".parse::<Server>().unwrap(), Server::Udp("1.1.1.1:53".parse().unwrap()));
+        assert_eq!("tcp://9.9.9.9:5353".parse::<Server>().unwrap(), Server::Tcp("9.9.9.9:5353".parse().unwrap()));
+        assert_eq!(
+            "tls://1.1.1.1".parse::<Server>().unwrap(),
+            Server::Tls { address : "1.1.1.1:853".parse().unwrap(), name : "1.1.1.1".to_string() }
+        );
+        match "https+get://127.0.0.1/dns-query".parse::<Server>().unwrap() {
+            Server::Https { address, get, url, .. } => {
+                assert_eq!(address, "127.0.0.1:443".parse().unwrap());
+                assert!(get);
+                assert_eq!(url.as_str(), "https://127.0.0.1/dns-query");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!("gopher://1.1.1.1".parse::<Server>().is_err());
+    }
+
+    #[test]
+    fn dns_over_tls() {
+        let response = exchange_with("tls://127.0.0.1:PORT", false);
+        assert_eq!(response.id(), 4242);
+        assert_eq!(response.answers()[0].rdata().to_ip_addr().unwrap().to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn dns_over_https_post() {
+        let response = exchange_with("https://127.0.0.1:PORT/dns-query", true);
+        assert_eq!(response.answers()[0].rdata().to_ip_addr().unwrap().to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn dns_over_https_get() {
+        let response = exchange_with("https+get://127.0.0.1:PORT/dns-query", true);
+        assert_eq!(response.id(), 0);
+        assert_eq!(response.answers()[0].rdata().to_ip_addr().unwrap().to_string(), "192.0.2.1");
+    }
+}
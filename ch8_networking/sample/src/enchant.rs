@@ -0,0 +1,71 @@
+use clap::{App, Arg, ArgMatches};
+use libsample::error::Error;
+use libsample::rpg::{self, Enchanter, Roster};
+use rand::seq::SliceRandom;
+
+fn main() {
+    let app = App::new("enchant")
+        .about("Has a member of the party enchant an item, or measures how often that works")
+        .arg(Arg::with_name("roster")
+            .short("r")
+            .long("roster")
+            .takes_value(true)
+            .value_name("file")
+            .help("TOML or JSON file of races and items instead of the built-in ones"))
+        .arg(Arg::with_name("race")
+            .long("race")
+            .takes_value(true)
+            .help("Race that casts the spell instead of a random one"))
+        .arg(Arg::with_name("item")
+            .long("item")
+            .takes_value(true)
+            .help("Item to enchant instead of a random one"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .help("Seeds the random number generator, so that runs repeat"))
+        .arg(Arg::with_name("simulate")
+            .long("simulate")
+            .takes_value(true)
+            .value_name("trials")
+            .help("Reports the success rate of every race on every item over <trials> spells"))
+        .get_matches();
+
+    if let Err(e) = run(&app) {
+        e.exit();
+    }
+}
+
+fn run(app : &ArgMatches) -> Result<(), Error> {
+    let roster = match app.value_of("roster") {
+        Some(path) => Roster::from_file(path)?,
+        None => Roster::default(),
+    };
+    let seed = match app.value_of("seed") {
+        Some(seed) => Some(seed.parse::<u64>().map_err(|e| Error::parse("<seed>", e))?),
+        None => None,
+    };
+    let mut rng = rpg::rng(seed);
+
+    if let Some(trials) = app.value_of("simulate") {
+        let trials : u64 = trials.parse().map_err(|e| Error::parse("<trials>", e))?;
+        for rate in roster.simulate(trials, &mut rng) {
+            println!("{}", rate);
+        }
+        return Ok(());
+    }
+
+    let enchanter = match app.value_of("race") {
+        Some(name) => roster.race(name)
+            .ok_or_else(|| Error::Usage(format!("no race called {} in the roster", name)))?
+            .clone(),
+        None => roster.races.choose(&mut rng).unwrap().clone(),
+    };
+    let mut thing = match app.value_of("item") {
+        Some(name) => roster.thing(name)
+            .ok_or_else(|| Error::Usage(format!("no item called {} in the roster", name)))?,
+        None => rpg::Thing::Item(roster.items.choose(&mut rng).unwrap().clone()),
+    };
+    println!("{}", enchanter.enchant(&mut thing, &mut rng));
+    Ok(())
+}
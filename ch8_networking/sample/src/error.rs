@@ -0,0 +1,125 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+
+use libmget::error::{self as mget_error, Kind};
+
+/// Everything that can go wrong in sample and serve.
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    /// Files, the served directory and the output.
+    Io { context : String, source : io::Error },
+    Parsing(AddrParseError),
+    /// A roster, or another file or argument, that doesn't parse.
+    Parse { what : String, source : Box<dyn StdError + Send + Sync> },
+    Network { context : String, source : io::Error },
+    /// Says what timed out, e.g. "connection to www.rustinaction.com:80".
+    Timeout(String),
+    Refused(String),
+    Http(reqwest::Error),
+}
+
+impl Error {
+    pub fn io(context : impl Into<String>, source : io::Error) -> Error {
+        Error::Io { context : context.into(), source }
+    }
+
+    /// Wraps the failure of a connection, bind or request to `what`, as a
+    /// timeout or refusal where that's what it was.
+    pub fn network(what : impl Into<String>, source : io::Error) -> Error {
+        let what = what.into();
+        match source.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout(what),
+            io::ErrorKind::ConnectionRefused => Error::Refused(what),
+            _ => Error::Network { context : format!("{} failed", what), source },
+        }
+    }
+
+    pub fn parse<E : Into<Box<dyn StdError + Send + Sync>>>(what : impl Into<String>, source : E) -> Error {
+        Error::Parse { what : what.into(), source : source.into() }
+    }
+
+    /// Which of libmget's exit statuses this error ends the process with.
+    pub fn kind(&self) -> Kind {
+        match self {
+            Error::Usage(_) | Error::Parsing(_) | Error::Parse { .. } => Kind::Usage,
+            Error::Io { .. } => Kind::Io,
+            Error::Http(e) if e.is_timeout() => Kind::Timeout,
+            Error::Network { .. } | Error::Http(_) => Kind::Network,
+            Error::Timeout(_) => Kind::Timeout,
+            Error::Refused(_) => Kind::Refused,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind().exit_code()
+    }
+
+    pub fn exit(&self) -> ! {
+        mget_error::exit(self, self.kind())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Io { context, .. } => write!(f, "{}", context),
+            Error::Parsing(_) => write!(f, "unable to parse the address"),
+            Error::Parse { what, .. } => write!(f, "unable to parse {}", what),
+            Error::Network { context, .. } => write!(f, "{}", context),
+            Error::Timeout(what) => write!(f, "{} timed out", what),
+            Error::Refused(what) => write!(f, "{} refused", what),
+            Error::Http(_) => write!(f, "HTTP request failed"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Network { source, .. } => Some(source),
+            Error::Parsing(source) => Some(source),
+            Error::Parse { source, .. } => Some(source.as_ref()),
+            Error::Http(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<AddrParseError> for Error {
+    fn from(value : AddrParseError) -> Self {
+        Error::Parsing(value)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value : reqwest::Error) -> Self {
+        Error::Http(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_and_sorts() {
+        let e : Error = "::g".parse::<std::net::Ipv6Addr>().unwrap_err().into();
+        assert_eq!(e.to_string(), "unable to parse the address");
+        assert_eq!(e.source().unwrap().to_string(), "invalid IPv6 address syntax");
+        assert_eq!(e.exit_code(), 2);
+        let refused = Error::network("connection to 127.0.0.1:1", io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(refused.to_string(), "connection to 127.0.0.1:1 refused");
+        assert_eq!(refused.exit_code(), 10);
+        let other = Error::network("listening on 127.0.0.1:80", io::ErrorKind::PermissionDenied.into());
+        assert_eq!(other.to_string(), "listening on 127.0.0.1:80 failed");
+        assert_eq!(other.exit_code(), 4);
+        let io = Error::io("unable to read roster.toml", io::ErrorKind::NotFound.into());
+        assert_eq!(io.to_string(), "unable to read roster.toml");
+        assert_eq!(io.source().unwrap().to_string(), "entity not found");
+        assert_eq!(io.exit_code(), 3);
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{Shutdown as Close, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::rpg::{self, Enchanter, Roster, Thing};
+use crate::server::Shutdown;
+
+const MAX_LINE_LEN : u64 = 1024;
+/// How long a client may leave a broadcast unread before it is dropped.
+const WRITE_TIMEOUT : Duration = Duration::from_secs(1);
+
+const HELP : &str = "commands: join <name> [race], races, items, who, inventory, take <item>, enchant <item>, quit";
+
+/// A character and what it carries, which outlives its connections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Player {
+    pub race : String,
+    pub inventory : Vec<Thing>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    players : BTreeMap<String, Player>,
+}
+
+struct Session {
+    name : Option<String>,
+    stream : TcpStream,
+}
+
+enum Control {
+    Continue,
+    Quit,
+}
+
+/// Everything the connections share, behind one lock so that replies and
+/// broadcasts go out in the order things happened.
+struct Game {
+    roster : Roster,
+    rng : StdRng,
+    state_file : Option<PathBuf>,
+    players : BTreeMap<String, Player>,
+    sessions : HashMap<u64, Session>,
+}
+
+impl Game {
+    /// Writes `line` to session `id`, which hangs up on a client that can't take it.
+    fn send(&mut self, id : u64, line : &str) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            if writeln!(session.stream, "{}", line).is_err() {
+                let _ = session.stream.shutdown(Close::Both);
+            }
+        }
+    }
+
+    /// Writes `line` to every player in the game but the one of session `from`.
+    fn broadcast(&mut self, from : u64, line : &str) {
+        let others : Vec<u64> = self.sessions.iter()
+            .filter(|(id, session)| **id != from && session.name.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in others {
+            self.send(id, &format!("* {}", line));
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let state = SavedState { players : self.players.clone() };
+        let json = serde_json::to_vec_pretty(&state)?;
+        // a crash halfway through leaves the previous state intact
+        let partial = path.with_extension("partial");
+        fs::write(&partial, json)?;
+        fs::rename(partial, path)
+    }
+
+    fn saved(&mut self, id : u64) {
+        if let Err(e) = self.save() {
+            self.send(id, &format!("error: the game could not be saved: {}", e));
+        }
+    }
+
+    fn handle(&mut self, id : u64, line : &str) -> Control {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("").to_ascii_lowercase();
+        let argument = words.collect::<Vec<&str>>().join(" ");
+        let name = self.sessions.get(&id).and_then(|session| session.name.clone());
+        match (command.as_str(), name) {
+            ("", _) => {}
+            ("help", _) => self.send(id, HELP),
+            ("races", _) => {
+                let races : Vec<String> = self.roster.races.iter()
+                    .map(|race| format!("{} ({:.0}%)", race.name, race.competency * 100.0))
+                    .collect();
+                self.send(id, &format!("races: {}", races.join(", ")));
+            }
+            ("items", _) => {
+                let items : Vec<&str> = self.roster.items.iter().map(|item| item.name.as_str()).collect();
+                self.send(id, &format!("items: {}", items.join(", ")));
+            }
+            ("who", _) => {
+                let mut online : Vec<String> = self.sessions.values()
+                    .filter_map(|session| session.name.as_ref())
+                    .map(|name| format!("{} the {}", name, self.players[name].race))
+                    .collect();
+                online.sort();
+                self.send(id, &format!("online: {}", online.join(", ")));
+            }
+            ("quit", _) => {
+                self.send(id, "farewell");
+                return Control::Quit;
+            }
+            ("join", None) => self.join(id, &argument),
+            ("join", Some(name)) => self.send(id, &format!("error: you are already playing as {}", name)),
+            ("inventory" | "take" | "enchant", None) => self.send(id, "error: join first"),
+            ("inventory", Some(name)) => {
+                let inventory : Vec<String> = self.players[&name].inventory.iter().map(|thing| thing.to_string()).collect();
+                self.send(id, &format!("inventory: {}", inventory.join(", ")));
+            }
+            ("take", Some(name)) => match self.roster.thing(&argument) {
+                Some(thing) => {
+                    self.send(id, &format!("you take a {}", thing));
+                    self.broadcast(id, &format!("{} takes a {}", name, thing));
+                    self.players.get_mut(&name).unwrap().inventory.push(thing);
+                    self.saved(id);
+                }
+                None => self.send(id, &format!("error: there is no {:?} to take, see items", argument)),
+            },
+            ("enchant", Some(name)) => self.enchant(id, &name, &argument),
+            (other, _) => self.send(id, &format!("error: unknown command {:?}, see help", other)),
+        }
+        Control::Continue
+    }
+
+    fn join(&mut self, id : u64, argument : &str) {
+        let mut words = argument.split_whitespace();
+        let (name, race) = match (words.next(), words.next(), words.next()) {
+            (Some(name), race, None) => (name.to_string(), race),
+            _ => return self.send(id, "error: usage: join <name> [race]"),
+        };
+        if self.sessions.values().any(|session| session.name.as_ref() == Some(&name)) {
+            return self.send(id, &format!("error: {} is already playing", name));
+        }
+        let greeting = match (self.players.get(&name), race) {
+            (Some(player), _) => format!("welcome back, {} the {}", name, player.race),
+            (None, None) => return self.send(id, "error: new players pick a race, see races"),
+            (None, Some(race)) => {
+                let race = match self.roster.race(race) {
+                    Some(race) => race.name.clone(),
+                    None => return self.send(id, &format!("error: there is no race called {}, see races", race)),
+                };
+                // everyone starts out with the first item of the roster
+                let starter = Thing::Item(self.roster.items[0].clone());
+                self.players.insert(name.clone(), Player { race : race.clone(), inventory : vec![starter] });
+                self.saved(id);
+                format!("welcome, {} the {}", name, race)
+            }
+        };
+        self.sessions.get_mut(&id).unwrap().name = Some(name.clone());
+        self.send(id, &greeting);
+        let race = self.players[&name].race.clone();
+        self.broadcast(id, &format!("{} the {} joins", name, race));
+    }
+
+    fn enchant(&mut self, id : u64, name : &str, item : &str) {
+        let player = &self.players[name];
+        let enchanter = match self.roster.race(&player.race) {
+            Some(race) => race.clone(),
+            None => return self.send(id, &format!("error: the {} race left the roster", player.race)),
+        };
+        let slot = match player.inventory.iter().position(|thing| thing.to_string().eq_ignore_ascii_case(item)) {
+            Some(slot) => slot,
+            None => return self.send(id, &format!("error: you carry no {:?}, see inventory", item)),
+        };
+        let thing = &mut self.players.get_mut(name).unwrap().inventory[slot];
+        let mut enchantment = enchanter.enchant(thing, &mut self.rng);
+        // the others hear it from the player, not the race
+        enchantment.enchanter = name.to_string();
+        self.send(id, &enchantment.to_string());
+        self.broadcast(id, &enchantment.to_string());
+        self.saved(id);
+    }
+
+    /// Forgets session `id`, telling the others when a player leaves.
+    fn leave(&mut self, id : u64) {
+        if let Some(Session { name : Some(name), .. }) = self.sessions.remove(&id) {
+            self.broadcast(id, &format!("{} leaves", name));
+        }
+    }
+}
+
+/// A line-oriented TCP game in which every player enchants their own things
+/// and hears about everybody else's spells.
+pub struct GameServer {
+    listener : TcpListener,
+    game : Arc<Mutex<Game>>,
+    running : Arc<AtomicBool>,
+}
+
+impl GameServer {
+    pub fn bind<A : ToSocketAddrs>(addr : A, roster : Roster) -> io::Result<GameServer> {
+        let game = Game {
+            roster,
+            rng : rpg::rng(None),
+            state_file : None,
+            players : BTreeMap::new(),
+            sessions : HashMap::new(),
+        };
+        Ok(GameServer {
+            listener : TcpListener::bind(addr)?,
+            game : Arc::new(Mutex::new(game)),
+            running : Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// Keeps the players in `path`, starting from the ones already there.
+    pub fn state_file(self, path : impl Into<PathBuf>) -> Result<GameServer, Error> {
+        let path = path.into();
+        let state : SavedState = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| Error::parse(path.display().to_string(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SavedState::default(),
+            Err(e) => return Err(Error::io(format!("unable to read {}", path.display()), e)),
+        };
+        {
+            let mut game = self.game.lock().unwrap();
+            game.players = state.players;
+            game.state_file = Some(path);
+        }
+        Ok(self)
+    }
+
+    /// Makes spells come out the same way every run.
+    pub fn seed(self, seed : u64) -> GameServer {
+        self.game.lock().unwrap().rng = rpg::rng(Some(seed));
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> io::Result<Shutdown> {
+        Shutdown::new(&self.listener, self.running.clone())
+    }
+
+    /// Plays until `Shutdown::shutdown` is called, then hangs up on everyone.
+    pub fn run(self) -> io::Result<()> {
+        let mut players = Vec::new();
+        let mut next_id = 0;
+        for stream in self.listener.incoming() {
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => {
+                    self.running.store(false, Ordering::SeqCst);
+                    return Err(e);
+                }
+            };
+            let id = next_id;
+            next_id += 1;
+            let game = self.game.clone();
+            let running = self.running.clone();
+            players.retain(|player : &thread::JoinHandle<()>| !player.is_finished());
+            players.push(thread::spawn(move || {
+                // a broken connection only concerns its player
+                let _ = play(&game, &running, id, stream);
+                game.lock().unwrap().leave(id);
+            }));
+        }
+        for session in self.game.lock().unwrap().sessions.values() {
+            let _ = session.stream.shutdown(Close::Both);
+        }
+        for player in players {
+            player.join().expect("player thread panicked");
+        }
+        Ok(())
+    }
+}
+
+fn play(game : &Mutex<Game>, running : &AtomicBool, id : u64, stream : TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    {
+        let mut game = game.lock().unwrap();
+        // too late for the hang-up at the end of `run`
+        if !running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        game.sessions.insert(id, Session { name : None, stream });
+        game.send(id, "welcome to the enchanters' hall, join <name> [race] to play or help for more");
+    }
+    loop {
+        let mut line = String::new();
+        if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE_LEN {
+            game.lock().unwrap().send(id, "error: line too long");
+            return Ok(());
+        }
+        if let Control::Quit = game.lock().unwrap().handle(id, line.trim()) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Client {
+        reader : BufReader<TcpStream>,
+        stream : TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr : SocketAddr) -> Client {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut client = Client { reader : BufReader::new(stream.try_clone().unwrap()), stream };
+            assert!(client.line().starts_with("welcome to the enchanters' hall"));
+            client
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+
+        fn ask(&mut self, command : &str) -> String {
+            writeln!(self.stream, "{}", command).unwrap();
+            self.line()
+        }
+    }
+
+    fn start(state_file : Option<&PathBuf>) -> (SocketAddr, Shutdown, thread::JoinHandle<io::Result<()>>) {
+        let mut server = GameServer::bind("127.0.0.1:0", Roster::default()).unwrap().seed(7);
+        if let Some(path) = state_file {
+            server = server.state_file(path).unwrap();
+        }
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        (addr, shutdown, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn players_hear_each_other() {
+        let (addr, shutdown, server) = start(None);
+        let mut alice = Client::connect(addr);
+        let mut bob = Client::connect(addr);
+        assert_eq!(alice.ask("join alice elf"), "welcome, alice the Elf");
+        assert_eq!(bob.ask("join bob DWARF"), "welcome, bob the Dwarf");
+        assert_eq!(alice.line(), "* bob the Dwarf joins");
+        assert_eq!(alice.ask("who"), "online: alice the Elf, bob the Dwarf");
+
+        let spell = bob.ask("enchant sword");
+        assert!(spell.starts_with("bob mutters incoherently. The Sword "), "{}", spell);
+        assert_eq!(alice.line(), format!("* {}", spell));
+        assert_eq!(bob.ask("take ring"), "you take a Ring");
+        assert_eq!(alice.line(), "* bob takes a Ring");
+        let inventory = bob.ask("inventory");
+        assert!(inventory == "inventory: Sword, Ring" || inventory == "inventory: Trinket, Ring", "{}", inventory);
+
+        assert_eq!(bob.ask("quit"), "farewell");
+        assert_eq!(alice.line(), "* bob leaves");
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+        // the server hung up on alice
+        assert_eq!(alice.line(), "");
+    }
+
+    #[test]
+    fn turns_away_bad_commands() {
+        let (addr, shutdown, server) = start(None);
+        let mut carol = Client::connect(addr);
+        assert_eq!(carol.ask("enchant sword"), "error: join first");
+        assert_eq!(carol.ask("join carol"), "error: new players pick a race, see races");
+        assert_eq!(carol.ask("join carol orc"), "error: there is no race called orc, see races");
+        assert_eq!(carol.ask("races"), "races: Dwarf (50%), Elf (95%), Human (80%)");
+        assert_eq!(carol.ask("join carol human"), "welcome, carol the Human");
+        assert_eq!(carol.ask("join dave elf"), "error: you are already playing as carol");
+        assert_eq!(carol.ask("enchant axe"), "error: you carry no \"axe\", see inventory");
+        assert_eq!(carol.ask("take axe"), "error: there is no \"axe\" to take, see items");
+        assert_eq!(carol.ask("dance"), "error: unknown command \"dance\", see help");
+
+        let mut impostor = Client::connect(addr);
+        assert_eq!(impostor.ask("join carol"), "error: carol is already playing");
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn keeps_players_across_restarts() {
+        let path = std::env::temp_dir().join(format!("sample-game-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (addr, shutdown, server) = start(Some(&path));
+        let mut erin = Client::connect(addr);
+        assert_eq!(erin.ask("join erin human"), "welcome, erin the Human");
+        assert_eq!(erin.ask("take shield"), "you take a Shield");
+        erin.ask("enchant shield");
+        let inventory = erin.ask("inventory");
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+
+        let (addr, shutdown, server) = start(Some(&path));
+        let mut erin = Client::connect(addr);
+        assert_eq!(erin.ask("join erin"), "welcome back, erin the Human");
+        assert_eq!(erin.ask("inventory"), inventory);
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
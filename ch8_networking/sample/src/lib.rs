@@ -0,0 +1,4 @@
+pub mod error;
+pub mod game;
+pub mod rpg;
+pub mod server;
@@ -1,8 +1,6 @@
-use std::error::Error;
-use std::ops::Add;
-use reqwest;
+use libsample::error::Error;
 
-fn reqwest_test() -> Result<(),Box<dyn Error>>{
+fn reqwest_test() -> Result<(), Error> {
     let url = "http://www.rustinaction.com/";
     let mut response = reqwest::get(url)?;
     let content = response.text()?;
@@ -10,165 +8,104 @@ fn reqwest_test() -> Result<(),Box<dyn Error>>{
     Ok(())
 }
 
-use rand;
 use rand::seq::SliceRandom;
-use rand::Rng;
-
-#[derive(Debug)]
-struct Dwarf{}
-
-#[derive(Debug)]
-struct Elf{}
-
-#[derive(Debug)]
-struct Human{}
-
-#[derive(Debug)]
-enum Thing {
-    Sword,
-    Trinket,
-}
-
-trait Enchanter : std::fmt::Debug {
-    fn competency(&self) -> f64;
-    fn enchant(&self, thing : &mut Thing) {
-        let probability_of_success = self.competency();
-        let spell_is_successful = rand::thread_rng().gen_bool(probability_of_success);
-        print!("{:?} mutters incoherently. ", self);
-        if spell_is_successful {
-            println!("The {:?} glows brightly. ", thing);
-        } else {
-            println!("The {:?} fizzes, then turns into a worthless trinket. ", thing);
-            *thing = Thing::Trinket{};
-        }
-    }
-}
-impl Enchanter for Dwarf {
-    fn competency(&self) -> f64 {
-        0.5
-    }
-}
-
-impl Enchanter for Elf {
-    fn competency(&self) -> f64 {
-        0.95
-    }
-}
-
-impl Enchanter for Human {
-    fn competency(&self) -> f64 {
-        0.8
-    }
-}
+use libsample::rpg::Roster;
 
 fn rpg_test() {
-    let mut it = Thing::Sword;
-    let d = Dwarf{};
-    let e = Elf{};
-    let h = Human{};
-    let party : Vec<&dyn Enchanter> = vec![&d, &h, &e];
-    let spellcaster = party.choose(&mut rand::thread_rng()).unwrap();
-    spellcaster.enchant(&mut it);
+    let roster = Roster::default();
+    let mut rng = rand::thread_rng();
+    let mut it = roster.thing("Sword").unwrap();
+    let party = roster.enchanters();
+    let spellcaster = party.choose(&mut rng).unwrap();
+    println!("{}", spellcaster.enchant(&mut it, &mut rng));
 }
 
 use std::io::prelude::*;
 use std::net::TcpStream;
 
-fn tcp_test() -> std::io::Result<()> {
-    let host = "www.rustinaction.com:80";
-    let mut conn = TcpStream::connect(host)?;
-    conn.write_all(b"GET / HTTP/1.O")?;
-    conn.write_all(b"\r\n")?;
-    conn.write_all(b"Host: www.rustinaction.com")?;
-    conn.write_all(b"\r\n\r\n")?;
-    std::io::copy(&mut conn, &mut std::io::stdout())?;
+/// Sends a hand-written HTTP/1.0 GET to `host` and copies the whole response,
+/// head and body, to `out`.
+fn tcp_get<W : Write>(host : &str, path : &str, out : &mut W) -> Result<(), Error> {
+    let mut conn = TcpStream::connect(host)
+        .map_err(|e| Error::network(format!("connection to {}", host), e))?;
+    let sending = |e| Error::network(format!("request to {}", host), e);
+    write!(conn, "GET {} HTTP/1.0", path).map_err(sending)?;
+    conn.write_all(b"\r\n").map_err(sending)?;
+    write!(conn, "Host: {}", host).map_err(sending)?;
+    conn.write_all(b"\r\n\r\n").map_err(sending)?;
+    std::io::copy(&mut conn, out).map_err(|e| Error::network(format!("response from {}", host), e))?;
     Ok(())
 }
 
-use std::fs::File;
-use std::net::Ipv6Addr;
-use std::net::AddrParseError;
-
-#[derive(Debug)]
-enum UpstreamError {
-    IO(std::io::Error),
-    Parsing(AddrParseError),
-}
-
-impl std::fmt::Display for UpstreamError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
+fn tcp_test() -> Result<(), Error> {
+    tcp_get("www.rustinaction.com:80", "/", &mut std::io::stdout())
 }
 
-impl std::error::Error for UpstreamError {}
-
-impl From<std::io::Error> for UpstreamError {
-    fn from(value: std::io::Error) -> Self {
-        UpstreamError::IO(value)
-    }
-}
+use std::fs::File;
+use std::net::Ipv6Addr;
 
-impl From<std::net::AddrParseError> for UpstreamError {
-    fn from(value: std::net::AddrParseError) -> Self {
-        UpstreamError::Parsing(value)
-    }
-}
-fn error_test() -> Result<(), UpstreamError>{
-    // version 1 to use map_err
-    /*let _f = File::open("invisible.txt")
-        .map_err(UpstreamError::IO)?;
-    let _localhost = "::1".parse::<Ipv6Addr>()
-        .map_err(UpstreamError::Parsing)?;*/
-    let _f = File::open("invisible.txt")?;
+fn error_test() -> Result<(), Error> {
+    // an io::Error doesn't say which file it was about, so that goes in here,
+    // while the AddrParseError converts by itself
+    let _f = File::open("invisible.txt")
+        .map_err(|e| Error::io("unable to open invisible.txt", e))?;
     let _localhost = "::1".parse::<Ipv6Addr>()?;
     Ok(())
 }
 
-use rand::RngCore;
-use std::fmt;
-use std::fmt::Display;
-
-#[derive(Debug)]
-struct MacAddress([u8;6]);
-
-impl Display for MacAddress {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let octet = &self.0;
-        write!(
-            f,
-            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-            octet[0],octet[1], octet[2],
-            octet[3], octet[4], octet[5]
-        )
-    }
-}
-
-impl MacAddress {
-    fn new() -> MacAddress {
-        let mut octets : [u8;6] = [0;6];
-        rand::thread_rng().fill_bytes(&mut octets);
-        octets[0] |= 0b_0000_0011;
-        MacAddress {0:octets}
-    }
-    fn is_local(&self) -> bool {
-        (self.0[0] & 0b_0000_0010) == 0b_0000_0010
-    }
-    fn is_unicast(&self) -> bool {
-        (self.0[0] & 0b_0000_0001) == 0b_0000_0001
-    }
-}
+use libmget::ethernet::MacAddress;
 
 fn mac_test() {
     let mac = MacAddress::new();
     assert!(mac.is_local());
     assert!(mac.is_unicast());
     println!("mac: {}", mac);
+    let parsed : MacAddress = "00-50-56-c0-00-08".parse().unwrap();
+    assert!(parsed.is_universal());
+    assert!(parsed.is_unicast());
+    println!("parsed: {} ({})", parsed, parsed.vendor().unwrap_or("unknown vendor"));
 }
 fn main() {
-    //reqwest_test();
+    let fetched = reqwest_test();
     rpg_test();
-    tcp_test();
-    //error_test();
+    let fetched = fetched.and(tcp_test());
+    // invisible.txt isn't meant to exist, so this shows what the error looks like
+    if let Err(e) = error_test() {
+        eprintln!("error_test: {}", e);
+    }
     mac_test();
+    if let Err(e) = fetched {
+        e.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsample::server::Server;
+
+    #[test]
+    fn tcp_get_fetches_from_the_server() {
+        let root = std::env::temp_dir().join(format!("sample-tcp-get-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>rust in action</h1>\n").unwrap();
+        let server = Server::bind("127.0.0.1:0", &root).unwrap().threads(1);
+        let host = server.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle().unwrap();
+        let server = std::thread::spawn(move || server.run());
+
+        let mut response = Vec::new();
+        tcp_get(&host, "/", &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>rust in action</h1>\n"));
+
+        let mut response = Vec::new();
+        tcp_get(&host, "/missing.html", &mut response).unwrap();
+        assert!(String::from_utf8(response).unwrap().starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,310 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// The roster the samples play with unless given another one.
+pub static DEFAULT_ROSTER : &str = include_str!("../data/roster.toml");
+
+/// A random number generator that replays the same spells given the same
+/// `seed`, or a fresh one every run without.
+pub fn rng(seed : Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+    pub name : String,
+    /// How much harder than a plain sword this is to enchant, from 0 to 1.
+    #[serde(default)]
+    pub difficulty : f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Thing {
+    Item(Item),
+    Trinket,
+}
+
+impl Thing {
+    fn difficulty(&self) -> f64 {
+        match self {
+            Thing::Item(item) => item.difficulty,
+            Thing::Trinket => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Thing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Thing::Item(item) => write!(f, "{}", item.name),
+            Thing::Trinket => write!(f, "Trinket"),
+        }
+    }
+}
+
+/// What became of a spell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Enchantment {
+    pub enchanter : String,
+    pub thing : Thing,
+    pub success : bool,
+}
+
+impl fmt::Display for Enchantment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mutters incoherently. ", self.enchanter)?;
+        if self.success {
+            write!(f, "The {} glows brightly.", self.thing)
+        } else {
+            write!(f, "The {} fizzes, then turns into a worthless trinket.", self.thing)
+        }
+    }
+}
+
+pub trait Enchanter : fmt::Debug {
+    fn name(&self) -> &str;
+    fn competency(&self) -> f64;
+    fn enchant(&self, thing : &mut Thing, rng : &mut dyn RngCore) -> Enchantment {
+        let probability_of_success = (self.competency() * (1.0 - thing.difficulty())).clamp(0.0, 1.0);
+        let spell_is_successful = rng.gen_bool(probability_of_success);
+        let enchantment = Enchantment {
+            enchanter : self.name().to_string(),
+            thing : thing.clone(),
+            success : spell_is_successful,
+        };
+        if !spell_is_successful {
+            *thing = Thing::Trinket;
+        }
+        enchantment
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Race {
+    pub name : String,
+    pub competency : f64,
+}
+
+impl Enchanter for Race {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn competency(&self) -> f64 {
+        self.competency
+    }
+}
+
+/// The races and items of a game, read from TOML with `[[races]]` and
+/// `[[items]]` tables, or from JSON with `races` and `items` arrays.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Roster {
+    pub races : Vec<Race>,
+    pub items : Vec<Item>,
+}
+
+impl Default for Roster {
+    fn default() -> Self {
+        Roster::from_toml(DEFAULT_ROSTER).expect("the built-in roster is valid")
+    }
+}
+
+impl Roster {
+    pub fn from_toml(text : &str) -> Result<Roster, Error> {
+        let roster : Roster = toml::from_str(text).map_err(|e| Error::parse("the roster", e))?;
+        roster.check()
+    }
+
+    pub fn from_json(text : &str) -> Result<Roster, Error> {
+        let roster : Roster = serde_json::from_str(text).map_err(|e| Error::parse("the roster", e))?;
+        roster.check()
+    }
+
+    /// Reads a `.json` file as JSON and anything else as TOML.
+    pub fn from_file<P : AsRef<Path>>(path : P) -> Result<Roster, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::io(format!("unable to read {}", path.display()), e))?;
+        let roster = match path.extension() {
+            Some(extension) if extension == "json" => Roster::from_json(&text),
+            _ => Roster::from_toml(&text),
+        };
+        roster.map_err(|e| match e {
+            Error::Parse { source, .. } => Error::Parse { what : path.display().to_string(), source },
+            other => other,
+        })
+    }
+
+    fn check(self) -> Result<Roster, Error> {
+        let invalid = |message : String| Err(Error::parse("the roster", message));
+        if self.races.is_empty() || self.items.is_empty() {
+            return invalid("it needs at least one race and one item".to_string());
+        }
+        for race in &self.races {
+            if !(0.0..=1.0).contains(&race.competency) {
+                return invalid(format!("competency of {} is not between 0 and 1", race.name));
+            }
+            if self.races.iter().filter(|other| other.name.eq_ignore_ascii_case(&race.name)).count() > 1 {
+                return invalid(format!("race {} appears twice", race.name));
+            }
+        }
+        for item in &self.items {
+            if !(0.0..=1.0).contains(&item.difficulty) {
+                return invalid(format!("difficulty of {} is not between 0 and 1", item.name));
+            }
+            if self.items.iter().filter(|other| other.name.eq_ignore_ascii_case(&item.name)).count() > 1 {
+                return invalid(format!("item {} appears twice", item.name));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Every race as something that can cast spells.
+    pub fn enchanters(&self) -> Vec<Box<dyn Enchanter>> {
+        self.races.iter().map(|race| Box::new(race.clone()) as Box<dyn Enchanter>).collect()
+    }
+
+    pub fn race(&self, name : &str) -> Option<&Race> {
+        self.races.iter().find(|race| race.name.eq_ignore_ascii_case(name))
+    }
+
+    /// A fresh, unenchanted `name`.
+    pub fn thing(&self, name : &str) -> Option<Thing> {
+        self.items.iter().find(|item| item.name.eq_ignore_ascii_case(name)).cloned().map(Thing::Item)
+    }
+
+    /// Has every race enchant a fresh one of every item `trials` times.
+    pub fn simulate(&self, trials : u64, rng : &mut dyn RngCore) -> Vec<SuccessRate> {
+        let mut rates = Vec::new();
+        for enchanter in self.enchanters() {
+            for item in &self.items {
+                let successes = (0..trials)
+                    .filter(|_| enchanter.enchant(&mut Thing::Item(item.clone()), rng).success)
+                    .count() as u64;
+                rates.push(SuccessRate {
+                    race : enchanter.name().to_string(),
+                    item : item.name.clone(),
+                    successes,
+                    trials,
+                });
+            }
+        }
+        rates
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuccessRate {
+    pub race : String,
+    pub item : String,
+    pub successes : u64,
+    pub trials : u64,
+}
+
+impl SuccessRate {
+    pub fn rate(&self) -> f64 {
+        if self.trials == 0 {
+            return 0.0;
+        }
+        self.successes as f64 / self.trials as f64
+    }
+}
+
+impl fmt::Display for SuccessRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{:<10} {:<10} {:>6.2}% ({}/{})",
+            self.race, self.item, self.rate() * 100.0, self.successes, self.trials,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_default_roster() {
+        let roster = Roster::default();
+        let names : Vec<&str> = roster.races.iter().map(|race| race.name.as_str()).collect();
+        assert_eq!(names, ["Dwarf", "Elf", "Human"]);
+        assert_eq!(roster.race("elf").unwrap().competency, 0.95);
+        assert_eq!(roster.thing("sword"), Some(Thing::Item(Item { name : "Sword".to_string(), difficulty : 0.0 })));
+        assert_eq!(roster.thing("Axe"), None);
+        assert_eq!(roster.enchanters().len(), 3);
+    }
+
+    #[test]
+    fn reads_json_rosters() {
+        let roster = Roster::from_json(r#"{
+            "races": [{"name": "Gnome", "competency": 0.7}],
+            "items": [{"name": "Wand", "difficulty": 0.5}, {"name": "Hat"}]
+        }"#).unwrap();
+        assert_eq!(roster.race("Gnome").unwrap().competency, 0.7);
+        assert_eq!(roster.items[1].difficulty, 0.0);
+    }
+
+    #[test]
+    fn rejects_bad_rosters() {
+        let bad = [
+            "[[races]]\nname = \"Orc\"\ncompetency = 1.5\n[[items]]\nname = \"Club\"\n",
+            "[[races]]\nname = \"Orc\"\ncompetency = 0.5\n",
+            "[[races]]\nname = \"Orc\"\ncompetency = 0.5\n[[races]]\nname = \"orc\"\ncompetency = 0.1\n[[items]]\nname = \"Club\"\n",
+            "[[races]]\nname = \"Orc\"\n",
+        ];
+        for text in bad {
+            let e = Roster::from_toml(text).unwrap_err();
+            assert_eq!(e.exit_code(), 2, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn seeded_spells_replay() {
+        let roster = Roster::default();
+        let cast = |seed| {
+            let mut rng = rng(Some(seed));
+            roster.enchanters().iter()
+                .map(|enchanter| enchanter.enchant(&mut roster.thing("Ring").unwrap(), &mut rng).success)
+                .collect::<Vec<bool>>()
+        };
+        assert_eq!(cast(7), cast(7));
+        assert!((0..16).map(cast).any(|outcomes| outcomes != cast(7)));
+    }
+
+    #[test]
+    fn failures_leave_trinkets() {
+        let mut rng = rng(Some(1));
+        let hopeless = Race { name : "Troll".to_string(), competency : 0.0 };
+        let mut sword = Roster::default().thing("Sword").unwrap();
+        let enchantment = hopeless.enchant(&mut sword, &mut rng);
+        assert!(!enchantment.success);
+        assert_eq!(sword, Thing::Trinket);
+        assert_eq!(enchantment.to_string(), "Troll mutters incoherently. The Sword fizzes, then turns into a worthless trinket.");
+        let master = Race { name : "Wizard".to_string(), competency : 1.0 };
+        let mut sword = Roster::default().thing("Sword").unwrap();
+        assert!(master.enchant(&mut sword, &mut rng).success);
+        assert_eq!(sword.to_string(), "Sword");
+    }
+
+    #[test]
+    fn simulation_tracks_competency() {
+        let roster = Roster::default();
+        let rates = roster.simulate(4000, &mut rng(Some(42)));
+        assert_eq!(rates.len(), roster.races.len() * roster.items.len());
+        for rate in rates {
+            let race = roster.race(&rate.race).unwrap();
+            let item = roster.thing(&rate.item).unwrap();
+            let expected = race.competency * (1.0 - item.difficulty());
+            assert!((rate.rate() - expected).abs() < 0.03, "{} expected {:.3}", rate, expected);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use clap::{App, Arg, ArgMatches};
+use libsample::error::Error;
+use libsample::game::GameServer;
+use libsample::rpg::Roster;
+
+fn main() {
+    let app = App::new("rpg-server")
+        .about("Hosts a text game in which players enchant things, over telnet or nc")
+        .arg(Arg::with_name("bind")
+            .short("b")
+            .long("bind")
+            .takes_value(true)
+            .value_name("addr")
+            .default_value("127.0.0.1:4000")
+            .help("Address and port to listen on"))
+        .arg(Arg::with_name("state")
+            .short("s")
+            .long("state")
+            .takes_value(true)
+            .value_name("file")
+            .default_value("rpg-state.json")
+            .help("JSON file the players are kept in between runs"))
+        .arg(Arg::with_name("roster")
+            .short("r")
+            .long("roster")
+            .takes_value(true)
+            .value_name("file")
+            .help("TOML or JSON file of races and items instead of the built-in ones"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .help("Seeds the random number generator, so that spells repeat"))
+        .get_matches();
+
+    if let Err(e) = run(&app) {
+        e.exit();
+    }
+}
+
+fn run(app : &ArgMatches) -> Result<(), Error> {
+    let roster = match app.value_of("roster") {
+        Some(path) => Roster::from_file(path)?,
+        None => Roster::default(),
+    };
+    let bind = app.value_of("bind").unwrap();
+    let mut server = GameServer::bind(bind, roster)
+        .map_err(|e| Error::network(format!("listening on {}", bind), e))?
+        .state_file(app.value_of("state").unwrap())?;
+    if let Some(seed) = app.value_of("seed") {
+        server = server.seed(seed.parse().map_err(|e| Error::parse("<seed>", e))?);
+    }
+
+    let shutdown = server.shutdown_handle()
+        .map_err(|e| Error::io("unable to prepare for shutting down", e))?;
+    ctrlc::set_handler(move || {
+        eprintln!("shutting down");
+        shutdown.shutdown();
+    }).map_err(|e| Error::io("unable to handle Ctrl-C", std::io::Error::other(e)))?;
+    let addr = server.local_addr().map_err(|e| Error::network(format!("listening on {}", bind), e))?;
+    eprintln!("playing on {}", addr);
+    server.run().map_err(|e| Error::network(format!("accepting connections on {}", bind), e))?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use clap::{App, Arg, ArgMatches};
+use libsample::error::Error;
+use libsample::server::Server;
+
+fn main() {
+    let app = App::new("serve")
+        .about("Serves the files under a directory over HTTP/1.1")
+        .arg(Arg::with_name("root")
+            .default_value(".")
+            .help("Directory to serve"))
+        .arg(Arg::with_name("bind")
+            .short("b")
+            .long("bind")
+            .takes_value(true)
+            .value_name("addr")
+            .default_value("127.0.0.1:8080")
+            .help("Address and port to listen on"))
+        .arg(Arg::with_name("threads")
+            .short("t")
+            .long("threads")
+            .takes_value(true)
+            .default_value("4")
+            .help("Connections to serve at once"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Doesn't write an access log to stderr"))
+        .get_matches();
+
+    if let Err(e) = run(&app) {
+        e.exit();
+    }
+}
+
+fn run(app : &ArgMatches) -> Result<(), Error> {
+    let root = app.value_of("root").unwrap();
+    if !std::path::Path::new(root).is_dir() {
+        return Err(Error::Usage(format!("{} is not a directory", root)));
+    }
+    let threads : usize = app.value_of("threads").unwrap()
+        .parse()
+        .ok()
+        .filter(|threads| *threads > 0)
+        .ok_or_else(|| Error::Usage("<threads> must be a positive number".to_string()))?;
+    let bind = app.value_of("bind").unwrap();
+    let mut server = Server::bind(bind, root)
+        .map_err(|e| Error::network(format!("listening on {}", bind), e))?
+        .threads(threads);
+    if !app.is_present("quiet") {
+        server = server.access_log(std::io::stderr());
+    }
+
+    let shutdown = server.shutdown_handle()
+        .map_err(|e| Error::io("unable to prepare for shutting down", e))?;
+    ctrlc::set_handler(move || {
+        eprintln!("shutting down");
+        shutdown.shutdown();
+    }).map_err(|e| Error::io("unable to handle Ctrl-C", std::io::Error::other(e)))?;
+    let addr = server.local_addr().map_err(|e| Error::network(format!("listening on {}", bind), e))?;
+    eprintln!("serving {} on http://{}/", root, addr);
+    server.run().map_err(|e| Error::network(format!("accepting connections on {}", bind), e))?;
+    Ok(())
+}
@@ -0,0 +1,657 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::unbounded;
+
+/// How long a kept-alive connection may sit idle, and how long a client may
+/// take to send a request once it started one.
+const KEEP_ALIVE : Duration = Duration::from_secs(5);
+/// How often an idle worker looks up to see whether the server is shutting down.
+const IDLE_CHECK : Duration = Duration::from_millis(100);
+const MAX_HEAD_LEN : usize = 8192;
+
+enum Work {
+    Connection(TcpStream),
+    Finished,
+}
+
+type AccessLog = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// A threaded HTTP/1.1 server for the files under a directory.
+pub struct Server {
+    listener : TcpListener,
+    root : PathBuf,
+    threads : usize,
+    access_log : Option<AccessLog>,
+    running : Arc<AtomicBool>,
+}
+
+/// Stops a running server: it stops accepting, lets the workers finish the
+/// requests they are in the middle of and then returns from `run`.
+#[derive(Clone)]
+pub struct Shutdown {
+    running : Arc<AtomicBool>,
+    addr : SocketAddr,
+}
+
+impl Shutdown {
+    /// A handle for whatever accepts on `listener` for as long as `running` holds.
+    pub(crate) fn new(listener : &TcpListener, running : Arc<AtomicBool>) -> io::Result<Shutdown> {
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(Shutdown { running, addr })
+    }
+
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        // wakes the accept loop up so that it sees the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl Server {
+    pub fn bind<A : ToSocketAddrs>(addr : A, root : impl Into<PathBuf>) -> io::Result<Server> {
+        Ok(Server {
+            listener : TcpListener::bind(addr)?,
+            root : root.into(),
+            threads : 4,
+            access_log : None,
+            running : Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    pub fn threads(mut self, threads : usize) -> Server {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Writes a line in the Common Log Format to `log` for every response.
+    pub fn access_log<W : Write + Send + 'static>(mut self, log : W) -> Server {
+        self.access_log = Some(Arc::new(Mutex::new(Box::new(log))));
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> io::Result<Shutdown> {
+        Shutdown::new(&self.listener, self.running.clone())
+    }
+
+    /// Serves until `Shutdown::shutdown` is called.
+    pub fn run(self) -> io::Result<()> {
+        let (todo_tx, todo_rx) = unbounded();
+        let root = Arc::new(self.root);
+        let mut workers = Vec::with_capacity(self.threads);
+        for _ in 0..self.threads {
+            let todo = todo_rx.clone();
+            let root = root.clone();
+            let access_log = self.access_log.clone();
+            let running = self.running.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    match todo.recv() {
+                        Err(_) | Ok(Work::Finished) => break,
+                        Ok(Work::Connection(stream)) => {
+                            let worker = Worker { root : &root, access_log : access_log.as_ref(), running : &running };
+                            // a broken connection only concerns its client
+                            let _ = worker.serve(stream);
+                        }
+                    }
+                }
+            }));
+        }
+
+        for stream in self.listener.incoming() {
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => todo_tx.send(Work::Connection(stream)).unwrap(),
+                // the client gave up before we got to it
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => {
+                    self.running.store(false, Ordering::SeqCst);
+                    return Err(e);
+                }
+            }
+        }
+        for _ in 0..self.threads {
+            todo_tx.send(Work::Finished).unwrap();
+        }
+        for worker in workers {
+            worker.join().expect("worker thread panicked");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Get,
+    Head,
+}
+
+struct Request {
+    method : Method,
+    target : String,
+    line : String,
+    keep_alive : bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum RequestError {
+    /// The client closed the connection or went quiet between requests.
+    Closed,
+    Malformed,
+    NotImplemented,
+    MethodNotAllowed(String),
+    Version(String),
+}
+
+struct Response {
+    status : u16,
+    headers : Vec<(&'static str, String)>,
+    body : Body,
+}
+
+enum Body {
+    Empty,
+    Text(&'static str),
+    File(File, u64),
+}
+
+impl Response {
+    fn error(status : u16) -> Response {
+        let body = match status {
+            400 => "400 Bad Request\n",
+            403 => "403 Forbidden\n",
+            404 => "404 Not Found\n",
+            405 => "405 Method Not Allowed\n",
+            501 => "501 Not Implemented\n",
+            505 => "505 HTTP Version Not Supported\n",
+            _ => "500 Internal Server Error\n",
+        };
+        Response {
+            status,
+            headers : vec![("Content-Type", "text/plain; charset=utf-8".to_string())],
+            body : Body::Text(body),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Text(text) => text.len() as u64,
+            Body::File(_, len) => *len,
+        }
+    }
+}
+
+fn reason(status : u16) -> &'static str {
+    match status {
+        200 => "OK",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+fn content_type(path : &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") | Some("md") | Some("rs") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Decodes the `%XX` escapes of a request path.
+fn percent_decode(path : &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The file under `root` a request target stands for, or the status to
+/// answer with instead. Targets can't climb out of `root`.
+fn resolve(root : &Path, target : &str) -> Result<PathBuf, u16> {
+    let path = target.split(['?', '#']).next().unwrap();
+    if !path.starts_with('/') {
+        return Err(400);
+    }
+    let path = percent_decode(path).ok_or(400u16)?;
+    if path.contains('\0') {
+        return Err(400);
+    }
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(&path).components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir | Component::Prefix(_) => return Err(403),
+        }
+    }
+    Ok(resolved)
+}
+
+/// The date of `time` as the Common Log Format writes it, in UTC.
+fn log_date(time : SystemTime) -> String {
+    const MONTHS : [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days, for days since 1970-01-01
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day, MONTHS[month as usize - 1], year, secs / 3600, secs % 3600 / 60, secs % 60,
+    )
+}
+
+struct Worker<'a> {
+    root : &'a Path,
+    access_log : Option<&'a AccessLog>,
+    running : &'a AtomicBool,
+}
+
+impl<'a> Worker<'a> {
+    /// Answers the requests on `stream` until the client or the server is done.
+    fn serve(&self, stream : TcpStream) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let (line, response, keep_alive, method) = match self.read_request(&mut reader) {
+                Ok(request) => {
+                    let response = self.respond(&request);
+                    (request.line, response, request.keep_alive, request.method)
+                }
+                Err(RequestError::Closed) => return Ok(()),
+                Err(RequestError::MethodNotAllowed(line)) => {
+                    let mut response = Response::error(405);
+                    response.headers.push(("Allow", "GET, HEAD".to_string()));
+                    (line, response, false, Method::Get)
+                }
+                Err(RequestError::Version(line)) => (line, Response::error(505), false, Method::Get),
+                Err(RequestError::NotImplemented) => (String::new(), Response::error(501), false, Method::Get),
+                Err(RequestError::Malformed) => (String::new(), Response::error(400), false, Method::Get),
+            };
+            let keep_alive = keep_alive && self.running.load(Ordering::SeqCst);
+            let sent = self.write_response(&mut writer, response, method, keep_alive)?;
+            self.log(peer, &line, sent);
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next request on a kept-alive connection, giving up when
+    /// the client stays quiet or the server shuts down.
+    fn wait_for_request(&self, reader : &mut BufReader<TcpStream>) -> Result<(), RequestError> {
+        let idle_since = Instant::now();
+        reader.get_ref().set_read_timeout(Some(IDLE_CHECK)).map_err(|_| RequestError::Closed)?;
+        loop {
+            match reader.fill_buf() {
+                Ok([]) => return Err(RequestError::Closed),
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    if !self.running.load(Ordering::SeqCst) || idle_since.elapsed() > KEEP_ALIVE {
+                        return Err(RequestError::Closed);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return Err(RequestError::Closed),
+            }
+        }
+        reader.get_ref().set_read_timeout(Some(KEEP_ALIVE)).map_err(|_| RequestError::Closed)
+    }
+
+    fn read_request(&self, reader : &mut BufReader<TcpStream>) -> Result<Request, RequestError> {
+        self.wait_for_request(reader)?;
+        let mut head_len = 0;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            let read = reader.by_ref().take((MAX_HEAD_LEN - head_len) as u64)
+                .read_until(b'\n', &mut line)
+                .map_err(|_| RequestError::Malformed)?;
+            // also covers heads longer than MAX_HEAD_LEN, cut off mid-line
+            if read == 0 || !line.ends_with(b"\n") {
+                return Err(RequestError::Malformed);
+            }
+            head_len += read;
+            let line = String::from_utf8(line).map_err(|_| RequestError::Malformed)?;
+            let line = line.trim_end_matches(['\r', '\n']).to_string();
+            if line.is_empty() {
+                // tolerates blank lines ahead of the request line
+                if lines.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            lines.push(line);
+        }
+
+        let request_line = lines.remove(0);
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if !method.is_empty() && !target.is_empty() => (method, target, version),
+            _ => return Err(RequestError::Malformed),
+        };
+        let http_1_1 = match version {
+            "HTTP/1.1" => true,
+            "HTTP/1.0" => false,
+            version if version.starts_with("HTTP/") => return Err(RequestError::Version(request_line)),
+            _ => return Err(RequestError::Malformed),
+        };
+
+        let mut keep_alive = http_1_1;
+        let mut content_length = 0;
+        for line in &lines {
+            let (name, value) = line.split_once(':').ok_or(RequestError::Malformed)?;
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "connection" => {
+                    let options = value.to_ascii_lowercase();
+                    if options.split(',').any(|option| option.trim() == "close") {
+                        keep_alive = false;
+                    } else if options.split(',').any(|option| option.trim() == "keep-alive") {
+                        keep_alive = true;
+                    }
+                }
+                "content-length" => content_length = value.parse().map_err(|_| RequestError::Malformed)?,
+                "transfer-encoding" => return Err(RequestError::NotImplemented),
+                _ => {}
+            }
+        }
+        // nothing we serve takes a body, but the next request starts after it
+        let skipped = io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())
+            .map_err(|_| RequestError::Malformed)?;
+        if skipped != content_length {
+            return Err(RequestError::Malformed);
+        }
+
+        let method = match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            _ => return Err(RequestError::MethodNotAllowed(request_line)),
+        };
+        Ok(Request { method, target : target.to_string(), line : request_line, keep_alive })
+    }
+
+    fn respond(&self, request : &Request) -> Response {
+        let mut path = match resolve(self.root, &request.target) {
+            Ok(path) => path,
+            Err(status) => return Response::error(status),
+        };
+        if path.is_dir() {
+            let target = request.target.split(['?', '#']).next().unwrap();
+            if !target.ends_with('/') {
+                // relative links in the index resolve against the directory
+                return Response {
+                    status : 301,
+                    headers : vec![("Location", format!("{}/", target))],
+                    body : Body::Empty,
+                };
+            }
+            path.push("index.html");
+        }
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Response::error(403),
+            Err(_) => return Response::error(404),
+        };
+        match file.metadata() {
+            Ok(metadata) if metadata.is_file() => Response {
+                status : 200,
+                headers : vec![("Content-Type", content_type(&path).to_string())],
+                body : Body::File(file, metadata.len()),
+            },
+            _ => Response::error(404),
+        }
+    }
+
+    /// Sends `response` and returns how many body bytes went out.
+    fn write_response(&self, writer : &mut TcpStream, response : Response, method : Method, keep_alive : bool) -> io::Result<(u16, u64)> {
+        let len = response.len();
+        let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", len));
+        head.push_str(if keep_alive { "Connection: keep-alive\r\n" } else { "Connection: close\r\n" });
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        let sent = match (method, response.body) {
+            (Method::Head, _) | (_, Body::Empty) => 0,
+            (Method::Get, Body::Text(text)) => {
+                writer.write_all(text.as_bytes())?;
+                len
+            }
+            (Method::Get, Body::File(file, _)) => io::copy(&mut file.take(len), writer)?,
+        };
+        writer.flush()?;
+        Ok((response.status, sent))
+    }
+
+    fn log(&self, peer : SocketAddr, request_line : &str, (status, sent) : (u16, u64)) {
+        if let Some(log) = self.access_log {
+            let mut log = log.lock().unwrap();
+            let _ = writeln!(
+                log,
+                "{} - - [{}] \"{}\" {} {}",
+                peer.ip(), log_date(SystemTime::now()), request_line.escape_default(), status, sent,
+            );
+            let _ = log.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn root(name : &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("sample-serve-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>hello</h1>\n").unwrap();
+        fs::write(root.join("notes.txt"), "one\ntwo\n").unwrap();
+        fs::write(root.join("docs").join("index.html"), "<p>docs</p>\n").unwrap();
+        root
+    }
+
+    type Log = Arc<Mutex<Vec<u8>>>;
+
+    fn start(name : &str) -> (SocketAddr, Shutdown, Log, thread::JoinHandle<io::Result<()>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::bind("127.0.0.1:0", root(name)).unwrap()
+            .threads(2)
+            .access_log(Shared(log.clone()));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        (addr, shutdown, log, thread::spawn(move || server.run()))
+    }
+
+    /// Reads one response off `stream`, its head and then Content-Length bytes.
+    fn response(stream : &mut BufReader<TcpStream>, head_only : bool) -> (String, String) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            head.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        let len = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; if head_only { 0 } else { len }];
+        stream.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn get(addr : SocketAddr, request : &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        response(&mut BufReader::new(stream), request.starts_with("HEAD"))
+    }
+
+    #[test]
+    fn serves_files() {
+        let (addr, shutdown, log, server) = start("files");
+        let (head, body) = get(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!(body, "<h1>hello</h1>\n");
+
+        let (head, body) = get(addr, "GET /notes.txt?x=1 HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        assert!(head.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(head.contains("Content-Length: 8\r\n"));
+        assert_eq!(body, "one\ntwo\n");
+
+        let (head, body) = get(addr, "HEAD /notes.txt HTTP/1.0\r\n\r\n");
+        assert!(head.contains("Content-Length: 8\r\n"));
+        assert_eq!(body, "");
+
+        let (head, _) = get(addr, "GET /docs HTTP/1.0\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(head.contains("Location: /docs/\r\n"));
+        let (_, body) = get(addr, "GET /d%6fcs/ HTTP/1.0\r\n\r\n");
+        assert_eq!(body, "<p>docs</p>\n");
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        let lines : Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|line| line.starts_with("127.0.0.1 - - [")));
+        // two workers log in whichever order they finish
+        assert!(lines.iter().any(|line| line.ends_with(" +0000] \"GET / HTTP/1.0\" 200 15")));
+        assert!(lines.iter().any(|line| line.ends_with("\"HEAD /notes.txt HTTP/1.0\" 200 0")));
+    }
+
+    #[test]
+    fn refuses_bad_requests() {
+        let (addr, shutdown, _, server) = start("errors");
+        let status = |request : &str| get(addr, request).0.lines().next().unwrap().to_string();
+        assert_eq!(status("GET /missing HTTP/1.0\r\n\r\n"), "HTTP/1.1 404 Not Found");
+        assert_eq!(status("GET /../etc/passwd HTTP/1.0\r\n\r\n"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(status("GET /docs/%2e%2e/%2e%2e/x HTTP/1.0\r\n\r\n"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(status("GET /%zz HTTP/1.0\r\n\r\n"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(status("what\r\n\r\n"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(status("GET / HTTP/2.0\r\n\r\n"), "HTTP/1.1 505 HTTP Version Not Supported");
+        let (head, _) = get(addr, "DELETE / HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(head.contains("Allow: GET, HEAD\r\n"));
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn keeps_connections_alive() {
+        let (addr, shutdown, _, server) = start("keep-alive");
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        // the second request comes along with a body to skip
+        stream.get_mut().write_all(b"GET /notes.txt HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let (head, body) = response(&mut stream, false);
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        assert_eq!(body, "one\ntwo\n");
+        stream.get_mut().write_all(b"GET / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /docs/ HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let (_, body) = response(&mut stream, false);
+        assert_eq!(body, "<h1>hello</h1>\n");
+        let (head, body) = response(&mut stream, false);
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!(body, "<p>docs</p>\n");
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // an idle kept-alive connection doesn't hold the shutdown up for long
+        let mut idle = BufReader::new(TcpStream::connect(addr).unwrap());
+        idle.get_mut().write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let (head, _) = response(&mut idle, false);
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        let started = Instant::now();
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+        assert!(started.elapsed() < KEEP_ALIVE);
+        let mut rest = Vec::new();
+        idle.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn formats_log_dates() {
+        assert_eq!(log_date(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(log_date(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3723)), "29/Feb/2000:01:02:03 +0000");
+        assert_eq!(log_date(UNIX_EPOCH + Duration::from_secs(1_792_368_000)), "19/Oct/2026:00:00:00 +0000");
+    }
+}
@@ -0,0 +1,311 @@
+//! The clock filter, selection, cluster and combine algorithms of RFC 5905,
+//! run once over a burst of samples rather than continuously.
+
+use chrono::{DateTime, Utc};
+
+use crate::ntp::{seconds, NTPResult, PHI, PRECISION};
+
+/// Samples the clock filter keeps per server.
+pub const FILTER_SIZE : usize = 8;
+/// Servers further than this from a primary clock, in seconds, are not used.
+const MAX_DISTANCE : f64 = 1.5;
+/// The least round trip delay a root distance assumes, in seconds.
+const MIN_DISPERSION : f64 = 0.01;
+/// The cluster algorithm stops discarding outliers at this many survivors.
+const MIN_SURVIVORS : usize = 3;
+
+/// What the clock filter makes of the samples from one server. All times
+/// are in seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub server : String,
+    pub stratum : u8,
+    pub reference : String,
+    pub offset : f64,
+    pub delay : f64,
+    pub dispersion : f64,
+    pub jitter : f64,
+    pub root_delay : f64,
+    pub root_dispersion : f64,
+}
+
+impl Peer {
+    /// Runs the clock filter over the newest `FILTER_SIZE` samples, aged as
+    /// of `now`. The sample with the least delay is trusted most, as queueing
+    /// only ever adds to the delay and to the error of the offset.
+    pub fn filter(server : &str, samples : &[NTPResult], now : DateTime<Utc>) -> Option<Peer> {
+        let newest = &samples[samples.len().saturating_sub(FILTER_SIZE)..];
+        let mut stages : Vec<(&NTPResult, f64)> = newest.iter()
+            .map(|sample| (sample, sample.dispersion() + PHI * seconds(now - sample.t4)))
+            .collect();
+        stages.sort_by(|a, b| a.0.delay().total_cmp(&b.0.delay()));
+        let (best, _) = *stages.first()?;
+
+        let dispersion = stages.iter()
+            .enumerate()
+            .map(|(i, (_, dispersion))| dispersion / 2_f64.powi(i as i32 + 1))
+            .sum();
+        let jitter = if stages.len() > 1 {
+            let squares : f64 = stages[1..].iter()
+                .map(|(sample, _)| (sample.offset() - best.offset()).powi(2))
+                .sum();
+            (squares / (stages.len() - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Some(Peer {
+            server : server.to_string(),
+            stratum : newest[newest.len() - 1].stratum,
+            reference : newest[newest.len() - 1].reference.clone(),
+            offset : best.offset(),
+            delay : best.delay(),
+            dispersion,
+            jitter : jitter.max(PRECISION),
+            root_delay : best.root_delay,
+            root_dispersion : best.root_dispersion,
+        })
+    }
+
+    /// The root distance: how far off from the primary clock at the root of
+    /// the server's tree our reading could be.
+    pub fn distance(&self) -> f64 {
+        (self.root_delay + self.delay).max(MIN_DISPERSION) / 2.0
+            + self.root_dispersion + self.dispersion + self.jitter
+    }
+}
+
+/// The result of checking the local clock against a set of peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    /// How far the peers think the local clock is behind, in seconds.
+    pub offset : f64,
+    pub jitter : f64,
+    /// Indices of the peers the offset was combined from, best first.
+    pub survivors : Vec<usize>,
+    /// Indices of the peers the intersection algorithm found correct,
+    /// including the outliers the cluster algorithm dropped.
+    pub truechimers : Vec<usize>,
+}
+
+impl Estimate {
+    /// The peer that all others are compared with.
+    pub fn system_peer(&self) -> usize {
+        self.survivors[0]
+    }
+}
+
+/// Selects the peers that agree on the time, discards the outliers among
+/// them and combines the rest into a single offset. `None` when no majority
+/// of peers agree.
+pub fn synchronize(peers : &[Peer]) -> Option<Estimate> {
+    let candidates : Vec<usize> = (0..peers.len())
+        .filter(|&i| peers[i].distance() < MAX_DISTANCE)
+        .collect();
+    let (low, high) = intersect(peers, &candidates)?;
+    let mut truechimers : Vec<usize> = candidates.into_iter()
+        .filter(|&i| (low..=high).contains(&peers[i].offset))
+        .collect();
+    let metric = |i : usize| MAX_DISTANCE * peers[i].stratum as f64 + peers[i].distance();
+    truechimers.sort_by(|&a, &b| metric(a).total_cmp(&metric(b)));
+    let survivors = cluster(peers, truechimers.clone());
+    let (offset, jitter) = combine(peers, &survivors);
+    Some(Estimate { offset, jitter, survivors, truechimers })
+}
+
+/// Marzullo's algorithm as refined by RFC 5905: the smallest interval that
+/// lies within the correctness intervals, `offset ± distance`, of the most
+/// candidates, as long as those are a majority.
+pub fn intersect(peers : &[Peer], candidates : &[usize]) -> Option<(f64, f64)> {
+    let n = candidates.len();
+    let mut edges = Vec::with_capacity(3 * n);
+    for &i in candidates {
+        let peer = &peers[i];
+        edges.push((peer.offset - peer.distance(), 1));
+        edges.push((peer.offset, 0));
+        edges.push((peer.offset + peer.distance(), -1));
+    }
+    // Lower edges sort before upper ones at the same point, so that
+    // intervals which only touch still count as overlapping.
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let mut allow = 0;
+    while 2 * allow < n {
+        let needed = (n - allow) as i32;
+        let mut found = 0;
+
+        let mut low = None;
+        let mut chime = 0;
+        for &(edge, kind) in &edges {
+            chime += kind;
+            if chime >= needed {
+                low = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        let mut high = None;
+        chime = 0;
+        for &(edge, kind) in edges.iter().rev() {
+            chime -= kind;
+            if chime >= needed {
+                high = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        if let (Some(low), Some(high)) = (low, high) {
+            if found <= allow && low < high {
+                return Some((low, high));
+            }
+        }
+        allow += 1;
+    }
+    None
+}
+
+/// Repeatedly drops the survivor whose offset is furthest from the others,
+/// until that would leave too few or the spread among the survivors is
+/// already no worse than the jitter of the best of them.
+fn cluster(peers : &[Peer], mut survivors : Vec<usize>) -> Vec<usize> {
+    while survivors.len() > MIN_SURVIVORS {
+        let selection_jitter = |i : usize| {
+            let squares : f64 = survivors.iter()
+                .map(|&j| (peers[j].offset - peers[i].offset).powi(2))
+                .sum();
+            (squares / (survivors.len() - 1) as f64).sqrt()
+        };
+        let (worst, max_jitter) = survivors.iter()
+            .enumerate()
+            .map(|(position, &i)| (position, selection_jitter(i)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let min_jitter = survivors.iter()
+            .map(|&i| peers[i].jitter)
+            .fold(f64::INFINITY, f64::min);
+        if max_jitter < min_jitter {
+            break;
+        }
+        survivors.remove(worst);
+    }
+    survivors
+}
+
+/// Averages the offsets of the survivors, weighted by the inverse of their
+/// root distances.
+fn combine(peers : &[Peer], survivors : &[usize]) -> (f64, f64) {
+    let system_peer = &peers[survivors[0]];
+    let mut weights = 0.0;
+    let mut offset = 0.0;
+    let mut spread = 0.0;
+    for &i in survivors {
+        let weight = 1.0 / peers[i].distance();
+        weights += weight;
+        offset += peers[i].offset * weight;
+        spread += (peers[i].offset - system_peer.offset).powi(2) * weight;
+    }
+    let selection_jitter = (spread / weights).sqrt();
+    (offset / weights, (system_peer.jitter.powi(2) + selection_jitter.powi(2)).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration as ChronoDuration, TimeZone};
+
+    fn sample(offset_ms : i64, delay_ms : i64) -> NTPResult {
+        let ms = ChronoDuration::milliseconds;
+        let t1 = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        NTPResult {
+            t1,
+            t2 : t1 + ms(offset_ms + delay_ms / 2),
+            t3 : t1 + ms(offset_ms + delay_ms / 2),
+            t4 : t1 + ms(delay_ms),
+            stratum : 2,
+            precision : 2_f64.powi(-20),
+            root_delay : 0.01,
+            root_dispersion : 0.01,
+            reference : "192.0.2.1".to_string(),
+        }
+    }
+
+    fn peer(server : &str, offset : f64) -> Peer {
+        Peer {
+            server : server.to_string(),
+            stratum : 1,
+            reference : "GPS".to_string(),
+            offset,
+            delay : 0.02,
+            dispersion : 0.001,
+            jitter : 0.001,
+            root_delay : 0.0,
+            root_dispersion : 0.0,
+        }
+    }
+
+    #[test]
+    fn filter_trusts_the_shortest_round_trip() {
+        let samples = [sample(40, 80), sample(10, 20), sample(25, 50), sample(12, 24)];
+        let peer = Peer::filter("ntp", &samples, samples[3].t4).unwrap();
+        assert!((peer.offset - 0.010).abs() < 1e-9);
+        assert!((peer.delay - 0.020).abs() < 1e-9);
+        assert_eq!(peer.stratum, 2);
+        // The offsets of the other samples differ from the best by 2, 15 and 30 ms.
+        let expected = ((0.002_f64.powi(2) + 0.015_f64.powi(2) + 0.030_f64.powi(2)) / 3.0).sqrt();
+        assert!((peer.jitter - expected).abs() < 1e-9);
+        assert!(peer.dispersion > 0.0 && peer.dispersion < 1e-3);
+        assert!(Peer::filter("ntp", &[], Utc::now()).is_none());
+    }
+
+    #[test]
+    fn filter_keeps_the_newest_samples() {
+        let mut samples = vec![sample(0, 2)];
+        samples.extend((0..FILTER_SIZE).map(|_| sample(50, 30)));
+        let peer = Peer::filter("ntp", &samples, Utc::now()).unwrap();
+        assert!((peer.offset - 0.050).abs() < 1e-9);
+        assert_eq!(peer.jitter, PRECISION);
+    }
+
+    #[test]
+    fn falsetickers_are_left_out() {
+        let peers = [peer("a", 0.010), peer("b", 0.012), peer("c", 0.011), peer("d", 5.0)];
+        let (low, high) = intersect(&peers, &[0, 1, 2, 3]).unwrap();
+        assert!(low <= 0.010 && high >= 0.012, "{} {}", low, high);
+        assert!(high < 5.0 - peers[3].distance());
+        let estimate = synchronize(&peers).unwrap();
+        assert_eq!(estimate.truechimers.len(), 3);
+        assert!(!estimate.truechimers.contains(&3));
+        assert!((estimate.offset - 0.011).abs() < 1e-3);
+    }
+
+    #[test]
+    fn no_majority_no_time() {
+        let peers = [peer("a", 0.0), peer("b", 1.0)];
+        assert!(intersect(&peers, &[0, 1]).is_none());
+        assert!(synchronize(&peers).is_none());
+        assert!(synchronize(&[]).is_none());
+        let alone = synchronize(&peers[..1]).unwrap();
+        assert_eq!(alone.system_peer(), 0);
+        assert_eq!(alone.offset, 0.0);
+    }
+
+    #[test]
+    fn cluster_drops_outliers() {
+        let mut peers = vec![peer("a", 0.010), peer("b", 0.011), peer("c", 0.012), peer("d", 0.030)];
+        for peer in &mut peers {
+            peer.root_dispersion = 0.05;
+        }
+        let estimate = synchronize(&peers).unwrap();
+        assert_eq!(estimate.truechimers.len(), 4);
+        assert_eq!(estimate.survivors.len(), 3);
+        assert!(!estimate.survivors.contains(&3));
+        assert!((estimate.offset - 0.011).abs() < 1e-6);
+        assert!(estimate.jitter >= 0.001);
+    }
+}
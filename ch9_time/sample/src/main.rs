@@ -2,166 +2,90 @@
 use kernel32;
 #[cfg(windows)]
 use winapi;
-#[cfg(not(windows))]
-use libc;
 
-use byteorder::{BigEndian, ReadBytesExt};
 use chrono::{Local,Utc};
-use chrono::{DateTime,TimeZone, Timelike, Duration as ChronoDuration};
-use clap::{App, Arg};
-use std::arch::aarch64::ST;
+use chrono::{DateTime,TimeZone, Duration as ChronoDuration};
+use clap::{App, Arg, ArgMatches};
+use std::fmt;
+use std::fs;
 use std::mem::zeroed;
-use std::net::UdpSocket;
-use std::time::Duration;
 
-const NTP_MESSAGE_LENGTH : usize = 48;
-const NTP_TO_UNIX_SECONDS : i64 = 2_208_988_800;
-const LOCAL_ADDR : &'static str = "0.0.0.0:12300";
+mod filter;
+mod ntp;
+mod server;
 
-#[derive(Default, Debug, Copy, Clone)]
-struct NTPTimestamp {
-    seconds : u32,
-    fraction : u32,
-}
-
-struct NTPMessage {
-    data : [u8;NTP_MESSAGE_LENGTH],
-}
+use filter::Peer;
+use server::Server;
 
-#[derive(Debug)]
-struct NTPResult {
-    t1 : DateTime<Utc>,
-    t2 : DateTime<Utc>,
-    t3 : DateTime<Utc>,
-    t4 : DateTime<Utc>,
-}
-
-impl NTPResult {
-    fn offset(&self) -> i64 {
-        let duration = (self.t2 - self.t1) + (self.t4 - self.t3);
-        duration.num_milliseconds() / 2
-    }
+const DEFAULT_SERVERS : [&str;5] = [
+    "time.nist.gov",
+    "time.apple.com",
+    "time.euro.apple.com",
+    "time.google.com",
+    "time2.google.com",
+];
 
-    fn delay(&self) -> i64 {
-        let duration = (self.t4 - self.t1) - (self.t3 - self.t2);
-        duration.num_milliseconds()
-    }
-}
-
-impl From<NTPTimestamp> for DateTime<Utc> {
-    fn from(value: NTPTimestamp) -> Self {
-        let secs = value.seconds as i64 - NTP_TO_UNIX_SECONDS;
-        let mut nanos = value.fraction as f64;
-        nanos *= 1e9;
-        nanos /= 2_f64.powi(32);
-        Utc.timestamp_opt(secs, nanos as u32).unwrap()
-    }
-}
-
-impl From<DateTime<Utc>> for NTPTimestamp {
-    fn from(value: DateTime<Utc>) -> Self {
-        let secs = value.timestamp() + NTP_TO_UNIX_SECONDS;
-        let mut fraction = value.nanosecond() as f64;
-        fraction *= 2_f64.powi(32);
-        fraction /= 1e9;
-        NTPTimestamp { seconds: secs as u32, fraction: fraction as u32 }
-    }
-}
-
-impl NTPMessage {
-    fn new() -> Self {
-        NTPMessage { data: [0;NTP_MESSAGE_LENGTH] }
-    }
-    fn client() -> Self {
-        const VERSION : u8 = 0b00_011_000;
-        const MODE : u8 = 0b00_000_011;
-        let mut msg = NTPMessage::new();
-        msg.data[0] |= VERSION;
-        msg.data[0] |= MODE;
-        msg
-    }
-    fn parse_timestamp(&self, i : usize) -> Result<NTPTimestamp, std::io::Error> {
-        let mut reader = &self.data[i..i+8];
-        let seconds = reader.read_u32::<BigEndian>()?;
-        let fraction = reader.read_u32::<BigEndian>()?;
-        Ok(NTPTimestamp{
-            seconds : seconds,
-            fraction : fraction,
-        })
-    }
-    fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(32)
+/// The servers given with --server and those listed in the --config file,
+/// one per line with `#` starting a comment, or else the default ones.
+fn servers(args : &ArgMatches) -> Result<Vec<String>, std::io::Error> {
+    let mut servers : Vec<String> = args.values_of("server")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    if let Some(path) = args.value_of("config") {
+        let text = fs::read_to_string(path)?;
+        for line in text.lines() {
+            let server = line.split('#').next().unwrap().trim();
+            if !server.is_empty() {
+                servers.push(server.to_string());
+            }
+        }
     }
-    fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(40)
+    if servers.is_empty() {
+        servers = DEFAULT_SERVERS.iter().map(|server| server.to_string()).collect();
     }
+    Ok(servers)
 }
 
-fn weighted_mean(values:&[f64], weights :&[f64]) -> f64 {
-    let mut result = 0.0;
-    let mut sum_of_weights = 0.0;
-    for (v,w) in values.inter().zip(weights) {
-        result  += v*w;
-        sum_of_weights += w;
+fn check_time(servers : &[String], samples : usize) -> Result<f64, std::io::Error> {
+    let mut peers = Vec::with_capacity(servers.len());
+    for server in servers {
+        match ntp::sample(&ntp::address(server), samples) {
+            Ok(results) => peers.extend(Peer::filter(server, &results, Utc::now())),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => println!("{} => rejected: {}", server, e),
+            Err(e) => println!("{} => no reply: {}", server, e),
+        }
     }
-    result/sum_of_weights
-}
-
-fn ntp_roundtrip(host:&str, port : u16) -> Result<NTPResult, std::io::Error> {
-    let destination = format!("{}:{}", host,port);
-    let timeout = Duration::from_secs(1);
-    let request = NTPMessage::client();
-    let mut response = NTPMessage::new();
-    let message = request.data;
-    let udp = UdpSocket::bind(LOCAL_ADDR)?;
-    udp.connect(&destination).expect("Unable to connect");
-    let t1 = Utc::now();
-    udp.send(&message)?;
-    udp.set_read_timeout(Some(timeout))?;
-    udp.recv_from(&mut response.data)?;
-    let t4 = Utc::now();
-    let t2 :DateTime<Utc> = response.rx_time().unwrap().into();
-    let t3 :DateTime<Utc> = response.tx_time().unwrap().into();
-    Ok (NTPResult { t1: t1, t2: t2, t3: t3, t4: t4 })
-
-}
+    let estimate = filter::synchronize(&peers)
+        .ok_or_else(|| std::io::Error::other("not enough servers agree on the time"))?;
 
-fn check_time() -> Result<f64, std::io::Error> {
-    const NTP_PORT : u16 = 123;
-    let servers = [
-        "time.nist.gov",
-        "time.apple.com",
-        "time.euro.apple.com",
-        "time.google.com",
-        "time2.google.com",
-    ];
-    let mut times = Vec::with_capacity(servers.len());
-    for &server in servers.iter() {
-        print!("{} =>", server);
-        let calc = ntp_roundtrip(&server, NTP_PORT);
-        match calc {
-            Ok(time) => {
-                println!(" {}ms away from local system time", time.offset());
-                times.push(time);
-            }
-            Err(_) => {
-                println!(" ? [response took too long]")
-            }
+    println!(
+        "  {:<20} {:<15} {:>2} {:>10} {:>9} {:>9}",
+        "server", "refid", "st", "offset ms", "delay ms", "jitter ms",
+    );
+    for (i, peer) in peers.iter().enumerate() {
+        // The same tally codes as ntpq: the system peer, the other survivors,
+        // outliers and falsetickers.
+        let tally = if i == estimate.system_peer() {
+            '*'
+        } else if estimate.survivors.contains(&i) {
+            '+'
+        } else if estimate.truechimers.contains(&i) {
+            '-'
+        } else {
+            'x'
         };
+        println!(
+            "{} {:<20} {:<15} {:>2} {:>+10.3} {:>9.3} {:>9.3}",
+            tally, peer.server, peer.reference, peer.stratum, peer.offset * 1e3, peer.delay * 1e3, peer.jitter * 1e3,
+        );
     }
-    let mut offsets = Vec::with_capacity(servers.len());
-    let mut offset_weights = Vec::with_capacity(servers.len());
-    for time in &times {
-        let offset = time.offset() as f64;
-        let delay = time.delay() as f64;
-        let weight = 1_000_000.0 / (delay * delay);
-        if weight.is_finite() {
-            offsets.push(offset);
-            offset_weights.push(weight);
-        }
-    }
-    let avg_offset = weighted_mean(&offsets, &offset_weights);
-    Ok(avg_offset)
+    println!(
+        "{:+.3}ms away from local system time (jitter {:.3}ms, {} of {} servers)",
+        estimate.offset * 1e3, estimate.jitter * 1e3, estimate.survivors.len(), peers.len(),
+    );
+    Ok(estimate.offset)
 }
 
 struct Clock;
@@ -170,16 +94,81 @@ impl Clock {
         Local::now()
     }
     #[cfg(not(windows))]
-    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> () {
+    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> Result<(), std::io::Error> {
         use libc::{timeval, time_t, suseconds_t};
         use libc::{settimeofday, timezone};
         let t = t.with_timezone(&Local);
         let mut u : timeval = unsafe {zeroed()};
         u.tv_sec = t.timestamp() as time_t;
         u.tv_usec = t.timestamp_subsec_micros() as suseconds_t;
-        unsafe {
+        let result = unsafe {
             let mock_tz: *const timezone = std::ptr::null();
-            settimeofday(&u as *const timeval, mock_tz);
+            settimeofday(&u as *const timeval, mock_tz)
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    /// Has the kernel run the clock slightly fast or slow until it has
+    /// gained `offset` seconds, so that time never jumps, let alone goes
+    /// backwards.
+    #[cfg(not(windows))]
+    fn slew(offset : f64) -> Result<(), std::io::Error> {
+        use libc::{adjtime, timeval, time_t, suseconds_t};
+        let micros = (offset * 1e6).round() as i64;
+        let delta = timeval {
+            tv_sec : micros.div_euclid(1_000_000) as time_t,
+            tv_usec : micros.rem_euclid(1_000_000) as suseconds_t,
+        };
+        let result = unsafe { adjtime(&delta as *const timeval, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// How the kernel slews: by half a millisecond every second.
+const SLEW_RATE : f64 = 500e-6;
+
+/// What check-ntp does about an offset, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Adjustment {
+    Slew(f64),
+    Step(f64),
+}
+
+impl Adjustment {
+    /// Slews away offsets up to `step_threshold` seconds and steps past
+    /// bigger ones, which would take too long to slew.
+    fn for_offset(offset : f64, step_threshold : f64) -> Adjustment {
+        if offset.abs() > step_threshold {
+            Adjustment::Step(offset)
+        } else {
+            Adjustment::Slew(offset)
+        }
+    }
+
+    fn apply(self) -> Result<(), std::io::Error> {
+        match self {
+            Adjustment::Slew(offset) => Clock::slew(offset),
+            Adjustment::Step(offset) => {
+                let offset = ChronoDuration::nanoseconds((offset * 1e9) as i64);
+                Clock::set(Utc::now() + offset)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Adjustment::Slew(offset) => write!(
+                f, "slew the clock by {:+.3}ms over {:.0}s",
+                offset * 1e3, offset.abs() / SLEW_RATE,
+            ),
+            Adjustment::Step(offset) => write!(f, "step the clock by {:+.3}ms", offset * 1e3),
         }
     }
 }
@@ -196,7 +185,7 @@ fn main() {
         .arg(
             Arg::with_name("action")
             .takes_value(true)
-            .possible_values(&["get", "set","check-ntp"])
+            .possible_values(&["get", "set","check-ntp", "serve"])
             .default_value("get"),
         )
         .arg(
@@ -211,6 +200,50 @@ fn main() {
             ])
             .default_value("rfc3339")
         )
+        .arg(
+            Arg::with_name("samples")
+            .short("n")
+            .long("samples")
+            .takes_value(true)
+            .default_value("4")
+            .help("How many requests 'check-ntp' sends to each server")
+        )
+        .arg(
+            Arg::with_name("server")
+            .long("server")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("host[:port]")
+            .help("Server for 'check-ntp' to ask instead of the public ones; repeat for more")
+        )
+        .arg(
+            Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("file")
+            .help("File listing servers for 'check-ntp', one per line")
+        )
+        .arg(
+            Arg::with_name("step-threshold")
+            .long("step-threshold")
+            .takes_value(true)
+            .value_name("ms")
+            .default_value("128")
+            .help("Offsets up to this are slewed away gradually by 'check-ntp', bigger ones are stepped")
+        )
+        .arg(
+            Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Prints how 'set' or 'check-ntp' would change the clock without changing it")
+        )
+        .arg(
+            Arg::with_name("bind")
+            .long("bind")
+            .takes_value(true)
+            .default_value("0.0.0.0:123")
+            .help("Address that 'serve' answers NTP requests on")
+        )
         .arg(
             Arg::with_name("datetime").help(
                 "When <action> is 'set', apply <datetime>. \
@@ -220,6 +253,7 @@ fn main() {
     let args = app.get_matches();
     let action = args.value_of("action").unwrap();
     let std = args.value_of("std").unwrap();
+    let dry_run = args.is_present("dry-run");
     if action == "set" {
         let t_ = args.value_of("datetime").unwrap();
         let parser = match std {
@@ -232,22 +266,61 @@ fn main() {
             t_, std
         );
         let t = parser(t_).expect(&err_msg);
-        Clock::set(t);
+        if dry_run {
+            println!("Would set the clock to {}", t.to_rfc3339());
+        } else if let Err(e) = Clock::set(t) {
+            eprintln!("Unable to set the time: {}", e);
+            std::process::exit(1);
+        }
     } else if action == "check-ntp" {
-        let offset = check_time().unwrap() as isize;
-        let adjust_ms_ = offset.signum() * offset.abs().min(200) / 5;
-        let adjust_ms = ChronoDuration::milliseconds(adjust_ms_ as i64);
-        let now : DateTime<Utc> = Utc::now() + adjust_ms;
-        Clock::set(now);
-    }
-    let maybe_error = std::io::Error::last_os_error();
-    let os_error_code = &maybe_error.raw_os_error();
-    match os_error_code {
-        Some(0) => (),
-        Some(_) => eprintln!("Unable to set the time: {:?}", maybe_error),
-        None => (),
+        let samples = args.value_of("samples").unwrap();
+        let samples = match samples.parse::<usize>() {
+            Ok(n) if (1..=filter::FILTER_SIZE).contains(&n) => n,
+            _ => {
+                eprintln!("<samples> must be a number from 1 to {}", filter::FILTER_SIZE);
+                std::process::exit(2);
+            }
+        };
+        let step_threshold = match args.value_of("step-threshold").unwrap().parse::<f64>() {
+            Ok(ms) if ms >= 0.0 => ms / 1e3,
+            _ => {
+                eprintln!("<ms> must be a number of milliseconds, 0 or more");
+                std::process::exit(2);
+            }
+        };
+        let servers = match servers(&args) {
+            Ok(servers) => servers,
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", args.value_of("config").unwrap(), e);
+                std::process::exit(2);
+            }
+        };
+        let offset = match check_time(&servers, samples) {
+            Ok(offset) => offset,
+            Err(e) => {
+                eprintln!("Unable to check the time: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let adjustment = Adjustment::for_offset(offset, step_threshold);
+        if dry_run {
+            println!("Would {}", adjustment);
+        } else if let Err(e) = adjustment.apply() {
+            eprintln!("Unable to {}: {}", adjustment, e);
+            std::process::exit(1);
+        }
+    } else if action == "serve" {
+        let bind = args.value_of("bind").unwrap();
+        let result = Server::bind(bind).and_then(|server| {
+            println!("Serving the local time on {}", server.local_addr()?);
+            server.run()
+        });
+        if let Err(e) = result {
+            eprintln!("Unable to serve on {}: {}", bind, e);
+            std::process::exit(1);
+        }
     }
-    
+
     let now = Clock::get();
     match std {
         "timestamp" => println!("{}", now.timestamp()),
@@ -256,3 +329,18 @@ fn main() {
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_only_past_the_threshold() {
+        assert_eq!(Adjustment::for_offset(0.05, 0.128), Adjustment::Slew(0.05));
+        assert_eq!(Adjustment::for_offset(-0.128, 0.128), Adjustment::Slew(-0.128));
+        assert_eq!(Adjustment::for_offset(-0.2, 0.128), Adjustment::Step(-0.2));
+        assert_eq!(Adjustment::for_offset(0.001, 0.0), Adjustment::Step(0.001));
+        assert_eq!(Adjustment::Slew(0.05).to_string(), "slew the clock by +50.000ms over 100s");
+        assert_eq!(Adjustment::Step(-2.5).to_string(), "step the clock by -2500.000ms");
+    }
+}
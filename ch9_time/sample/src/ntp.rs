@@ -0,0 +1,534 @@
+use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, TimeZone, Timelike, Utc, Duration as ChronoDuration};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+pub const NTP_MESSAGE_LENGTH : usize = 48;
+const NTP_TO_UNIX_SECONDS : i64 = 2_208_988_800;
+pub const NTP_PORT : u16 = 123;
+/// Port 0 lets the OS pick a free port, so that several clients can run at once.
+const LOCAL_ADDR : &str = "0.0.0.0:0";
+const LOCAL_ADDR_V6 : &str = "[::]:0";
+
+/// How long to wait between two requests to the same server.
+const SAMPLE_INTERVAL : Duration = Duration::from_millis(500);
+
+/// How precisely `Utc::now()` reads the local clock, in seconds.
+pub const PRECISION : f64 = 1e-6;
+
+/// How fast, in seconds per second, a reading loses accuracy as the local
+/// clock drifts away from it (RFC 5905's PHI).
+pub const PHI : f64 = 15e-6;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NTPTimestamp {
+    seconds : u32,
+    fraction : u32,
+}
+
+impl NTPTimestamp {
+    fn read(bytes : &[u8]) -> NTPTimestamp {
+        NTPTimestamp {
+            seconds : BigEndian::read_u32(&bytes[0..4]),
+            fraction : BigEndian::read_u32(&bytes[4..8]),
+        }
+    }
+    fn write(&self, bytes : &mut [u8]) {
+        BigEndian::write_u32(&mut bytes[0..4], self.seconds);
+        BigEndian::write_u32(&mut bytes[4..8], self.fraction);
+    }
+}
+
+impl From<NTPTimestamp> for DateTime<Utc> {
+    fn from(value: NTPTimestamp) -> Self {
+        let secs = value.seconds as i64 - NTP_TO_UNIX_SECONDS;
+        let mut nanos = value.fraction as f64;
+        nanos *= 1e9;
+        nanos /= 2_f64.powi(32);
+        Utc.timestamp_opt(secs, nanos as u32).unwrap()
+    }
+}
+
+impl From<DateTime<Utc>> for NTPTimestamp {
+    fn from(value: DateTime<Utc>) -> Self {
+        let secs = value.timestamp() + NTP_TO_UNIX_SECONDS;
+        let mut fraction = value.nanosecond() as f64;
+        fraction *= 2_f64.powi(32);
+        fraction /= 1e9;
+        NTPTimestamp { seconds: secs as u32, fraction: fraction as u32 }
+    }
+}
+
+/// Warns of a leap second at the end of the current day, or that the clock
+/// isn't synchronized at all.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Leap {
+    NoWarning = 0,
+    InsertSecond = 1,
+    DeleteSecond = 2,
+    Alarm = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Reserved = 0,
+    SymmetricActive = 1,
+    SymmetricPassive = 2,
+    Client = 3,
+    Server = 4,
+    Broadcast = 5,
+    Control = 6,
+    Private = 7,
+}
+
+/// The header of an NTP v3 or v4 packet. Extension fields and MACs after
+/// it are ignored.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NTPMessage {
+    pub leap : Leap,
+    pub version : u8,
+    pub mode : Mode,
+    pub stratum : u8,
+    /// The log2 of the polling interval, in seconds.
+    pub poll : i8,
+    /// The log2 of the precision of the sender's clock, in seconds.
+    pub precision : i8,
+    /// The round trip to the primary clock, in seconds.
+    pub root_delay : f64,
+    /// How far off the sender's clock can be from the primary clock, in seconds.
+    pub root_dispersion : f64,
+    pub reference_id : [u8;4],
+    /// When the sender's clock was last set.
+    pub reference_time : NTPTimestamp,
+    /// The transmit time of the request that this packet answers.
+    pub origin_time : NTPTimestamp,
+    pub receive_time : NTPTimestamp,
+    pub transmit_time : NTPTimestamp,
+}
+
+/// Why a packet can't be used.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    Length(usize),
+    Version(u8),
+    Mode(Mode),
+    /// The reply answers some other request than ours.
+    Origin,
+    /// The reply carries no transmit time to read the server's clock from.
+    Transmit,
+    /// The server told us to go away, with a code such as `RATE` or `DENY`.
+    KissOfDeath(String),
+    Stratum(u8),
+    Unsynchronized,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Length(length) => write!(f, "{} bytes is too short for an NTP packet", length),
+            PacketError::Version(version) => write!(f, "NTP version {} is not supported", version),
+            PacketError::Mode(mode) => write!(f, "expected a server reply, not a {:?} packet", mode),
+            PacketError::Origin => write!(f, "the reply does not answer our request"),
+            PacketError::Transmit => write!(f, "the reply has no transmit time"),
+            PacketError::KissOfDeath(code) => write!(f, "kiss-of-death from the server ({})", code),
+            PacketError::Stratum(stratum) => write!(f, "the server has invalid stratum {}", stratum),
+            PacketError::Unsynchronized => write!(f, "the server's clock is not synchronized"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl From<PacketError> for io::Error {
+    fn from(value : PacketError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// One exchange with a server. Apart from the timestamps, all times are in
+/// seconds.
+#[derive(Debug, Clone)]
+pub struct NTPResult {
+    pub t1 : DateTime<Utc>,
+    pub t2 : DateTime<Utc>,
+    pub t3 : DateTime<Utc>,
+    pub t4 : DateTime<Utc>,
+    pub stratum : u8,
+    pub precision : f64,
+    pub root_delay : f64,
+    pub root_dispersion : f64,
+    pub reference : String,
+}
+
+impl NTPResult {
+    /// How far the server's clock is ahead of ours.
+    pub fn offset(&self) -> f64 {
+        (seconds(self.t2 - self.t1) + seconds(self.t3 - self.t4)) / 2.0
+    }
+
+    /// The round trip, less the time the server held on to the request.
+    pub fn delay(&self) -> f64 {
+        seconds(self.t4 - self.t1) - seconds(self.t3 - self.t2)
+    }
+
+    /// The most the reading can be off by, from the precision of both clocks
+    /// and the drift of ours during the round trip.
+    pub fn dispersion(&self) -> f64 {
+        self.precision + PRECISION + PHI * seconds(self.t4 - self.t1)
+    }
+}
+
+pub fn seconds(duration : ChronoDuration) -> f64 {
+    match duration.num_nanoseconds() {
+        Some(nanos) => nanos as f64 / 1e9,
+        None => duration.num_milliseconds() as f64 / 1e3,
+    }
+}
+
+/// Stratum 16 and up means unsynchronized.
+const MAX_STRATUM : u8 = 15;
+
+impl NTPMessage {
+    /// A request sent at `transmitted`, which the server echoes back as the
+    /// origin time of its reply.
+    pub fn client(transmitted : DateTime<Utc>) -> Self {
+        NTPMessage {
+            leap : Leap::NoWarning,
+            version : 3,
+            mode : Mode::Client,
+            stratum : 0,
+            poll : 0,
+            precision : 0,
+            root_delay : 0.0,
+            root_dispersion : 0.0,
+            reference_id : [0;4],
+            reference_time : NTPTimestamp::default(),
+            origin_time : NTPTimestamp::default(),
+            receive_time : NTPTimestamp::default(),
+            transmit_time : transmitted.into(),
+        }
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<NTPMessage, PacketError> {
+        if bytes.len() < NTP_MESSAGE_LENGTH {
+            return Err(PacketError::Length(bytes.len()));
+        }
+        let version = (bytes[0] >> 3) & 0b111;
+        if !(3..=4).contains(&version) {
+            return Err(PacketError::Version(version));
+        }
+        let leap = match bytes[0] >> 6 {
+            0 => Leap::NoWarning,
+            1 => Leap::InsertSecond,
+            2 => Leap::DeleteSecond,
+            _ => Leap::Alarm,
+        };
+        let mode = match bytes[0] & 0b111 {
+            0 => Mode::Reserved,
+            1 => Mode::SymmetricActive,
+            2 => Mode::SymmetricPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::Control,
+            _ => Mode::Private,
+        };
+        let mut reference_id = [0;4];
+        reference_id.copy_from_slice(&bytes[12..16]);
+        Ok(NTPMessage {
+            leap,
+            version,
+            mode,
+            stratum : bytes[1],
+            poll : bytes[2] as i8,
+            precision : bytes[3] as i8,
+            root_delay : read_short(&bytes[4..8]),
+            root_dispersion : read_short(&bytes[8..12]),
+            reference_id,
+            reference_time : NTPTimestamp::read(&bytes[16..24]),
+            origin_time : NTPTimestamp::read(&bytes[24..32]),
+            receive_time : NTPTimestamp::read(&bytes[32..40]),
+            transmit_time : NTPTimestamp::read(&bytes[40..48]),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8;NTP_MESSAGE_LENGTH] {
+        let mut bytes = [0;NTP_MESSAGE_LENGTH];
+        bytes[0] = (self.leap as u8) << 6 | (self.version & 0b111) << 3 | self.mode as u8;
+        bytes[1] = self.stratum;
+        bytes[2] = self.poll as u8;
+        bytes[3] = self.precision as u8;
+        write_short(&mut bytes[4..8], self.root_delay);
+        write_short(&mut bytes[8..12], self.root_dispersion);
+        bytes[12..16].copy_from_slice(&self.reference_id);
+        self.reference_time.write(&mut bytes[16..24]);
+        self.origin_time.write(&mut bytes[24..32]);
+        self.receive_time.write(&mut bytes[32..40]);
+        self.transmit_time.write(&mut bytes[40..48]);
+        bytes
+    }
+
+    /// The reply to this request from a server whose clock reads `received`
+    /// when the request arrives and `transmitted` when the reply leaves.
+    /// `None` unless this is a client request.
+    pub fn reply(&self, received : DateTime<Utc>, transmitted : DateTime<Utc>, stratum : u8, reference_id : [u8;4]) -> Option<NTPMessage> {
+        if self.mode != Mode::Client {
+            return None;
+        }
+        Some(NTPMessage {
+            leap : Leap::NoWarning,
+            version : self.version,
+            mode : Mode::Server,
+            stratum,
+            poll : self.poll,
+            precision : -20,
+            root_delay : 0.0,
+            root_dispersion : 0.0,
+            reference_id,
+            reference_time : received.into(),
+            origin_time : self.transmit_time,
+            receive_time : received.into(),
+            transmit_time : transmitted.into(),
+        })
+    }
+
+    /// Checks that this is a usable reply from a server to `request`.
+    pub fn check_reply(&self, request : &NTPMessage) -> Result<(), PacketError> {
+        if self.mode != Mode::Server {
+            return Err(PacketError::Mode(self.mode));
+        }
+        if self.origin_time != request.transmit_time {
+            return Err(PacketError::Origin);
+        }
+        if self.transmit_time == NTPTimestamp::default() {
+            return Err(PacketError::Transmit);
+        }
+        if self.stratum == 0 {
+            let code = self.reference();
+            return Err(if code.is_empty() { PacketError::Stratum(0) } else { PacketError::KissOfDeath(code) });
+        }
+        if self.stratum > MAX_STRATUM {
+            return Err(PacketError::Stratum(self.stratum));
+        }
+        if self.leap == Leap::Alarm {
+            return Err(PacketError::Unsynchronized);
+        }
+        Ok(())
+    }
+
+    /// The reference ID as people write it: the four letter code of a
+    /// reference clock or a kiss-of-death at strata 0 and 1, the address of
+    /// the server's own server otherwise.
+    pub fn reference(&self) -> String {
+        if self.stratum > 1 {
+            return Ipv4Addr::from(self.reference_id).to_string();
+        }
+        self.reference_id.iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '?' })
+            .collect()
+    }
+}
+
+/// Reads one of the 16.16 fixed point durations in the header.
+fn read_short(bytes : &[u8]) -> f64 {
+    BigEndian::read_u32(bytes) as f64 / 65536.0
+}
+
+fn write_short(bytes : &mut [u8], seconds : f64) {
+    BigEndian::write_u32(bytes, (seconds * 65536.0).round() as u32);
+}
+
+/// Adds the NTP port to `server` unless it names one already, e.g.
+/// `time.google.com` becomes `time.google.com:123` and `::1` `[::1]:123`.
+pub fn address(server : &str) -> String {
+    if server.parse::<SocketAddr>().is_ok() {
+        return server.to_string();
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return SocketAddr::new(ip, NTP_PORT).to_string();
+    }
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+        _ => format!("{}:{}", server, NTP_PORT),
+    }
+}
+
+/// Makes one round trip to `destination`, a `host:port` address.
+pub fn ntp_roundtrip(destination : &str) -> Result<NTPResult, std::io::Error> {
+    let destination = destination.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found"))?;
+    let timeout = Duration::from_secs(1);
+    let mut buffer = [0;NTP_MESSAGE_LENGTH];
+    let udp = UdpSocket::bind(if destination.is_ipv6() { LOCAL_ADDR_V6 } else { LOCAL_ADDR })?;
+    udp.connect(destination)?;
+    let t1 = Utc::now();
+    let request = NTPMessage::client(t1);
+    udp.send(&request.to_bytes())?;
+    udp.set_read_timeout(Some(timeout))?;
+    let length = udp.recv(&mut buffer)?;
+    let t4 = Utc::now();
+    let response = NTPMessage::from_bytes(&buffer[..length])?;
+    response.check_reply(&request)?;
+    Ok(NTPResult {
+        t1,
+        t2 : response.receive_time.into(),
+        t3 : response.transmit_time.into(),
+        t4,
+        stratum : response.stratum,
+        precision : 2_f64.powi(response.precision as i32),
+        root_delay : response.root_delay,
+        root_dispersion : response.root_dispersion,
+        reference : response.reference(),
+    })
+}
+
+/// Makes `count` round trips to `destination`, keeping the ones that were
+/// answered. Fails with the last error only when none were, and at once on
+/// a kiss-of-death, as RFC 5905 asks clients to stop sending to the server.
+pub fn sample(destination : &str, count : usize) -> Result<Vec<NTPResult>, std::io::Error> {
+    let mut results = Vec::with_capacity(count);
+    let mut last_error = None;
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+        match ntp_roundtrip(destination) {
+            Ok(result) => results.push(result),
+            Err(e) if is_kiss_of_death(&e) => return Err(e),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) if results.is_empty() => Err(e),
+        _ => Ok(results),
+    }
+}
+
+fn is_kiss_of_death(error : &io::Error) -> bool {
+    matches!(
+        error.get_ref().and_then(|inner| inner.downcast_ref::<PacketError>()),
+        Some(PacketError::KissOfDeath(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_and_delay() {
+        let t1 = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let ms = ChronoDuration::milliseconds;
+        // The server is 100 ms ahead, 10 ms away each way and takes 2 ms to answer.
+        let result = NTPResult {
+            t1,
+            t2 : t1 + ms(110),
+            t3 : t1 + ms(112),
+            t4 : t1 + ms(22),
+            stratum : 1,
+            precision : 2_f64.powi(-20),
+            root_delay : 0.0,
+            root_dispersion : 0.0,
+            reference : "GPS".to_string(),
+        };
+        assert!((result.offset() - 0.1).abs() < 1e-9);
+        assert!((result.delay() - 0.02).abs() < 1e-9);
+        let round_trip : DateTime<Utc> = NTPTimestamp::from(result.t3).into();
+        assert!(seconds(round_trip - result.t3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address("time.google.com"), "time.google.com:123");
+        assert_eq!(address("localhost:1123"), "localhost:1123");
+        assert_eq!(address("127.0.0.1"), "127.0.0.1:123");
+        assert_eq!(address("::1"), "[::1]:123");
+        assert_eq!(address("[::1]:1123"), "[::1]:1123");
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let now = Utc::now();
+        let request = NTPMessage::client(now);
+        let bytes = request.to_bytes();
+        assert_eq!(bytes[0], 0b00_011_011);
+        assert_eq!(NTPMessage::from_bytes(&bytes).unwrap(), request);
+
+        let mut reply = request.reply(now, now, 2, [192, 0, 2, 1]).unwrap();
+        reply.leap = Leap::InsertSecond;
+        reply.version = 4;
+        reply.poll = 6;
+        reply.root_delay = 0.015625;
+        reply.root_dispersion = 1.5;
+        let bytes = reply.to_bytes();
+        assert_eq!(bytes[0], 0b01_100_100);
+        assert_eq!(bytes[3] as i8, -20);
+        assert_eq!(&bytes[4..12], &[0, 0, 4, 0, 0, 1, 128, 0]);
+        assert_eq!(&bytes[24..32], &request.to_bytes()[40..48]);
+        let parsed = NTPMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, reply);
+        assert_eq!(parsed.reference(), "192.0.2.1");
+        let t3 : DateTime<Utc> = parsed.transmit_time.into();
+        assert!(seconds(t3 - now).abs() < 1e-6);
+        assert!(parsed.reply(now, now, 2, [0;4]).is_none());
+
+        let mut long = bytes.to_vec();
+        long.extend_from_slice(&[0;20]);
+        assert_eq!(NTPMessage::from_bytes(&long).unwrap(), reply);
+        assert_eq!(NTPMessage::from_bytes(&bytes[..47]), Err(PacketError::Length(47)));
+        let mut version_2 = bytes;
+        version_2[0] = 0b00_010_100;
+        assert_eq!(NTPMessage::from_bytes(&version_2), Err(PacketError::Version(2)));
+    }
+
+    #[test]
+    fn checks_replies() {
+        let now = Utc::now();
+        let request = NTPMessage::client(now);
+        let reply = request.reply(now, now, 1, *b"GPS\0").unwrap();
+        assert_eq!(reply.check_reply(&request), Ok(()));
+        assert_eq!(reply.reference(), "GPS");
+
+        let stale = NTPMessage::client(now - ChronoDuration::seconds(1));
+        assert_eq!(reply.check_reply(&stale), Err(PacketError::Origin));
+        assert_eq!(request.check_reply(&request), Err(PacketError::Mode(Mode::Client)));
+        let blank = NTPMessage { transmit_time : NTPTimestamp::default(), ..reply };
+        assert_eq!(blank.check_reply(&request), Err(PacketError::Transmit));
+
+        let kiss = NTPMessage { stratum : 0, reference_id : *b"RATE", ..reply };
+        assert_eq!(kiss.check_reply(&request), Err(PacketError::KissOfDeath("RATE".to_string())));
+        let unspecified = NTPMessage { stratum : 0, reference_id : [0;4], ..reply };
+        assert_eq!(unspecified.check_reply(&request), Err(PacketError::Stratum(0)));
+        let unsynchronized = NTPMessage { stratum : 16, ..reply };
+        assert_eq!(unsynchronized.check_reply(&request), Err(PacketError::Stratum(16)));
+        let alarm = NTPMessage { leap : Leap::Alarm, ..reply };
+        assert_eq!(alarm.check_reply(&request), Err(PacketError::Unsynchronized));
+    }
+
+    #[test]
+    fn stops_sampling_on_a_kiss_of_death() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let addr = server.local_addr().unwrap();
+        let kisses = thread::spawn(move || {
+            let mut buffer = [0;NTP_MESSAGE_LENGTH];
+            let mut requests = 0;
+            while let Ok((length, client)) = server.recv_from(&mut buffer) {
+                requests += 1;
+                let now = Utc::now();
+                let request = NTPMessage::from_bytes(&buffer[..length]).unwrap();
+                let reply = request.reply(now, now, 0, *b"DENY").unwrap();
+                server.send_to(&reply.to_bytes(), client).unwrap();
+            }
+            requests
+        });
+        let error = sample(&addr.to_string(), 4).unwrap_err();
+        assert!(is_kiss_of_death(&error), "{}", error);
+        assert_eq!(kisses.join().unwrap(), 1);
+    }
+}
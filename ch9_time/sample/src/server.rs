@@ -0,0 +1,69 @@
+use chrono::Utc;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::ntp::{NTPMessage, NTP_MESSAGE_LENGTH};
+
+/// What a server that isn't synchronized to anything reports: the
+/// conventional stratum of an undisciplined local clock, and the address
+/// ntpd gives its local clock driver as the reference ID.
+const STRATUM : u8 = 10;
+const REFID : [u8;4] = [127, 127, 1, 1];
+
+/// Answers NTP client requests with the time of the local clock.
+pub struct Server {
+    socket : UdpSocket,
+}
+
+impl Server {
+    pub fn bind<A : ToSocketAddrs>(addr : A) -> Result<Server, std::io::Error> {
+        Ok(Server { socket : UdpSocket::bind(addr)? })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.socket.local_addr()
+    }
+
+    /// Serves until receiving fails. Datagrams that aren't NTP v3 or v4
+    /// client requests are dropped.
+    pub fn run(&self) -> Result<(), std::io::Error> {
+        let mut buffer = [0;NTP_MESSAGE_LENGTH];
+        loop {
+            let (length, client) = self.socket.recv_from(&mut buffer)?;
+            let received = Utc::now();
+            let request = match NTPMessage::from_bytes(&buffer[..length]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            if let Some(reply) = request.reply(received, Utc::now(), STRATUM, REFID) {
+                if let Err(e) = self.socket.send_to(&reply.to_bytes(), client) {
+                    eprintln!("Unable to answer {}: {}", client, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp;
+    use std::thread;
+
+    #[test]
+    fn clients_agree_with_the_local_clock() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        let results = ntp::sample(&addr.to_string(), 2).unwrap();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result.stratum, STRATUM);
+            assert_eq!(result.reference, "127.127.1.1");
+            assert!(result.offset().abs() < 0.05, "{:?}", result);
+            assert!(result.delay() >= 0.0 && result.delay() < 0.05, "{:?}", result);
+        }
+        let junk = UdpSocket::bind("127.0.0.1:0").unwrap();
+        junk.send_to(b"not ntp", addr).unwrap();
+        assert_eq!(ntp::sample(&addr.to_string(), 1).unwrap().len(), 1);
+    }
+}